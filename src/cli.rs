@@ -0,0 +1,178 @@
+use std::path::PathBuf;
+
+use anyhow::*;
+use clap::Parser;
+use raytracer::core::{
+    BounceLimits, MAX_RENDER_SCALE, MAX_SAMPLES_PER_FRAME, RenderSettings, Sampler, Scene,
+};
+
+const DEFAULT_SCENE: &str = "./assets/scenes/default.toml";
+const DEFAULT_OUTPUT: &str = "render.png";
+/// Samples per pixel of headless renders without `--spp`.
+const DEFAULT_HEADLESS_SAMPLES: u32 = 144;
+
+/// GPU path tracer. Opens a window by default, renders straight to an image with `--output` or
+/// `--headless`.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Scene file to render
+    #[arg(default_value = DEFAULT_SCENE)]
+    pub scene: PathBuf,
+    /// Image to render to, `.exr` and `.hdr` keep the linear radiance. Implies `--headless`
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Render without a window, to `render.png` unless `--output` is given
+    #[arg(long)]
+    pub headless: bool,
+    /// Image width in pixels, logical pixels of the window when interactive
+    #[arg(long, default_value_t = 800)]
+    pub width: u32,
+    /// Image height in pixels, logical pixels of the window when interactive
+    #[arg(long, default_value_t = 600)]
+    pub height: u32,
+    /// Render resolution relative to the window, e.g. 0.5 for a faster preview
+    #[arg(long, default_value_t = 1.0)]
+    pub render_scale: f32,
+    /// Samples per pixel to accumulate. Interactive renders refine indefinitely by default
+    #[arg(long)]
+    pub spp: Option<u32>,
+    /// Samples per pixel traced by one frame, lower values keep the window responsive
+    #[arg(long, default_value_t = 36)]
+    pub samples_per_frame: u32,
+    /// Surface interactions after which a path is terminated
+    #[arg(long, default_value_t = RenderSettings::default().max_bounces)]
+    pub bounces: u32,
+    /// Bounces off diffuse surfaces after which a path is terminated
+    #[arg(long, default_value_t = BounceLimits::default().diffuse)]
+    pub diffuse_bounces: u32,
+    /// Mirror and glossy reflections after which a path is terminated
+    #[arg(long, default_value_t = BounceLimits::default().specular)]
+    pub specular_bounces: u32,
+    /// Refractions through glass after which a path is terminated
+    #[arg(long, default_value_t = BounceLimits::default().transmission)]
+    pub transmission_bounces: u32,
+    /// Bounces after which paths are randomly terminated based on the energy they carry
+    #[arg(long, default_value_t = BounceLimits::default().russian_roulette_depth)]
+    pub russian_roulette_depth: u32,
+    /// Distribution of the random numbers: random, stratified, sobol or blue-noise
+    #[arg(long, default_value_t = Sampler::default())]
+    pub sampler: Sampler,
+    /// Noise tile of the blue-noise sampler, instead of the built-in one
+    #[arg(long, value_name = "PATH")]
+    pub noise_texture: Option<PathBuf>,
+    /// Seed of the random numbers, renders with the same seed are identical
+    #[arg(long, default_value_t = 0)]
+    pub seed: u32,
+    /// Use the graphics adapter whose name contains this, ignoring case
+    #[arg(long)]
+    pub adapter: Option<String>,
+    /// Print the available graphics adapters and exit
+    #[arg(long)]
+    pub list_adapters: bool,
+    /// Render on the CPU instead of the GPU (headless only)
+    #[arg(long)]
+    pub cpu: bool,
+    /// Camera position, overrides the scene
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_vec3)]
+    pub position: Option<[f32; 3]>,
+    /// Point the camera looks at, overrides the scene
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_vec3)]
+    pub look_at: Option<[f32; 3]>,
+    /// Vertical field of view in degrees, overrides the scene
+    #[arg(long)]
+    pub vfov: Option<f32>,
+    /// Cone angle of the rays through a pixel in degrees, overrides the scene
+    #[arg(long)]
+    pub defocus_angle: Option<f32>,
+    /// Distance of the sharp plane, overrides the scene
+    #[arg(long)]
+    pub focus_distance: Option<f32>,
+}
+
+impl Cli {
+    /// Where to write the image, `None` for an interactive session.
+    pub fn output(&self) -> Option<PathBuf> {
+        match &self.output {
+            Some(output) => Some(output.clone()),
+            None if self.headless || self.cpu => Some(PathBuf::from(DEFAULT_OUTPUT)),
+            None => None,
+        }
+    }
+
+    pub fn settings(&self) -> Result<RenderSettings> {
+        ensure!(
+            self.width > 0 && self.height > 0,
+            "the resolution must not be zero"
+        );
+        ensure!(self.spp != Some(0), "--spp must be at least 1");
+        ensure!(
+            self.render_scale > 0.0 && self.render_scale <= MAX_RENDER_SCALE,
+            "--render-scale must be in (0, {MAX_RENDER_SCALE}]"
+        );
+        ensure!(
+            self.samples_per_frame > 0 && self.samples_per_frame <= MAX_SAMPLES_PER_FRAME,
+            "--samples-per-frame must be in [1, {MAX_SAMPLES_PER_FRAME}]"
+        );
+        let samples_per_pixel = match self.output() {
+            Some(_) => Some(self.spp.unwrap_or(DEFAULT_HEADLESS_SAMPLES)),
+            None => self.spp,
+        };
+        Ok(RenderSettings {
+            width: self.width,
+            height: self.height,
+            samples_per_pixel,
+            samples_per_frame: self.samples_per_frame,
+            render_scale: self.render_scale,
+            max_bounces: self.bounces,
+            bounce_limits: BounceLimits {
+                diffuse: self.diffuse_bounces,
+                specular: self.specular_bounces,
+                transmission: self.transmission_bounces,
+                russian_roulette_depth: self.russian_roulette_depth,
+            },
+            sampler: self.sampler,
+            noise_texture: self.noise_texture.clone(),
+            seed: self.seed,
+            adapter: self.adapter.clone(),
+        })
+    }
+
+    /// Applies the camera overrides to the scene.
+    pub fn apply_camera(&self, scene: &mut Scene) -> Result<()> {
+        let camera = &mut scene.camera;
+        if let Some(position) = self.position {
+            camera.position = position.into();
+        }
+        if let Some(look_at) = self.look_at {
+            camera.look_at = look_at.into();
+        }
+        if let Some(vfov) = self.vfov {
+            ensure!(vfov > 0.0 && vfov < 180.0, "--vfov must be in (0, 180)");
+            camera.vfov = vfov;
+        }
+        if let Some(defocus_angle) = self.defocus_angle {
+            ensure!(
+                (0.0..180.0).contains(&defocus_angle),
+                "--defocus-angle must be in [0, 180)"
+            );
+            camera.defocus_angle = defocus_angle;
+        }
+        if let Some(focus_distance) = self.focus_distance {
+            ensure!(focus_distance > 0.0, "--focus-distance must be positive");
+            camera.focus_distance = Some(focus_distance);
+        }
+        Ok(())
+    }
+}
+
+fn parse_vec3(value: &str) -> Result<[f32; 3]> {
+    let components = value
+        .split(',')
+        .map(|component| component.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid vector \"{value}\""))?;
+    components
+        .try_into()
+        .map_err(|_| anyhow!("expected three comma separated numbers, got \"{value}\""))
+}
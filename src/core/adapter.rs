@@ -0,0 +1,71 @@
+use anyhow::*;
+
+/// Picks the graphics adapter to render with.
+///
+/// With a `name` the first adapter whose name contains it, ignoring case, is used. Otherwise the
+/// most powerful adapter is preferred, falling back to a software adapter (llvmpipe, WARP, ...)
+/// when there is no other. A `surface` restricts the choice to adapters that can present to it.
+pub(crate) async fn request_adapter(
+    instance: &wgpu::Instance,
+    surface: Option<&wgpu::Surface<'_>>,
+    name: Option<&str>,
+) -> Result<wgpu::Adapter> {
+    if let Some(name) = name {
+        let lowercase_name = name.to_lowercase();
+        let adapters = instance.enumerate_adapters(wgpu::Backends::all());
+        let available: Vec<String> = adapters.iter().map(describe).collect();
+        return adapters
+            .into_iter()
+            .filter(|adapter| surface.is_none_or(|surface| adapter.is_surface_supported(surface)))
+            .find(|adapter| {
+                adapter
+                    .get_info()
+                    .name
+                    .to_lowercase()
+                    .contains(&lowercase_name)
+            })
+            .with_context(|| {
+                format!(
+                    "no graphics adapter matches \"{name}\", available: {}",
+                    available.join(", ")
+                )
+            });
+    }
+
+    let adapter = match instance
+        .request_adapter(&wgpu::RequestAdapterOptionsBase {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            compatible_surface: surface,
+        })
+        .await
+    {
+        Result::Ok(adapter) => adapter,
+        Err(_) => instance
+            .request_adapter(&wgpu::RequestAdapterOptionsBase {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                force_fallback_adapter: true,
+                compatible_surface: surface,
+            })
+            .await
+            .context("no suitable graphics adapter found")?,
+    };
+    Ok(adapter)
+}
+
+/// Name, type and backend of every adapter on the system.
+pub fn list_adapters() -> Vec<String> {
+    wgpu::Instance::new(&wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        ..Default::default()
+    })
+    .enumerate_adapters(wgpu::Backends::all())
+    .iter()
+    .map(describe)
+    .collect()
+}
+
+fn describe(adapter: &wgpu::Adapter) -> String {
+    let info = adapter.get_info();
+    format!("{} ({:?}, {})", info.name, info.device_type, info.backend)
+}
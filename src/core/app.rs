@@ -2,25 +2,33 @@ use std::{collections::HashSet, sync::Arc};
 
 use winit::{
     application::ApplicationHandler,
-    event::{DeviceEvent, ElementState, KeyEvent, WindowEvent},
+    dpi::LogicalSize,
+    event::{DeviceEvent, ElementState, KeyEvent, MouseScrollDelta, WindowEvent},
     keyboard::{KeyCode, PhysicalKey},
     window::Window,
 };
 
-use crate::{core::render_state::RenderState, math::vec::Vec3};
+use crate::{
+    core::{render_state::RenderState, scene::Scene, settings::RenderSettings},
+    math::vec::Vec3,
+};
 
 pub struct RayTracer<'window> {
     render_state: Option<RenderState<'window>>,
     window: Option<Arc<Window>>,
     keys_pressed: HashSet<KeyCode>,
+    scene: Scene,
+    settings: RenderSettings,
 }
 
 impl RayTracer<'_> {
-    pub fn empty() -> Self {
+    pub fn new(scene: Scene, settings: RenderSettings) -> Self {
         Self {
             render_state: None,
             window: None,
             keys_pressed: HashSet::new(),
+            scene,
+            settings,
         }
     }
 }
@@ -57,13 +65,21 @@ impl RayTracer<'_> {
 
 impl ApplicationHandler for RayTracer<'_> {
     fn resumed(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
-        let window_attributes = Window::default_attributes().with_title("Ray Tracer");
+        let window_attributes = Window::default_attributes()
+            .with_title("Ray Tracer")
+            .with_inner_size(LogicalSize::new(self.settings.width, self.settings.height));
         let window = Arc::new(event_loop.create_window(window_attributes).unwrap());
 
         let _ = window.set_cursor_grab(winit::window::CursorGrabMode::Locked);
-        let _ = window.set_cursor_visible(false);
+        window.set_cursor_visible(false);
         self.window = Some(window.clone());
-        self.render_state = Some(pollster::block_on(RenderState::new(window)));
+        match pollster::block_on(RenderState::new(window, &self.scene, &self.settings)) {
+            Ok(render_state) => self.render_state = Some(render_state),
+            Err(error) => {
+                eprintln!("failed to set up rendering: {error:#}");
+                event_loop.exit();
+            }
+        }
     }
 
     fn window_event(
@@ -92,8 +108,50 @@ impl ApplicationHandler for RayTracer<'_> {
                     if self.keys_pressed.contains(&KeyCode::KeyD) {
                         state.camera.translate(Vec3::new(speed, 0.0, 0.0));
                     }
+
+                    // R/F push the focus plane away/closer, Z/X close/open the aperture
+                    let focus_speed = 1.02;
+                    let focus_distance = state.camera.focus_distance();
+                    if self.keys_pressed.contains(&KeyCode::KeyR) {
+                        state
+                            .camera
+                            .set_focus_distance(focus_distance * focus_speed);
+                    }
+                    if self.keys_pressed.contains(&KeyCode::KeyF) {
+                        state
+                            .camera
+                            .set_focus_distance(focus_distance / focus_speed);
+                    }
+                    let aperture_speed = 0.05;
+                    let defocus_angle = state.camera.defocus_angle();
+                    if self.keys_pressed.contains(&KeyCode::KeyZ) {
+                        state
+                            .camera
+                            .set_defocus_angle(defocus_angle - aperture_speed);
+                    }
+                    if self.keys_pressed.contains(&KeyCode::KeyX) {
+                        state
+                            .camera
+                            .set_defocus_angle(defocus_angle + aperture_speed);
+                    }
                     state.update();
                     state.render();
+
+                    if let Some(window) = &self.window {
+                        let display = state.display();
+                        let (width, height) = state.render_resolution();
+                        window.set_title(&format!(
+                            "Ray Tracer - {}x{} - {} spp ({}/frame, {}, {} bounces) - {:?} {:+.1} EV",
+                            width,
+                            height,
+                            state.samples(),
+                            state.samples_per_frame(),
+                            state.sampler(),
+                            state.max_bounces(),
+                            display.tone_mapping,
+                            display.exposure
+                        ));
+                    }
                 }
                 if let Some(window) = &mut self.window {
                     window.request_redraw();
@@ -101,7 +159,18 @@ impl ApplicationHandler for RayTracer<'_> {
             }
             WindowEvent::Resized(new_size) => {
                 if let Some(state) = &mut self.render_state {
-                    state.window_size = new_size;
+                    state.resize(new_size);
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                if let Some(state) = &mut self.render_state {
+                    // Scrolling up moves the focus plane away
+                    let lines = match delta {
+                        MouseScrollDelta::LineDelta(_, y) => y,
+                        MouseScrollDelta::PixelDelta(position) => position.y as f32 / 40.0,
+                    };
+                    let focus_distance = state.camera.focus_distance() * 1.1_f32.powf(lines);
+                    state.camera.set_focus_distance(focus_distance);
                 }
             }
             WindowEvent::KeyboardInput {
@@ -113,18 +182,54 @@ impl ApplicationHandler for RayTracer<'_> {
                         ..
                     },
                 ..
-            } => {
-                if !repeat && let Some(render_state) = &mut self.render_state {
-                    match state {
-                        ElementState::Pressed => {
-                            self.keys_pressed.insert(key_code);
+            } if !repeat && let Some(render_state) = &mut self.render_state => match state {
+                ElementState::Pressed => {
+                    // T cycles the tone mapping, -/= lower/raise the exposure by half a stop,
+                    // [/] halve/double the samples per frame, ,/. shorten/lengthen the paths,
+                    // N cycles the sampler, 9/0 lower/raise the render scale, F12 saves a PNG
+                    // screenshot, with shift an EXR
+                    let mut display = render_state.display();
+                    let samples_per_frame = render_state.samples_per_frame();
+                    let max_bounces = render_state.max_bounces();
+                    match key_code {
+                        KeyCode::KeyT => display.tone_mapping = display.tone_mapping.next(),
+                        KeyCode::Minus => display.exposure -= 0.5,
+                        KeyCode::Equal => display.exposure += 0.5,
+                        KeyCode::BracketLeft => {
+                            render_state.set_samples_per_frame(samples_per_frame / 2)
+                        }
+                        KeyCode::BracketRight => {
+                            render_state.set_samples_per_frame(samples_per_frame.saturating_mul(2))
+                        }
+                        KeyCode::Comma if max_bounces > 0 => {
+                            render_state.set_max_bounces(max_bounces - 1)
+                        }
+                        KeyCode::Period => render_state.set_max_bounces(max_bounces + 1),
+                        KeyCode::KeyN => render_state.set_sampler(render_state.sampler().next()),
+                        KeyCode::Digit9 => {
+                            render_state.set_render_scale(render_state.render_scale() - 0.25)
+                        }
+                        KeyCode::Digit0 => {
+                            render_state.set_render_scale(render_state.render_scale() + 0.25)
                         }
-                        ElementState::Released => {
-                            self.keys_pressed.remove(&key_code);
+                        KeyCode::F12 => {
+                            let shift = self.keys_pressed.contains(&KeyCode::ShiftLeft)
+                                || self.keys_pressed.contains(&KeyCode::ShiftRight);
+                            let extension = if shift { "exr" } else { "png" };
+                            match render_state.save_screenshot(extension) {
+                                Ok(path) => println!("Saved screenshot to {}", path.display()),
+                                Err(error) => eprintln!("failed to save screenshot: {error:#}"),
+                            }
                         }
+                        _ => (),
                     }
+                    render_state.set_display(display);
+                    self.keys_pressed.insert(key_code);
                 }
-            }
+                ElementState::Released => {
+                    self.keys_pressed.remove(&key_code);
+                }
+            },
             _ => (),
         }
     }
@@ -135,18 +240,14 @@ impl ApplicationHandler for RayTracer<'_> {
         _device_id: winit::event::DeviceId,
         event: DeviceEvent,
     ) {
-        match event {
-            DeviceEvent::MouseMotion { delta: (x, y) } => {
-                if let (Some(render_state), Some(window)) = (&mut self.render_state, &self.window) {
-                    let size = window.inner_size();
-                    render_state.camera.rotate((
-                        -y as f32 / size.height as f32,
-                        -x as f32 / size.width as f32,
-                    ));
-                }
-                // println!("{:?}", delta);
-            }
-            _ => (),
+        if let DeviceEvent::MouseMotion { delta: (x, y) } = event
+            && let (Some(render_state), Some(window)) = (&mut self.render_state, &self.window)
+        {
+            let size = window.inner_size();
+            render_state.camera.rotate((
+                -y as f32 / size.height as f32,
+                -x as f32 / size.width as f32,
+            ));
         }
     }
 }
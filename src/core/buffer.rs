@@ -1,15 +1,67 @@
-use std::sync::Arc;
+use anyhow::*;
+
+/// Objects that are uploaded to the GPU as elements of a storage buffer.
+pub(crate) trait BufferObject {
+    /// Size in bytes of one element, including the trailing padding required by WGSL.
+    const SIZE: usize;
 
-trait BufferObject {
     fn to_bytes(&self) -> Vec<u8>;
 }
 
-struct Buffer {
-    object: Arc<dyn BufferObject>,
+impl BufferObject for u32 {
+    const SIZE: usize = 4;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
 }
 
-impl Buffer {
-    fn new(object: Arc<dyn BufferObject>) -> Self {
-        Self { object }
+/// Packs `objects` into the bytes of a runtime-sized WGSL array.
+///
+/// wgpu rejects zero-sized bindings, so an empty slice produces a single zeroed element.
+pub(crate) fn to_storage_bytes<T: BufferObject>(objects: &[T]) -> Vec<u8> {
+    if objects.is_empty() {
+        return vec![0u8; T::SIZE];
     }
+
+    let mut bytes = Vec::<u8>::with_capacity(objects.len() * T::SIZE);
+    for object in objects {
+        let object_bytes = object.to_bytes();
+        assert!(object_bytes.len() == T::SIZE);
+        bytes.extend_from_slice(&object_bytes);
+    }
+    bytes
+}
+
+/// Copies the contents of `buffer` back to the CPU. The buffer needs `COPY_SRC` usage.
+pub(crate) fn read_buffer(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &wgpu::Buffer,
+) -> Result<Vec<u8>> {
+    let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("readback_buffer"),
+        size: buffer.size(),
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::wgt::CommandEncoderDescriptor {
+        label: Some("readback_encoder"),
+    });
+    encoder.copy_buffer_to_buffer(buffer, 0, &readback_buffer, 0, buffer.size());
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = readback_buffer.slice(..);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::PollType::wait_indefinitely())?;
+    receiver.recv()??;
+
+    let bytes = slice.get_mapped_range().to_vec();
+    readback_buffer.unmap();
+
+    Ok(bytes)
 }
@@ -0,0 +1,354 @@
+use std::ops::Range;
+
+use crate::{
+    core::{aabb::Aabb, buffer::BufferObject},
+    math::vec::Vec3,
+};
+
+/// Number of buckets the centroids are sorted into when evaluating split candidates.
+const BIN_COUNT: usize = 16;
+/// Entries of the traversal stack in compute.wgsl and the CPU renderer, a copy of the shader's
+/// `BVH_STACK_SIZE`.
+pub(crate) const BVH_STACK_SIZE: usize = 32;
+/// Nodes this deep become leaves. Traversal pushes at most one node per level, so the stack
+/// never overflows and drops subtrees.
+const MAX_DEPTH: u32 = 32;
+const _: () = assert!(MAX_DEPTH as usize <= BVH_STACK_SIZE);
+
+/// Node of a flattened bounding volume hierarchy.
+///
+/// Interior nodes have `count == 0` and their children are stored next to each other at
+/// `left_or_first` and `left_or_first + 1`. Leaves reference `count` entries of
+/// `Bvh::indices` starting at `left_or_first`.
+#[derive(Clone, Copy)]
+pub(crate) struct BvhNode {
+    pub bounds: Aabb,
+    pub left_or_first: u32,
+    pub count: u32,
+}
+
+impl BufferObject for BvhNode {
+    const SIZE: usize = 32;
+
+    /// ## WGSL schema:
+    /// struct BvhNode {
+    ///     min: vec3<f32>,
+    ///     left_or_first: u32,
+    ///     max: vec3<f32>,
+    ///     count: u32,
+    /// }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.bounds.min.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.left_or_first.to_le_bytes());
+        bytes.extend_from_slice(&self.bounds.max.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        bytes
+    }
+}
+
+/// Bounding volume hierarchy built with the surface area heuristic.
+///
+/// `indices` maps the leaf ranges back to the primitives the hierarchy was built from, so
+/// `indices[node.left_or_first + i]` is the `i`-th primitive of a leaf.
+pub(crate) struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bounds: Aabb,
+    count: u32,
+}
+
+struct Split {
+    axis: usize,
+    bin: usize,
+    cost: f32,
+}
+
+impl Bvh {
+    pub fn build(primitive_bounds: &[Aabb]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(primitive_bounds.len() * 2),
+            indices: (0..primitive_bounds.len() as u32).collect(),
+        };
+
+        if primitive_bounds.is_empty() {
+            // An empty root is never entered by the traversal.
+            bvh.nodes.push(BvhNode {
+                bounds: Aabb::empty(),
+                left_or_first: 0,
+                count: 0,
+            });
+            return bvh;
+        }
+
+        let centroids: Vec<_> = primitive_bounds.iter().map(Aabb::centroid).collect();
+
+        bvh.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            left_or_first: 0,
+            count: primitive_bounds.len() as u32,
+        });
+        bvh.subdivide(0, 0, primitive_bounds, &centroids);
+        bvh
+    }
+
+    fn subdivide(
+        &mut self,
+        node_index: usize,
+        depth: u32,
+        primitive_bounds: &[Aabb],
+        centroids: &[Vec3],
+    ) {
+        let node = self.nodes[node_index];
+        let first = node.left_or_first as usize;
+        let count = node.count as usize;
+        let range = first..first + count;
+
+        let bounds = self.indices[range.clone()]
+            .iter()
+            .fold(Aabb::empty(), |bounds, &i| {
+                bounds.union(&primitive_bounds[i as usize])
+            });
+        self.nodes[node_index].bounds = bounds;
+
+        if count <= 1 || depth >= MAX_DEPTH {
+            return;
+        }
+
+        let centroid_bounds = self.indices[range.clone()]
+            .iter()
+            .fold(Aabb::empty(), |bounds, &i| {
+                bounds.grow(&centroids[i as usize])
+            });
+
+        let Some(split) =
+            self.find_best_split(&range, &centroid_bounds, primitive_bounds, centroids)
+        else {
+            return;
+        };
+
+        let leaf_cost = count as f32 * bounds.surface_area();
+        if split.cost >= leaf_cost {
+            return;
+        }
+
+        // Partition the node's primitives in place so each child owns a contiguous range.
+        let mut i = first;
+        let mut j = first + count;
+        while i < j {
+            let centroid = centroids[self.indices[i] as usize];
+            if bin_index(centroid[split.axis], &centroid_bounds, split.axis) <= split.bin {
+                i += 1;
+            } else {
+                j -= 1;
+                self.indices.swap(i, j);
+            }
+        }
+
+        let left_count = i - first;
+        if left_count == 0 || left_count == count {
+            return;
+        }
+
+        let left_index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            left_or_first: first as u32,
+            count: left_count as u32,
+        });
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            left_or_first: i as u32,
+            count: (count - left_count) as u32,
+        });
+        self.nodes[node_index].left_or_first = left_index as u32;
+        self.nodes[node_index].count = 0;
+
+        self.subdivide(left_index, depth + 1, primitive_bounds, centroids);
+        self.subdivide(left_index + 1, depth + 1, primitive_bounds, centroids);
+    }
+
+    /// Evaluates the SAH cost at every bin boundary of every axis and returns the cheapest.
+    fn find_best_split(
+        &self,
+        range: &Range<usize>,
+        centroid_bounds: &Aabb,
+        primitive_bounds: &[Aabb],
+        centroids: &[Vec3],
+    ) -> Option<Split> {
+        let mut best: Option<Split> = None;
+
+        for axis in 0..3 {
+            if centroid_bounds.extent()[axis] <= 0.0 {
+                continue;
+            }
+
+            let mut bins = [Bin {
+                bounds: Aabb::empty(),
+                count: 0,
+            }; BIN_COUNT];
+            for &i in &self.indices[range.clone()] {
+                let bin = &mut bins[bin_index(centroids[i as usize][axis], centroid_bounds, axis)];
+                bin.bounds = bin.bounds.union(&primitive_bounds[i as usize]);
+                bin.count += 1;
+            }
+
+            // Sweep from both sides to get the area and count left and right of every boundary.
+            let mut left_area = [0.0; BIN_COUNT - 1];
+            let mut left_count = [0; BIN_COUNT - 1];
+            let mut right_area = [0.0; BIN_COUNT - 1];
+            let mut right_count = [0; BIN_COUNT - 1];
+            let mut left_bounds = Aabb::empty();
+            let mut right_bounds = Aabb::empty();
+            let mut left_sum = 0;
+            let mut right_sum = 0;
+            for i in 0..BIN_COUNT - 1 {
+                left_sum += bins[i].count;
+                left_bounds = left_bounds.union(&bins[i].bounds);
+                left_count[i] = left_sum;
+                left_area[i] = left_bounds.surface_area();
+
+                right_sum += bins[BIN_COUNT - 1 - i].count;
+                right_bounds = right_bounds.union(&bins[BIN_COUNT - 1 - i].bounds);
+                right_count[BIN_COUNT - 2 - i] = right_sum;
+                right_area[BIN_COUNT - 2 - i] = right_bounds.surface_area();
+            }
+
+            for bin in 0..BIN_COUNT - 1 {
+                if left_count[bin] == 0 || right_count[bin] == 0 {
+                    continue;
+                }
+                let cost = left_count[bin] as f32 * left_area[bin]
+                    + right_count[bin] as f32 * right_area[bin];
+                if best.as_ref().is_none_or(|best| cost < best.cost) {
+                    best = Some(Split { axis, bin, cost });
+                }
+            }
+        }
+
+        best
+    }
+}
+
+fn bin_index(centroid: f32, centroid_bounds: &Aabb, axis: usize) -> usize {
+    let min = centroid_bounds.min[axis];
+    let extent = centroid_bounds.extent()[axis];
+    let bin = ((centroid - min) / extent * BIN_COUNT as f32) as usize;
+    bin.min(BIN_COUNT - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(center: Vec3, half_size: f32) -> Aabb {
+        let half = Vec3::new(half_size, half_size, half_size);
+        Aabb::new(center - half, center + half)
+    }
+
+    fn contains(outer: &Aabb, inner: &Aabb) -> bool {
+        (0..3).all(|axis| outer.min[axis] <= inner.min[axis] && inner.max[axis] <= outer.max[axis])
+    }
+
+    /// Checks the node's subtree, counts how often every primitive appears in its leaves and
+    /// returns the depth of its deepest leaf.
+    fn check_node(bvh: &Bvh, bounds: &[Aabb], index: usize, depth: u32, seen: &mut [u32]) -> u32 {
+        let node = &bvh.nodes[index];
+        let first = node.left_or_first as usize;
+        if node.count > 0 {
+            for &primitive in &bvh.indices[first..first + node.count as usize] {
+                assert!(contains(&node.bounds, &bounds[primitive as usize]));
+                seen[primitive as usize] += 1;
+            }
+            return depth;
+        }
+        for child in [first, first + 1] {
+            assert!(contains(&node.bounds, &bvh.nodes[child].bounds));
+        }
+        let left = check_node(bvh, bounds, first, depth + 1, seen);
+        let right = check_node(bvh, bounds, first + 1, depth + 1, seen);
+        left.max(right)
+    }
+
+    /// Builds the hierarchy, checks its invariants and returns its depth.
+    fn check(bounds: &[Aabb]) -> u32 {
+        let bvh = Bvh::build(bounds);
+        let mut seen = vec![0; bounds.len()];
+        let depth = check_node(&bvh, bounds, 0, 0, &mut seen);
+        assert!(seen.iter().all(|&count| count == 1));
+        assert!(depth <= MAX_DEPTH);
+        depth
+    }
+
+    #[test]
+    fn grid() {
+        let mut bounds = Vec::new();
+        for x in 0..8 {
+            for y in 0..4 {
+                for z in 0..6 {
+                    let center = Vec3::new(x as f32, y as f32 * 1.5, z as f32 * 0.7);
+                    bounds.push(cube(center, 0.1 + 0.05 * ((x + y + z) % 3) as f32));
+                }
+            }
+        }
+        assert!(check(&bounds) > 0);
+    }
+
+    #[test]
+    fn overlapping_and_identical() {
+        let mut bounds = vec![cube(Vec3::zero(), 1.0); 10];
+        bounds.push(cube(Vec3::new(0.5, 0.0, 0.0), 3.0));
+        bounds.push(cube(Vec3::new(10.0, 0.0, 0.0), 0.5));
+        check(&bounds);
+    }
+
+    #[test]
+    fn single_and_empty() {
+        assert_eq!(check(&[cube(Vec3::zero(), 1.0)]), 0);
+        assert_eq!(Bvh::build(&[]).nodes.len(), 1);
+    }
+
+    #[test]
+    fn degenerate_depth() {
+        // Boxes halving towards the origin, splits peel off only a few of the largest
+        let bounds: Vec<_> = (0..100)
+            .map(|i| {
+                let size = 0.5f32.powi(i);
+                cube(Vec3::new(size, size, size), size * 0.5)
+            })
+            .collect();
+        assert!(check(&bounds) > 16);
+    }
+
+    #[test]
+    fn depth_limit() {
+        let bounds: Vec<_> = (0..64)
+            .map(|i| cube(Vec3::new(i as f32, 0.0, 0.0), 0.1))
+            .collect();
+        let centroids: Vec<_> = bounds.iter().map(Aabb::centroid).collect();
+        let mut bvh = Bvh {
+            nodes: vec![BvhNode {
+                bounds: Aabb::empty(),
+                left_or_first: 0,
+                count: bounds.len() as u32,
+            }],
+            indices: (0..bounds.len() as u32).collect(),
+        };
+        // Starting 3 levels below the limit, the subtree may only be 3 levels deep
+        bvh.subdivide(0, MAX_DEPTH - 3, &bounds, &centroids);
+        let mut seen = vec![0; bounds.len()];
+        assert_eq!(check_node(&bvh, &bounds, 0, 0, &mut seen), 3);
+        assert!(seen.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn stack_size_matches_shader() {
+        let shader = include_str!("../shaders/compute.wgsl");
+        let declaration = format!("const BVH_STACK_SIZE = {BVH_STACK_SIZE}u;");
+        assert!(shader.contains(&declaration));
+    }
+}
@@ -5,6 +5,9 @@ use crate::math::{
     vec::{Radians, Vec3, Vec4},
 };
 
+/// Thin lens camera. Rays start on a disk around `position` and converge on the plane at
+/// `focus_distance`, so only that plane is sharp. A `defocus_angle` (the cone angle of the
+/// rays through a pixel, in degrees) of 0 gives a pinhole camera with everything in focus.
 pub struct Camera {
     image_width: u32,
     image_height: u32,
@@ -13,7 +16,11 @@ pub struct Camera {
     pixel_delta_u: Vec3,
     pixel_delta_v: Vec3,
     first_pixel_pos: Vec3,
+    defocus_disk_u: Vec3,
+    defocus_disk_v: Vec3,
     vfov: f32,
+    defocus_angle: f32,
+    focus_distance: f32,
     needs_update: bool,
 }
 
@@ -24,6 +31,8 @@ impl Camera {
         vfov: f32,
         position: Vec3,
         look_at: Vec3,
+        defocus_angle: f32,
+        focus_distance: f32,
     ) -> Self {
         Self {
             image_width,
@@ -33,11 +42,19 @@ impl Camera {
             pixel_delta_u: Vec3::zero(),
             pixel_delta_v: Vec3::zero(),
             first_pixel_pos: Vec3::zero(),
+            defocus_disk_u: Vec3::zero(),
+            defocus_disk_v: Vec3::zero(),
             vfov,
+            defocus_angle,
+            focus_distance,
             needs_update: true,
         }
     }
 
+    pub const fn needs_update(&self) -> bool {
+        self.needs_update
+    }
+
     pub fn update(&mut self) {
         if !self.needs_update {
             return;
@@ -45,10 +62,9 @@ impl Camera {
         self.needs_update = false;
         let up_vector = Vec3::new(0.0, 1.0, 0.0);
 
-        let focal_length = (self.position - self.look_at).length();
         let theta = self.vfov / 180.0 * consts::PI;
         let h = f32::tan(theta / 2.0);
-        let viewport_height = 2.0 * h * focal_length;
+        let viewport_height = 2.0 * h * self.focus_distance;
 
         let viewport_width = viewport_height * (self.image_width as f32 / self.image_height as f32);
 
@@ -63,9 +79,80 @@ impl Camera {
         self.pixel_delta_v = viewport_v.div(self.image_height as f32);
 
         let viewport_upper_left =
-            self.position - w.mul(focal_length) - viewport_u.div(2.0) - viewport_v.div(2.0);
+            self.position - w.mul(self.focus_distance) - viewport_u.div(2.0) - viewport_v.div(2.0);
         self.first_pixel_pos =
             viewport_upper_left + (self.pixel_delta_u + self.pixel_delta_v).mul(0.5);
+
+        let defocus_radius =
+            self.focus_distance * f32::tan(self.defocus_angle / 2.0 / 180.0 * consts::PI);
+        self.defocus_disk_u = u.mul(defocus_radius);
+        self.defocus_disk_v = v.mul(defocus_radius);
+    }
+
+    /// Changes the image size in pixels, e.g. after the window was resized.
+    pub fn resize(&mut self, image_width: u32, image_height: u32) {
+        self.image_width = image_width;
+        self.image_height = image_height;
+        self.needs_update = true;
+    }
+
+    pub const fn position(&self) -> Vec3 {
+        self.position
+    }
+
+    pub const fn look_at(&self) -> Vec3 {
+        self.look_at
+    }
+
+    pub const fn vfov(&self) -> f32 {
+        self.vfov
+    }
+
+    /// Parameters of the camera as text, e.g. to store in image metadata.
+    pub fn metadata(&self) -> Vec<(&'static str, String)> {
+        let vector = |v: Vec3| format!("{}, {}, {}", v.x(), v.y(), v.z());
+        vec![
+            ("camera.position", vector(self.position)),
+            ("camera.look_at", vector(self.look_at)),
+            ("camera.vfov", self.vfov.to_string()),
+            ("camera.defocus_angle", self.defocus_angle.to_string()),
+            ("camera.focus_distance", self.focus_distance.to_string()),
+        ]
+    }
+
+    pub const fn focus_distance(&self) -> f32 {
+        self.focus_distance
+    }
+
+    pub fn set_focus_distance(&mut self, focus_distance: f32) {
+        self.focus_distance = focus_distance.max(0.01);
+        self.needs_update = true;
+    }
+
+    pub const fn defocus_angle(&self) -> f32 {
+        self.defocus_angle
+    }
+
+    pub fn set_defocus_angle(&mut self, defocus_angle: f32) {
+        self.defocus_angle = defocus_angle.clamp(0.0, 179.0);
+        self.needs_update = true;
+    }
+
+    /// Ray through the point `(x, y)` of the image in pixels, pixel centers lie on whole
+    /// numbers, starting at `lens` on the unit disk. Returns origin and direction, the same ray
+    /// compute.wgsl generates.
+    pub(crate) fn ray(&self, x: f32, y: f32, lens: Vec3) -> (Vec3, Vec3) {
+        let pixel = self.first_pixel_pos + self.pixel_delta_u.mul(x) + self.pixel_delta_v.mul(y);
+        let origin =
+            self.position + self.defocus_disk_u.mul(lens.x()) + self.defocus_disk_v.mul(lens.y());
+        (origin, (pixel - origin).normalize())
+    }
+
+    /// Angle of the cone a pixel covers from the camera position at the point `(x, y)`, with
+    /// which compute.wgsl picks the texture mip levels.
+    pub(crate) fn pixel_spread(&self, x: f32, y: f32) -> f32 {
+        let pixel = self.first_pixel_pos + self.pixel_delta_u.mul(x) + self.pixel_delta_v.mul(y);
+        self.pixel_delta_u.length() / (pixel - self.position).length()
     }
 
     /// ## WGSL schema:
@@ -74,6 +161,8 @@ impl Camera {
     ///     pixel_delta_u: vec3<f32>,
     ///     pixel_delta_v: vec3<f32>,
     ///     position: vec3<f32>,
+    ///     defocus_disk_u: vec3<f32>, // zero for a pinhole camera
+    ///     defocus_disk_v: vec3<f32>,
     /// }
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::<u8>::new();
@@ -81,8 +170,10 @@ impl Camera {
         bytes.extend_from_slice(&self.pixel_delta_u.to_bytes());
         bytes.extend_from_slice(&self.pixel_delta_v.to_bytes());
         bytes.extend_from_slice(&self.position.to_bytes());
+        bytes.extend_from_slice(&self.defocus_disk_u.to_bytes());
+        bytes.extend_from_slice(&self.defocus_disk_v.to_bytes());
 
-        assert!(bytes.len() % 4 == 0);
+        assert!(bytes.len().is_multiple_of(4));
         bytes
     }
 
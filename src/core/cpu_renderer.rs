@@ -0,0 +1,1836 @@
+use std::{f32::consts::PI, sync::Mutex, thread};
+
+use anyhow::*;
+use image::RgbaImage;
+use rand::{SeedableRng, rngs::SmallRng};
+
+use crate::{
+    core::{
+        bvh::{BVH_STACK_SIZE, BvhNode},
+        camera::Camera,
+        environment::Environment,
+        frame::Frame,
+        light::{LightKind, LightSource},
+        material::Material,
+        medium::Fog,
+        primitive::{PrimitiveKind, PrimitiveRef},
+        scene::Scene,
+        settings::{BounceLimits, RenderSettings},
+        shape::{Planar, PlanarKind},
+        sphere::Sphere,
+        texture::{self, TEXTURE_LAYER_SIZE, TextureKind, TextureMapping, TextureSource},
+    },
+    math::vec::Vec3,
+    util::{random_float, sample_square},
+};
+
+const T_MIN: f32 = 0.001;
+const T_MAX: f32 = 9999.9;
+/// Volume boundaries a ray passes through before a path or shadow ray gives up.
+const MAX_CROSSINGS: u32 = 32;
+
+/// Path tracer running on the CPU, a port of compute.wgsl.
+///
+/// Renders the same scenes with the same materials, lights and light transport as the GPU
+/// `Tracer`, so it serves as a reference for the shader, checked against the GPU's golden
+/// images in tests/golden.rs, and as the fallback on machines without a usable graphics
+/// adapter. Rows are distributed over all cores.
+///
+/// Like the GPU renderer every frame adds `samples_per_frame` samples per pixel. The random
+/// numbers are seeded by pixel, frame and `seed`, so a render is reproducible. They are always
+/// independent, `settings.sampler` is ignored and only changes how fast the GPU renderer
+/// converges.
+pub struct CpuRenderer<'scene> {
+    scene: &'scene Scene,
+    pub camera: Camera,
+    width: u32,
+    height: u32,
+    nodes: Vec<BvhNode>,
+    primitives: Vec<PrimitiveRef>,
+    lights: Vec<LightSource>,
+    /// Mip levels of every layer of the texture array, see `texture::mip_chain`.
+    textures: Vec<Vec<RgbaImage>>,
+    environment_cdf: Vec<f32>,
+    environment_weight_sum: f32,
+    accumulation: Vec<[f32; 3]>,
+    accumulated_frames: u32,
+    accumulated_samples: u32,
+    samples_per_frame: u32,
+    seed: u32,
+    max_bounces: u32,
+    bounce_limits: BounceLimits,
+}
+
+struct Ray {
+    origin: Vec3,
+    dir: Vec3,
+}
+
+impl Ray {
+    fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.dir.mul(t)
+    }
+}
+
+struct Hit {
+    normal: Vec3,
+    collision: Vec3,
+    material: u32,
+    front_face: bool,
+    uv: [f32; 2],
+    /// Tangent along increasing u and the handedness of the bitangent, `None` when the surface
+    /// has no usable uv parametrization.
+    tangent: Option<(Vec3, f32)>,
+    /// Uv distance per world distance along the surface, for picking mip levels.
+    uv_scale: f32,
+    primitive: PrimitiveRef,
+}
+
+/// Kind of scattering event, the index into the per kind bounce counts.
+#[derive(Clone, Copy)]
+enum Bounce {
+    Diffuse = 0,
+    Specular = 1,
+    Transmission = 2,
+}
+
+struct LightSample {
+    dir: Vec3,
+    /// `T_MAX` for directional lights and the environment.
+    distance: f32,
+    radiance: Vec3,
+    /// Solid angle density, 0 for point, spot and directional lights.
+    pdf: f32,
+}
+
+/// Collision of a ray with the fog or a volume, where it scatters.
+struct MediumEvent {
+    distance: f32,
+    albedo: Vec3,
+    anisotropy: f32,
+}
+
+/// Principled BSDF at a hit, see `Surface` in compute.wgsl.
+struct Surface {
+    base_color: Vec3,
+    metallic: f32,
+    roughness: f32,
+    refraction_index: f32,
+    transmission: f32,
+    front_face: bool,
+    /// Complex index of refraction `eta + i k` of the metal, `None` for Schlick's
+    /// approximation.
+    conductor: Option<(Vec3, Vec3)>,
+    /// Shading normal on the side of the incoming ray.
+    normal: Vec3,
+}
+
+impl<'scene> CpuRenderer<'scene> {
+    pub fn new(scene: &'scene Scene, settings: &RenderSettings) -> Self {
+        let (width, height) = (settings.width, settings.height);
+        let camera = Camera::new(
+            width,
+            height,
+            scene.camera.vfov,
+            scene.camera.position,
+            scene.camera.look_at,
+            scene.camera.defocus_angle,
+            scene.camera.focus_distance(),
+        );
+        let (bvh, primitives) = scene.bvh();
+        let (environment_cdf, environment_weight_sum) =
+            Environment::distribution(scene.environment.as_ref());
+
+        Self {
+            scene,
+            camera,
+            width,
+            height,
+            nodes: bvh.nodes,
+            primitives,
+            lights: scene.light_sources(),
+            textures: scene.textures.iter().map(texture::mip_chain).collect(),
+            environment_cdf,
+            environment_weight_sum,
+            accumulation: vec![[0.0; 3]; (width * height) as usize],
+            accumulated_frames: 0,
+            accumulated_samples: 0,
+            samples_per_frame: settings.samples_per_frame.max(1),
+            seed: settings.seed,
+            max_bounces: settings.max_bounces,
+            bounce_limits: settings.bounce_limits,
+        }
+    }
+
+    /// Accumulates until `samples` samples per pixel are reached and returns the averaged
+    /// image.
+    pub fn render(&mut self, samples: u32) -> Result<Frame> {
+        if self.camera.needs_update() {
+            self.reset_accumulation();
+        }
+        self.camera.update();
+
+        while self.accumulated_samples < samples {
+            let frame_samples = self
+                .samples_per_frame
+                .min(samples - self.accumulated_samples);
+            self.render_frame(frame_samples);
+        }
+
+        let samples = self.samples().max(1) as f32;
+        let pixels = self
+            .accumulation
+            .iter()
+            .map(|[r, g, b]| [r / samples, g / samples, b / samples, 1.0])
+            .collect();
+        Ok(Frame::new(self.width, self.height, pixels))
+    }
+
+    /// Number of samples per pixel accumulated so far.
+    pub fn samples(&self) -> u32 {
+        self.accumulated_samples
+    }
+
+    /// Changes the seed of the random numbers and restarts the accumulation.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+        self.reset_accumulation();
+    }
+
+    fn reset_accumulation(&mut self) {
+        self.accumulated_frames = 0;
+        self.accumulated_samples = 0;
+        self.accumulation.fill([0.0; 3]);
+    }
+
+    fn render_frame(&mut self, samples: u32) {
+        let width = self.width as usize;
+        let frame = self.accumulated_frames;
+        let mut accumulation = std::mem::take(&mut self.accumulation);
+        let rows = Mutex::new(accumulation.chunks_mut(width).enumerate());
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    loop {
+                        let Some((y, row)) = rows.lock().unwrap().next() else {
+                            break;
+                        };
+                        for (x, pixel) in row.iter_mut().enumerate() {
+                            let color = self.render_pixel(x as u32, y as u32, frame, samples);
+                            for channel in 0..3 {
+                                pixel[channel] += color[channel];
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        self.accumulation = accumulation;
+        self.accumulated_frames += 1;
+        self.accumulated_samples += samples;
+    }
+
+    /// Sum of the `samples` samples of one frame for one pixel.
+    fn render_pixel(&self, x: u32, y: u32, frame: u32, samples: u32) -> Vec3 {
+        let pixel_index = (y * self.width + x) as u64;
+        let seed =
+            splitmix64(splitmix64(splitmix64(self.seed as u64) ^ frame as u64) ^ pixel_index);
+        let mut rng = SmallRng::seed_from_u64(seed);
+
+        let mut color = Vec3::zero();
+        for _ in 0..samples {
+            let offset = sample_square(&mut rng);
+            let lens = Vec3::random_in_unit_disk(&mut rng);
+            let (x, y) = (x as f32 + offset.x(), y as f32 + offset.y());
+            let (origin, dir) = self.camera.ray(x, y, lens);
+            let spread = self.camera.pixel_spread(x, y);
+            color += self.radiance(Ray { origin, dir }, spread, &mut rng);
+        }
+        color
+    }
+
+    /// `spread` is the angle of the pixel's ray cone, whose width at a hit picks the texture mip
+    /// level.
+    fn radiance(&self, ray: Ray, spread: f32, rng: &mut SmallRng) -> Vec3 {
+        let mut current_ray = ray;
+        let mut cone_spread = spread;
+        let mut cone_width = 0.0;
+        let mut attenuation = Vec3::new(1.0, 1.0, 1.0);
+        let mut radiance = Vec3::zero();
+        // Density with which the previous surface's BSDF picked `current_ray`, 0 for camera
+        // rays and smooth lobes, which light sampling can't reproduce
+        let mut bsdf_pdf = 0.0;
+        let epsilon = 0.001;
+        // Bounces so far per kind, see `count_bounce`
+        let mut bounces = [0; 3];
+        // Where the path last scattered, light densities are measured from there
+        let mut scatter_origin = current_ray.origin;
+        // Index of the volume material the ray travels through
+        let mut medium = None;
+        let mut crossings = 0;
+        let mut crossed = false;
+
+        let mut bounce = 0;
+        while bounce <= self.max_bounces {
+            // Russian roulette, see `BounceLimits`. Passing through a volume boundary continues
+            // the bounce.
+            if !crossed && bounce >= self.bounce_limits.russian_roulette_depth {
+                let survival = attenuation
+                    .x()
+                    .max(attenuation.y())
+                    .max(attenuation.z())
+                    .min(0.95);
+                if random_float(rng) >= survival {
+                    break;
+                }
+                attenuation = attenuation.div(survival);
+            }
+            crossed = false;
+
+            let hit = self.hit_scene(&current_ray, T_MAX);
+            let surface_distance = hit
+                .as_ref()
+                .map_or(T_MAX, |hit| (hit.collision - current_ray.origin).length());
+            if let Some(event) = self.sample_medium(&current_ray, surface_distance, medium, rng) {
+                // Scattering in the fog or a volume counts as a diffuse bounce
+                let position = current_ray.at(event.distance);
+                attenuation *= event.albedo;
+                radiance += attenuation
+                    * self.medium_direct_light(
+                        position,
+                        current_ray.dir,
+                        event.anisotropy,
+                        medium,
+                        rng,
+                    );
+                if !self.count_bounce(&mut bounces, Bounce::Diffuse) {
+                    break;
+                }
+                let dir = sample_henyey_greenstein(
+                    current_ray.dir,
+                    event.anisotropy,
+                    [random_float(rng), random_float(rng)],
+                );
+                bsdf_pdf = henyey_greenstein(current_ray.dir.dot(&dir), event.anisotropy);
+                cone_width += cone_spread * event.distance;
+                cone_spread += 1.0;
+                current_ray = Ray {
+                    origin: position,
+                    dir,
+                };
+                scatter_origin = position;
+                bounce += 1;
+                continue;
+            }
+
+            let Some(hit) = hit else {
+                // The environment map is also sampled as a light, the sky gradient isn't
+                let mut weight = 1.0;
+                if self.scene.environment.is_some() && bsdf_pdf > 0.0 {
+                    let light_pdf =
+                        self.environment_pdf(current_ray.dir) / self.lights.len() as f32;
+                    weight = power_heuristic(bsdf_pdf, light_pdf);
+                }
+                radiance += attenuation * self.environment_color(current_ray.dir).mul(weight);
+                break;
+            };
+
+            cone_width += cone_spread * surface_distance;
+            let lod = texture_lod(&hit, cone_width, current_ray.dir.dot(&hit.normal));
+
+            let material = &self.scene.materials[hit.material as usize];
+            if let Material::Volume(_) = material {
+                // The ray enters or leaves the medium unchanged
+                crossings += 1;
+                if crossings > MAX_CROSSINGS {
+                    break;
+                }
+                medium = hit.front_face.then_some(hit.material);
+                current_ray = Ray {
+                    origin: hit.collision,
+                    dir: current_ray.dir,
+                };
+                crossed = true;
+                continue;
+            }
+            if material.is_emissive() {
+                // The part of the light that next event estimation at the previous surface
+                // already picked up is weighted out
+                if hit.front_face {
+                    let mut weight = 1.0;
+                    if bsdf_pdf > 0.0 {
+                        weight = power_heuristic(bsdf_pdf, self.light_pdf(&hit, scatter_origin));
+                    }
+                    radiance += attenuation
+                        * self
+                            .emission(hit.material, hit.uv, hit.collision, lod)
+                            .mul(weight);
+                }
+                break;
+            }
+
+            let mut surface = self.surface(material, &hit, lod);
+            let out_dir = -current_ray.dir.normalize();
+            if out_dir.dot(&surface.normal) <= 0.0 {
+                // A normal map turned the surface away from the ray
+                surface.normal = hit.normal;
+            }
+            let frame = basis(surface.normal);
+            let wo = to_local(&frame, out_dir);
+            let wo = Vec3::new(wo.x(), wo.y(), wo.z().max(1e-6));
+
+            radiance += attenuation * self.direct_light(&hit, &surface, wo, medium, rng);
+
+            let Some(scattered) = sample_bsdf(&surface, wo, rng) else {
+                break;
+            };
+            let dir = to_world(&frame, scattered.dir);
+            // Directions the shading normal allows but the geometry doesn't end the path
+            let side = dir.dot(&hit.normal);
+            if side * scattered.dir.z() <= 0.0 || !self.count_bounce(&mut bounces, scattered.kind) {
+                break;
+            }
+
+            current_ray = Ray {
+                origin: hit.collision + hit.normal.mul(epsilon * side.signum()),
+                dir,
+            };
+            scatter_origin = current_ray.origin;
+            bsdf_pdf = scattered.pdf;
+            attenuation *= scattered.weight;
+            // Diffuse bounces widen the cone to about a radian, glossy ones by their lobe's width
+            cone_spread += match scattered.kind {
+                Bounce::Diffuse => 1.0,
+                Bounce::Specular | Bounce::Transmission => surface.roughness * surface.roughness,
+            };
+            bounce += 1;
+        }
+
+        radiance
+    }
+
+    /// Counts a bounce of the given kind, false when the path already used up the limit for it.
+    fn count_bounce(&self, bounces: &mut [u32; 3], kind: Bounce) -> bool {
+        let limit = match kind {
+            Bounce::Diffuse => self.bounce_limits.diffuse,
+            Bounce::Specular => self.bounce_limits.specular,
+            Bounce::Transmission => self.bounce_limits.transmission,
+        };
+        let count = &mut bounces[kind as usize];
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Closest hit in `(T_MIN, max_t)`.
+    fn hit_scene(&self, ray: &Ray, max_t: f32) -> Option<Hit> {
+        // Avoid infinities for axis aligned rays
+        let inv_dir = Vec3::new(
+            1.0 / safe_component(ray.dir.x()),
+            1.0 / safe_component(ray.dir.y()),
+            1.0 / safe_component(ray.dir.z()),
+        );
+
+        let mut closest_t = max_t;
+        let mut closest: Option<(PrimitiveRef, [f32; 2])> = None;
+
+        if hit_aabb(ray, inv_dir, &self.nodes[0], closest_t) >= T_MAX {
+            return None;
+        }
+
+        let mut stack = [0usize; BVH_STACK_SIZE];
+        let mut stack_size = 0;
+        let mut node_index = 0;
+        loop {
+            let node = &self.nodes[node_index];
+            let first = node.left_or_first as usize;
+            if node.count > 0 {
+                for &primitive in &self.primitives[first..first + node.count as usize] {
+                    let index = primitive.index as usize;
+                    match primitive.kind {
+                        PrimitiveKind::Sphere => {
+                            let t = hit_sphere(&self.scene.spheres[index], ray, closest_t);
+                            if t < closest_t {
+                                closest_t = t;
+                                closest = Some((primitive, [0.0, 0.0]));
+                            }
+                        }
+                        PrimitiveKind::Triangle => {
+                            if let Some((t, barycentric)) = self.hit_triangle(index, ray, closest_t)
+                            {
+                                closest_t = t;
+                                closest = Some((primitive, barycentric));
+                            }
+                        }
+                        PrimitiveKind::Planar => {
+                            let planar = &self.scene.planars[index];
+                            if let Some((t, coordinates)) = hit_planar(planar, ray, closest_t) {
+                                closest_t = t;
+                                closest = Some((primitive, coordinates));
+                            }
+                        }
+                    }
+                }
+            } else {
+                // Descend into the nearer child first and keep the other one for later
+                let t_left = hit_aabb(ray, inv_dir, &self.nodes[first], closest_t);
+                let t_right = hit_aabb(ray, inv_dir, &self.nodes[first + 1], closest_t);
+                let (near, far, t_near, t_far) = if t_right < t_left {
+                    (first + 1, first, t_right, t_left)
+                } else {
+                    (first, first + 1, t_left, t_right)
+                };
+
+                if t_near < T_MAX {
+                    if t_far < T_MAX && stack_size < BVH_STACK_SIZE {
+                        stack[stack_size] = far;
+                        stack_size += 1;
+                    }
+                    node_index = near;
+                    continue;
+                }
+            }
+
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            node_index = stack[stack_size];
+        }
+
+        let (primitive, barycentric) = closest?;
+        let index = primitive.index as usize;
+        Some(match primitive.kind {
+            PrimitiveKind::Sphere => {
+                sphere_hit(&self.scene.spheres[index], ray, closest_t, primitive)
+            }
+            PrimitiveKind::Triangle => {
+                self.triangle_hit(index, ray, closest_t, barycentric, primitive)
+            }
+            PrimitiveKind::Planar => planar_hit(
+                &self.scene.planars[index],
+                ray,
+                closest_t,
+                barycentric,
+                primitive,
+            ),
+        })
+    }
+
+    fn triangle_positions(&self, index: usize) -> [Vec3; 3] {
+        self.scene.triangles[index]
+            .vertices
+            .map(|vertex| self.scene.vertices[vertex as usize].position)
+    }
+
+    /// Möller–Trumbore intersection, returns the distance and the barycentric coordinates of
+    /// the second and third vertex.
+    fn hit_triangle(&self, index: usize, ray: &Ray, max_t: f32) -> Option<(f32, [f32; 2])> {
+        let [p0, p1, p2] = self.triangle_positions(index);
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+
+        let p = ray.dir.cross(&edge2);
+        let determinant = edge1.dot(&p);
+        if determinant.abs() < 1e-9 {
+            return None;
+        }
+        let inv_determinant = 1.0 / determinant;
+
+        let s = ray.origin - p0;
+        let b1 = s.dot(&p) * inv_determinant;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+
+        let q = s.cross(&edge1);
+        let b2 = ray.dir.dot(&q) * inv_determinant;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+
+        let t = edge2.dot(&q) * inv_determinant;
+        if !(T_MIN..max_t).contains(&t) {
+            return None;
+        }
+        Some((t, [b1, b2]))
+    }
+
+    fn triangle_hit(
+        &self,
+        index: usize,
+        ray: &Ray,
+        t: f32,
+        [b1, b2]: [f32; 2],
+        primitive: PrimitiveRef,
+    ) -> Hit {
+        let triangle = &self.scene.triangles[index];
+        let [a, b, c] = triangle
+            .vertices
+            .map(|vertex| &self.scene.vertices[vertex as usize]);
+        let weights = [1.0 - b1 - b2, b1, b2];
+
+        let geometric_normal = (b.position - a.position)
+            .cross(&(c.position - a.position))
+            .normalize();
+        let mut normal =
+            a.normal.mul(weights[0]) + b.normal.mul(weights[1]) + c.normal.mul(weights[2]);
+        if normal.length_squared() < 1e-12 {
+            normal = geometric_normal;
+        }
+        normal = normal.normalize();
+
+        let front_face = ray.dir.dot(&geometric_normal) < 0.0;
+        if normal.dot(&ray.dir) > 0.0 {
+            normal = -normal;
+        }
+
+        let uv = [0, 1].map(|i| weights[0] * a.uv[i] + weights[1] * b.uv[i] + weights[2] * c.uv[i]);
+
+        // Tangent from the uv derivatives, orthogonalized against the shading normal
+        let edge1 = b.position - a.position;
+        let edge2 = c.position - a.position;
+        let duv1 = [b.uv[0] - a.uv[0], b.uv[1] - a.uv[1]];
+        let duv2 = [c.uv[0] - a.uv[0], c.uv[1] - a.uv[1]];
+        let determinant = duv1[0] * duv2[1] - duv1[1] * duv2[0];
+        let mut tangent = None;
+        if determinant.abs() >= 1e-12 {
+            let u_tangent = (edge1.mul(duv2[1]) - edge2.mul(duv1[1])).div(determinant);
+            let bitangent = (edge2.mul(duv1[0]) - edge1.mul(duv2[0])).div(determinant);
+            let orthogonal = u_tangent - normal.mul(normal.dot(&u_tangent));
+            if orthogonal.length_squared() >= 1e-12 {
+                let handedness = if normal.cross(&orthogonal).dot(&bitangent) < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                };
+                tangent = Some((orthogonal.normalize(), handedness));
+            }
+        }
+
+        let uv_area = (duv1[0] * duv2[1] - duv1[1] * duv2[0]).abs();
+        let uv_scale = (uv_area / edge1.cross(&edge2).length()).sqrt();
+
+        Hit {
+            normal,
+            collision: ray.at(t),
+            material: triangle.material,
+            front_face,
+            uv,
+            tangent,
+            uv_scale,
+            primitive,
+        }
+    }
+
+    fn triangle_area(&self, index: usize) -> f32 {
+        let [p0, p1, p2] = self.triangle_positions(index);
+        0.5 * (p1 - p0).cross(&(p2 - p0)).length()
+    }
+
+    /// Light emitted by an emissive material at a point with uv coordinates `uv`, black for
+    /// every other kind.
+    fn emission(&self, material: u32, uv: [f32; 2], position: Vec3, lod: f32) -> Vec3 {
+        let Material::Emissive(emissive) = &self.scene.materials[material as usize] else {
+            return Vec3::zero();
+        };
+        let mut color = emissive.color;
+        if let Some(layer) = emissive.emission_texture {
+            color *= self.sample_color(layer, uv, position, lod);
+        }
+        color.mul(emissive.intensity)
+    }
+
+    fn sample_light(
+        &self,
+        light: &LightSource,
+        position: Vec3,
+        rng: &mut SmallRng,
+    ) -> Option<LightSample> {
+        match light.kind {
+            LightKind::Environment => self.sample_environment(rng),
+            LightKind::Directional => Some(LightSample {
+                dir: -light.direction,
+                distance: T_MAX,
+                radiance: light.emission,
+                pdf: 0.0,
+            }),
+            LightKind::Point | LightKind::Spot => {
+                let to_light = light.position - position;
+                let distance2 = to_light.length_squared();
+                let dir = to_light.div(distance2.sqrt());
+                let mut radiance = light.emission.div(distance2);
+                if light.kind == LightKind::Spot {
+                    radiance = radiance.mul(smoothstep(
+                        light.cos_outer,
+                        light.cos_inner,
+                        (-dir).dot(&light.direction),
+                    ));
+                }
+                Some(LightSample {
+                    dir,
+                    distance: distance2.sqrt(),
+                    radiance,
+                    pdf: 0.0,
+                })
+            }
+            LightKind::Sphere => {
+                // Uniformly sample the cone of directions the sphere covers
+                let sphere = &self.scene.spheres[light.index as usize];
+                let cone = sphere_cone(sphere, position);
+                if cone <= 0.0 {
+                    return None;
+                }
+                let cos_theta = 1.0 - random_float(rng) * cone;
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * random_float(rng);
+                let [u, v, w] = basis((sphere.center - position).normalize());
+                let dir =
+                    u.mul(sin_theta * phi.cos()) + v.mul(sin_theta * phi.sin()) + w.mul(cos_theta);
+
+                let mut distance = hit_sphere(
+                    sphere,
+                    &Ray {
+                        origin: position,
+                        dir,
+                    },
+                    T_MAX,
+                );
+                if distance >= T_MAX {
+                    // Grazing direction lost to rounding
+                    distance = (sphere.center - position).length();
+                }
+                let point = position + dir.mul(distance);
+                let uv = sphere_uv((point - sphere.center).normalize());
+                Some(LightSample {
+                    dir,
+                    distance,
+                    radiance: self.emission(sphere.material, uv, point, 0.0),
+                    pdf: 1.0 / (2.0 * PI * cone),
+                })
+            }
+            LightKind::Triangle => {
+                // Uniformly sample the triangle's area
+                let index = light.index as usize;
+                let triangle = &self.scene.triangles[index];
+                let [v0, v1, v2] = triangle
+                    .vertices
+                    .map(|vertex| &self.scene.vertices[vertex as usize]);
+                let su = random_float(rng).sqrt();
+                let b1 = 1.0 - su;
+                let b2 = random_float(rng) * su;
+                let (p0, p1, p2) = (v0.position, v1.position, v2.position);
+                let point = p0 + (p1 - p0).mul(b1) + (p2 - p0).mul(b2);
+                let light_normal = (p1 - p0).cross(&(p2 - p0)).normalize();
+                let uv = [0, 1].map(|i| (1.0 - b1 - b2) * v0.uv[i] + b1 * v1.uv[i] + b2 * v2.uv[i]);
+                area_light_sample(
+                    position,
+                    point,
+                    light_normal,
+                    self.triangle_area(index),
+                    self.emission(triangle.material, uv, point, 0.0),
+                )
+            }
+            LightKind::Planar => {
+                // Uniformly sample the quad's or disk's area
+                let planar = &self.scene.planars[light.index as usize];
+                let mut coordinates = [random_float(rng), random_float(rng)];
+                if planar.kind == PlanarKind::Disk {
+                    let radius = coordinates[0].sqrt();
+                    let phi = 2.0 * PI * coordinates[1];
+                    coordinates = [radius * phi.cos(), radius * phi.sin()];
+                }
+                let point = planar.origin
+                    + planar.edge_u.mul(coordinates[0])
+                    + planar.edge_v.mul(coordinates[1]);
+                area_light_sample(
+                    position,
+                    point,
+                    planar.normal(),
+                    planar.area(),
+                    self.emission(planar.material, planar_uv(planar, coordinates), point, 0.0),
+                )
+            }
+        }
+    }
+
+    /// Solid angle density with which light sampling from `origin` would have picked the
+    /// emitter `hit`, including the choice of the light.
+    fn light_pdf(&self, hit: &Hit, origin: Vec3) -> f32 {
+        let light_count = self.lights.len() as f32;
+        let index = hit.primitive.index as usize;
+        match hit.primitive.kind {
+            PrimitiveKind::Sphere => {
+                let cone = sphere_cone(&self.scene.spheres[index], origin);
+                if cone <= 0.0 {
+                    return 0.0;
+                }
+                1.0 / (2.0 * PI * cone * light_count)
+            }
+            PrimitiveKind::Triangle => {
+                let [p0, p1, p2] = self.triangle_positions(index);
+                let light_normal = (p1 - p0).cross(&(p2 - p0)).normalize();
+                let area = self.triangle_area(index);
+                area_light_pdf(hit.collision - origin, light_normal, area) / light_count
+            }
+            PrimitiveKind::Planar => {
+                let planar = &self.scene.planars[index];
+                if planar.kind == PlanarKind::Plane {
+                    // Planes are never sampled as lights
+                    return 0.0;
+                }
+                area_light_pdf(hit.collision - origin, planar.normal(), planar.area()) / light_count
+            }
+        }
+    }
+
+    /// Light sample of a uniformly picked light, see `sample_lights` in compute.wgsl.
+    fn sample_lights(&self, position: Vec3, rng: &mut SmallRng) -> Option<LightSample> {
+        let light_count = self.lights.len();
+        if light_count == 0 {
+            return None;
+        }
+        let index = ((random_float(rng) * light_count as f32) as usize).min(light_count - 1);
+        self.sample_light(&self.lights[index], position, rng)
+            .filter(|sample| sample.radiance.length_squared() > 0.0)
+    }
+
+    /// Contribution of a sample from `sample_lights` that arrives with the fraction
+    /// `visibility` and is scattered by `value` of a BSDF or phase function sampled with
+    /// density `scattering_pdf`.
+    fn light_contribution(
+        &self,
+        sample: &LightSample,
+        visibility: f32,
+        value: Vec3,
+        scattering_pdf: f32,
+    ) -> Vec3 {
+        let light_count = self.lights.len() as f32;
+        let contribution = (value * sample.radiance).mul(visibility);
+        if sample.pdf == 0.0 {
+            // Point, spot and directional lights can't be hit, light sampling is all there is
+            return contribution.mul(light_count);
+        }
+        let light_pdf = sample.pdf / light_count;
+        contribution.mul(power_heuristic(light_pdf, scattering_pdf) / light_pdf)
+    }
+
+    /// Next event estimation through the BSDF, see `direct_light` in compute.wgsl.
+    fn direct_light(
+        &self,
+        hit: &Hit,
+        surface: &Surface,
+        wo: Vec3,
+        medium: Option<u32>,
+        rng: &mut SmallRng,
+    ) -> Vec3 {
+        let Some(sample) = self.sample_lights(hit.collision, rng) else {
+            return Vec3::zero();
+        };
+
+        // Light from below can only arrive through the glass lobe, and only when the shading
+        // and the geometric normal agree about the side
+        let wi = to_local(&basis(surface.normal), sample.dir);
+        let side = sample.dir.dot(&hit.normal);
+        if side * wi.z() <= 0.0 {
+            return Vec3::zero();
+        }
+        let (value, bsdf_pdf) = eval_bsdf(surface, wo, wi);
+        if value.length_squared() == 0.0 {
+            return Vec3::zero();
+        }
+        let shadow_ray = Ray {
+            origin: hit.collision + hit.normal.mul(0.001 * side.signum()),
+            dir: sample.dir,
+        };
+        let visibility = self.transmittance(&shadow_ray, sample.distance * 0.999, medium);
+        if visibility == 0.0 {
+            return Vec3::zero();
+        }
+        self.light_contribution(&sample, visibility, value, bsdf_pdf)
+    }
+
+    /// Next event estimation through the phase function, see `medium_direct_light` in
+    /// compute.wgsl.
+    fn medium_direct_light(
+        &self,
+        position: Vec3,
+        dir: Vec3,
+        anisotropy: f32,
+        medium: Option<u32>,
+        rng: &mut SmallRng,
+    ) -> Vec3 {
+        let Some(sample) = self.sample_lights(position, rng) else {
+            return Vec3::zero();
+        };
+        let shadow_ray = Ray {
+            origin: position,
+            dir: sample.dir,
+        };
+        let visibility = self.transmittance(&shadow_ray, sample.distance * 0.999, medium);
+        if visibility == 0.0 {
+            return Vec3::zero();
+        }
+        let phase = henyey_greenstein(dir.dot(&sample.dir), anisotropy);
+        let value = Vec3::new(phase, phase, phase);
+        self.light_contribution(&sample, visibility, value, phase)
+    }
+
+    /// Density and scattering of the volume material `index`.
+    fn volume(&self, index: u32) -> (f32, Vec3, f32) {
+        let Material::Volume(volume) = &self.scene.materials[index as usize] else {
+            unreachable!("media are volume materials");
+        };
+        (volume.density, volume.albedo, volume.anisotropy)
+    }
+
+    /// Free flight sampling through the fog and `medium` up to the next surface at `max_t`, see
+    /// `sample_medium` in compute.wgsl.
+    fn sample_medium(
+        &self,
+        ray: &Ray,
+        max_t: f32,
+        medium: Option<u32>,
+        rng: &mut SmallRng,
+    ) -> Option<MediumEvent> {
+        let fog_random = random_float(rng);
+        let volume_random = random_float(rng);
+
+        let mut event = None;
+        if let Some(fog) = &self.scene.fog {
+            let (start, end) = fog_interval(fog, ray, max_t);
+            let t = start - (1.0 - fog_random).ln() / fog.density;
+            if end > start && t < end {
+                event = Some(MediumEvent {
+                    distance: t,
+                    albedo: fog.albedo,
+                    anisotropy: fog.anisotropy,
+                });
+            }
+        }
+        if let Some(index) = medium {
+            let (density, albedo, anisotropy) = self.volume(index);
+            let t = -(1.0 - volume_random).ln() / density;
+            if t < event
+                .as_ref()
+                .map_or(max_t, |event| event.distance.min(max_t))
+            {
+                event = Some(MediumEvent {
+                    distance: t,
+                    albedo,
+                    anisotropy,
+                });
+            }
+        }
+        event
+    }
+
+    /// Fraction of the light travelling `max_t` along the ray that makes it through the fog and
+    /// volumes, 0 when a surface blocks it.
+    fn transmittance(&self, ray: &Ray, max_t: f32, medium: Option<u32>) -> f32 {
+        let mut optical_depth = self.scene.fog.as_ref().map_or(0.0, |fog| {
+            let (start, end) = fog_interval(fog, ray, max_t);
+            fog.density * (end - start)
+        });
+        let mut current = medium;
+        let mut origin = ray.origin;
+        let mut remaining = max_t;
+        for _ in 0..MAX_CROSSINGS {
+            let segment_ray = Ray {
+                origin,
+                dir: ray.dir,
+            };
+            let hit = self.hit_scene(&segment_ray, remaining);
+            let segment = hit
+                .as_ref()
+                .map_or(remaining, |hit| (hit.collision - origin).length());
+            if let Some(index) = current {
+                optical_depth += self.volume(index).0 * segment;
+            }
+            let Some(hit) = hit else {
+                return (-optical_depth).exp();
+            };
+            if !matches!(
+                self.scene.materials[hit.material as usize],
+                Material::Volume(_)
+            ) {
+                return 0.0;
+            }
+            current = hit.front_face.then_some(hit.material);
+            origin = hit.collision;
+            remaining -= segment;
+        }
+        0.0
+    }
+
+    /// Texel of the environment map seen in direction `dir`.
+    fn environment_texel(&self, environment: &Environment, dir: Vec3) -> (u32, u32) {
+        let (width, height) = environment
+            .image
+            .as_ref()
+            .map_or((1, 1), |image| image.dimensions());
+        let phi = dir.z().atan2(dir.x()) + environment.rotation.to_radians();
+        let u = (phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = dir.y().clamp(-1.0, 1.0).acos() / PI;
+        (
+            ((u * width as f32) as u32).min(width - 1),
+            ((v * height as f32) as u32).min(height - 1),
+        )
+    }
+
+    fn environment_value(&self, environment: &Environment, texel: (u32, u32)) -> Vec3 {
+        environment.image.as_ref().map_or(Vec3::zero(), |image| {
+            let [r, g, b, _] = image.get_pixel(texel.0, texel.1).0;
+            Vec3::new(r, g, b)
+        })
+    }
+
+    /// Light arriving from infinitely far away in direction `dir`.
+    fn environment_color(&self, dir: Vec3) -> Vec3 {
+        let dir = dir.normalize();
+        let Some(environment) = &self.scene.environment else {
+            let a = 0.5 * (dir.y() + 1.0);
+            return Vec3::new(1.0, 1.0, 1.0).mul(1.0 - a) + Vec3::new(0.5, 0.7, 1.0).mul(a);
+        };
+        let texel = self.environment_texel(environment, dir);
+        self.environment_value(environment, texel)
+            .mul(environment.intensity)
+    }
+
+    /// Solid angle density with which `sample_environment` picks `dir`.
+    fn environment_pdf(&self, dir: Vec3) -> f32 {
+        let Some(environment) = &self.scene.environment else {
+            return 0.0;
+        };
+        let dir = dir.normalize();
+        let sin_theta = (1.0 - dir.y() * dir.y()).max(0.0).sqrt();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        let (width, height) = environment
+            .image
+            .as_ref()
+            .map_or((1, 1), |image| image.dimensions());
+        let texel = self.environment_texel(environment, dir);
+        let texel_sin_theta = (PI * (texel.1 as f32 + 0.5) / height as f32).sin();
+        let weight = luminance(self.environment_value(environment, texel)) * texel_sin_theta;
+        weight / self.environment_weight_sum * (width * height) as f32 / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Picks a texel by its luminance and a direction within it.
+    fn sample_environment(&self, rng: &mut SmallRng) -> Option<LightSample> {
+        let environment = self.scene.environment.as_ref()?;
+        let (width, height) = environment.image.as_ref()?.dimensions();
+        let (width, height) = (width as usize, height as usize);
+        let row_cdf = &self.environment_cdf[width * height..];
+        let y = search_cdf(row_cdf, random_float(rng));
+        let x = search_cdf(
+            &self.environment_cdf[y * width..(y + 1) * width],
+            random_float(rng),
+        );
+
+        let u = (x as f32 + random_float(rng)) / width as f32;
+        let v = (y as f32 + random_float(rng)) / height as f32;
+        let phi = 2.0 * PI * u - environment.rotation.to_radians();
+        let theta = PI * v;
+        let dir = Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        );
+
+        let pdf = self.environment_pdf(dir);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            dir,
+            distance: T_MAX,
+            radiance: self.environment_color(dir),
+            pdf,
+        })
+    }
+
+    /// Value of a scene texture at a point with uv coordinates `uv`, see `sample_texture` in
+    /// compute.wgsl. Images are filtered trilinearly at mip level `lod` with repeating uvs,
+    /// like the sampler of the texture array.
+    fn sample_texture(&self, index: u32, uv: [f32; 2], position: Vec3, lod: f32) -> [f32; 4] {
+        let source = &self.scene.texture_sources[index as usize];
+        if source.kind != TextureKind::Image {
+            let color = procedural_texture(source, uv, position);
+            return [color.x(), color.y(), color.z(), 1.0];
+        }
+
+        let levels = &self.textures[source.layer as usize];
+        let lod = lod.clamp(0.0, (levels.len() - 1) as f32);
+        let level = lod.floor() as usize;
+        let fraction = lod - level as f32;
+        let fine = sample_bilinear(&levels[level], uv);
+        if fraction == 0.0 {
+            return fine;
+        }
+        let coarse = sample_bilinear(&levels[level + 1], uv);
+        [0, 1, 2, 3].map(|i| fine[i] + (coarse[i] - fine[i]) * fraction)
+    }
+
+    /// Linear color of a texture, images are sRGB encoded.
+    fn sample_color(&self, index: u32, uv: [f32; 2], position: Vec3, lod: f32) -> Vec3 {
+        let [r, g, b, _] = self.sample_texture(index, uv, position, lod);
+        if self.scene.texture_sources[index as usize].kind != TextureKind::Image {
+            return Vec3::new(r, g, b);
+        }
+        Vec3::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b))
+    }
+
+    /// The principled BSDF a material is packed into, with its textures applied at a hit. See
+    /// `Material::to_bytes` and `material_surface` in compute.wgsl.
+    fn surface(&self, material: &Material, hit: &Hit, lod: f32) -> Surface {
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let mut surface = Surface {
+            base_color: white,
+            metallic: 0.0,
+            roughness: 1.0,
+            refraction_index: 1.0,
+            transmission: 0.0,
+            front_face: hit.front_face,
+            conductor: None,
+            normal: hit.normal,
+        };
+        match material {
+            Material::Diffuse(diffuse) => surface.base_color = diffuse.albedo,
+            Material::Metallic(metallic) => {
+                surface.base_color = metallic.albedo;
+                surface.metallic = 1.0;
+                surface.roughness = metallic.roughness;
+                surface.conductor = metallic.eta.zip(metallic.k);
+            }
+            Material::Dielectric(dielectric) => {
+                surface.roughness = dielectric.roughness;
+                surface.refraction_index = dielectric.refraction_index;
+                surface.transmission = 1.0;
+            }
+            Material::Pbr(pbr) => {
+                surface.base_color = pbr.base_color;
+                surface.metallic = pbr.metallic;
+                surface.roughness = pbr.roughness;
+                surface.refraction_index = pbr.refraction_index;
+                surface.transmission = pbr.transmission;
+            }
+            Material::Emissive(_) => unreachable!("emissive materials don't scatter"),
+            Material::Volume(_) => unreachable!("volumes are passed through"),
+        }
+
+        let [
+            color_texture,
+            roughness_texture,
+            metallic_texture,
+            normal_texture,
+        ] = material.textures();
+        let position = hit.collision;
+        if let Some(texture) = color_texture {
+            surface.base_color *= self.sample_color(texture, hit.uv, position, lod);
+        }
+        if let Some(texture) = roughness_texture {
+            surface.roughness *= self.sample_texture(texture, hit.uv, position, lod)[1];
+        }
+        if let Some(texture) = metallic_texture {
+            surface.metallic *= self.sample_texture(texture, hit.uv, position, lod)[2];
+        }
+        if let (Some(texture), Some((tangent, handedness))) = (normal_texture, hit.tangent) {
+            let normal = hit.normal;
+            let bitangent = normal.cross(&tangent).mul(handedness);
+            let [x, y, z, _] = self
+                .sample_texture(texture, hit.uv, position, lod)
+                .map(|c| c * 2.0 - 1.0);
+            surface.normal = (tangent.mul(x) + bitangent.mul(y) + normal.mul(z)).normalize();
+        }
+        surface
+    }
+}
+
+/// SplitMix64 finalizer, a bijective hash that mixes every input bit into the whole output.
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn safe_component(value: f32) -> f32 {
+    if value.abs() < 1e-8 { 1e-8 } else { value }
+}
+
+/// Distance at which the ray enters the node's box, or `T_MAX` when it misses it or enters
+/// beyond `max_t`.
+fn hit_aabb(ray: &Ray, inv_dir: Vec3, node: &BvhNode, max_t: f32) -> f32 {
+    let mut t_near = f32::MIN;
+    let mut t_far = f32::MAX;
+    for axis in 0..3 {
+        let t0 = (node.bounds.min[axis] - ray.origin[axis]) * inv_dir[axis];
+        let t1 = (node.bounds.max[axis] - ray.origin[axis]) * inv_dir[axis];
+        t_near = t_near.max(t0.min(t1));
+        t_far = t_far.min(t0.max(t1));
+    }
+
+    if t_near <= t_far && t_far > 0.0 && t_near < max_t {
+        return t_near;
+    }
+    T_MAX
+}
+
+/// Closest hit distance in `(T_MIN, max_t)`, or `max_t` on a miss.
+fn hit_sphere(sphere: &Sphere, ray: &Ray, max_t: f32) -> f32 {
+    let oc = ray.origin - sphere.center;
+    let a = ray.dir.length_squared();
+    let h = oc.dot(&ray.dir);
+    let c = oc.length_squared() - sphere.radius * sphere.radius;
+
+    let discriminant = h * h - a * c;
+    if discriminant >= 0.0 {
+        let sqrtd = discriminant.sqrt();
+        let mut t = (-h - sqrtd) / a;
+        if t < T_MIN || t > max_t {
+            t = (-h + sqrtd) / a;
+        }
+        if t >= T_MIN && t < max_t {
+            return t;
+        }
+    }
+    max_t
+}
+
+fn sphere_hit(sphere: &Sphere, ray: &Ray, t: f32, primitive: PrimitiveRef) -> Hit {
+    let collision = ray.at(t);
+    let mut normal = (collision - sphere.center).div(sphere.radius);
+    let uv = sphere_uv(normal);
+    let front_face = ray.dir.dot(&normal) < 0.0;
+    if !front_face {
+        normal = -normal;
+    }
+
+    Hit {
+        normal,
+        collision,
+        material: sphere.material,
+        front_face,
+        uv,
+        tangent: None,
+        // Geometric mean of the scales along u, which wraps around the equator, and along v
+        uv_scale: 1.0 / (PI * 2f32.sqrt() * sphere.radius),
+        primitive,
+    }
+}
+
+/// Longitude and latitude of a point on the unit sphere.
+fn sphere_uv(normal: Vec3) -> [f32; 2] {
+    [
+        (-normal.z()).atan2(normal.x()) / (2.0 * PI) + 0.5,
+        (-normal.y()).clamp(-1.0, 1.0).acos() / PI,
+    ]
+}
+
+/// Intersection with a quad, disk or plane, returns the distance and the hit point's
+/// coordinates along `edge_u` and `edge_v`.
+fn hit_planar(planar: &Planar, ray: &Ray, max_t: f32) -> Option<(f32, [f32; 2])> {
+    let normal = planar.edge_u.cross(&planar.edge_v);
+    let denominator = normal.dot(&ray.dir);
+    if denominator.abs() < 1e-9 {
+        return None;
+    }
+    let t = normal.dot(&(planar.origin - ray.origin)) / denominator;
+    if !(T_MIN..max_t).contains(&t) {
+        return None;
+    }
+
+    let offset = ray.at(t) - planar.origin;
+    let w = normal.div(normal.length_squared());
+    let a = w.dot(&offset.cross(&planar.edge_v));
+    let b = w.dot(&planar.edge_u.cross(&offset));
+    let inside = match planar.kind {
+        PlanarKind::Quad => (0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b),
+        PlanarKind::Disk => a * a + b * b <= 1.0,
+        PlanarKind::Plane => true,
+    };
+    inside.then_some((t, [a, b]))
+}
+
+fn planar_hit(
+    planar: &Planar,
+    ray: &Ray,
+    t: f32,
+    [a, b]: [f32; 2],
+    primitive: PrimitiveRef,
+) -> Hit {
+    let mut normal = planar.normal();
+    let front_face = ray.dir.dot(&normal) < 0.0;
+    if !front_face {
+        normal = -normal;
+    }
+    let mut uv_scale = 1.0 / planar.edge_u.cross(&planar.edge_v).length().sqrt();
+    if planar.kind == PlanarKind::Disk {
+        uv_scale *= 0.5;
+    }
+    let tangent = planar.edge_u.normalize();
+    let handedness = if normal.cross(&tangent).dot(&planar.edge_v) < 0.0 {
+        -1.0
+    } else {
+        1.0
+    };
+
+    Hit {
+        normal,
+        collision: ray.at(t),
+        material: planar.material,
+        front_face,
+        uv: planar_uv(planar, [a, b]),
+        tangent: Some((tangent, handedness)),
+        uv_scale,
+        primitive,
+    }
+}
+
+/// Disks map their bounding square of edge coordinates [-1, 1] to the unit uv square.
+fn planar_uv(planar: &Planar, [a, b]: [f32; 2]) -> [f32; 2] {
+    match planar.kind {
+        PlanarKind::Disk => [0.5 * a + 0.5, 0.5 * b + 0.5],
+        PlanarKind::Quad | PlanarKind::Plane => [a, b],
+    }
+}
+
+/// Bilinearly filtered texel of an image with repeating uvs.
+fn sample_bilinear(image: &RgbaImage, [u, v]: [f32; 2]) -> [f32; 4] {
+    let (width, height) = image.dimensions();
+    let x = u * width as f32 - 0.5;
+    let y = v * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let texel = |dx: i64, dy: i64| {
+        let tx = (x0 as i64 + dx).rem_euclid(width as i64) as u32;
+        let ty = (y0 as i64 + dy).rem_euclid(height as i64) as u32;
+        image
+            .get_pixel(tx, ty)
+            .0
+            .map(|channel| channel as f32 / 255.0)
+    };
+    let [t00, t10, t01, t11] = [texel(0, 0), texel(1, 0), texel(0, 1), texel(1, 1)];
+    [0, 1, 2, 3].map(|i| {
+        let top = t00[i] + (t10[i] - t00[i]) * fx;
+        let bottom = t01[i] + (t11[i] - t01[i]) * fx;
+        top + (bottom - top) * fy
+    })
+}
+
+/// Color of a procedural texture, see `procedural_texture` in compute.wgsl.
+fn procedural_texture(source: &TextureSource, [u, v]: [f32; 2], position: Vec3) -> Vec3 {
+    let point = match source.mapping {
+        TextureMapping::Uv => Vec3::new(u, v, 0.0),
+        TextureMapping::Solid => position,
+    }
+    .mul(source.scale);
+
+    let t = match source.kind {
+        TextureKind::Checker => {
+            let cell = [0, 1, 2].map(|axis| point[axis].floor() as i32);
+            ((cell[0] + cell[1] + cell[2]) & 1) as f32
+        }
+        TextureKind::Noise => 0.5 + 0.5 * fractal_noise(point, source.octaves, false),
+        TextureKind::Turbulence => fractal_noise(point, source.octaves, true),
+        TextureKind::Marble | TextureKind::Image => {
+            let turbulence = fractal_noise(point, source.octaves, true);
+            0.5 + 0.5 * (point.x() + source.distortion * turbulence).sin()
+        }
+    };
+    let t = t.clamp(0.0, 1.0);
+    source.color_a.mul(1.0 - t) + source.color_b.mul(t)
+}
+
+/// PCG hash (Jarzynski and Olano, "Hash Functions for GPU Rendering").
+fn pcg_hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// Dot product of `offset` with one of the 12 gradients of Perlin's improved noise, picked by
+/// hashing the lattice point `cell`.
+fn lattice_gradient(cell: [i32; 3], offset: [f32; 3]) -> f32 {
+    let hash = pcg_hash(cell[0] as u32 ^ pcg_hash(cell[1] as u32 ^ pcg_hash(cell[2] as u32)));
+    let h = hash % 12;
+    let (a, b) = match h {
+        0..4 => (offset[0], offset[1]),
+        4..8 => (offset[0], offset[2]),
+        _ => (offset[1], offset[2]),
+    };
+    let a = if h & 1 != 0 { -a } else { a };
+    let b = if h & 2 != 0 { -b } else { b };
+    a + b
+}
+
+/// Perlin's gradient noise, roughly in [-1, 1] and 0 on the integer lattice.
+fn gradient_noise(point: Vec3) -> f32 {
+    let cell = [0, 1, 2].map(|axis| point[axis].floor());
+    let offset = [0, 1, 2].map(|axis| point[axis] - cell[axis]);
+    let fade = offset.map(|t| t * t * t * (t * (t * 6.0 - 15.0) + 10.0));
+
+    let mut noise = 0.0;
+    for corner in 0..8 {
+        let delta = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+        let weight: f32 = (0..3)
+            .map(|axis| {
+                if delta[axis] == 1 {
+                    fade[axis]
+                } else {
+                    1.0 - fade[axis]
+                }
+            })
+            .product();
+        let lattice = [0, 1, 2].map(|axis| cell[axis] as i32 + delta[axis]);
+        let local = [0, 1, 2].map(|axis| offset[axis] - delta[axis] as f32);
+        noise += weight * lattice_gradient(lattice, local);
+    }
+    noise
+}
+
+/// fBm of `octaves` octaves of gradient noise, or turbulence, see `fractal_noise` in
+/// compute.wgsl.
+fn fractal_noise(point: Vec3, octaves: u32, turbulence: bool) -> f32 {
+    let (mut sum, mut total) = (0.0, 0.0);
+    let (mut amplitude, mut frequency) = (1.0, 1.0);
+    for _ in 0..octaves {
+        let noise = gradient_noise(point.mul(frequency));
+        sum += amplitude * if turbulence { noise.abs() } else { noise };
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+/// Mip level at which a texel of the texture array covers the ray cone's footprint `width` on
+/// the surface, which grows by 1 / cos for rays hitting it at a grazing angle.
+fn texture_lod(hit: &Hit, width: f32, cos_theta: f32) -> f32 {
+    let texels = TEXTURE_LAYER_SIZE as f32 * hit.uv_scale * width;
+    (texels / cos_theta.abs().max(0.01)).max(1e-8).log2()
+}
+
+/// Light arriving at `position` from `point` on a one sided area light, with the density of
+/// uniformly sampling the light's area converted to solid angle.
+fn area_light_sample(
+    position: Vec3,
+    point: Vec3,
+    light_normal: Vec3,
+    area: f32,
+    radiance: Vec3,
+) -> Option<LightSample> {
+    let to_light = point - position;
+    let distance2 = to_light.length_squared();
+    let distance = distance2.sqrt();
+    let dir = to_light.div(distance);
+    let cos_light = (-dir).dot(&light_normal);
+    if cos_light <= 0.0 {
+        // Area lights only emit from their front side
+        return None;
+    }
+    Some(LightSample {
+        dir,
+        distance,
+        radiance,
+        pdf: distance2 / (cos_light * area),
+    })
+}
+
+/// Solid angle density of uniformly sampling an area light seen along `to_light`.
+fn area_light_pdf(to_light: Vec3, light_normal: Vec3, area: f32) -> f32 {
+    let cos_light = to_light.normalize().dot(&light_normal).abs();
+    to_light.length_squared() / (cos_light * area)
+}
+
+/// 1 - cos of the half angle of the cone a sphere subtends, 0 when the point is inside it.
+fn sphere_cone(sphere: &Sphere, position: Vec3) -> f32 {
+    let sin2 = sphere.radius * sphere.radius / (sphere.center - position).length_squared();
+    if sin2 >= 1.0 {
+        return 0.0;
+    }
+    sin2 / (1.0 + (1.0 - sin2).sqrt())
+}
+
+/// Henyey-Greenstein phase function, also the density of `sample_henyey_greenstein`.
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+fn sample_henyey_greenstein(dir: Vec3, g: f32, [r1, r2]: [f32; 2]) -> Vec3 {
+    let mut cos_theta = 1.0 - 2.0 * r1;
+    if g.abs() > 1e-3 {
+        let square = (1.0 - g * g) / (1.0 - g + 2.0 * g * r1);
+        cos_theta = (1.0 + g * g - square * square) / (2.0 * g);
+    }
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * r2;
+    to_world(
+        &basis(dir),
+        Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta),
+    )
+}
+
+/// Distances along the ray where it enters and leaves the fog within `max_t`.
+fn fog_interval(fog: &Fog, ray: &Ray, max_t: f32) -> (f32, f32) {
+    if fog.density <= 0.0 {
+        return (0.0, 0.0);
+    }
+    let height = fog.height.unwrap_or(f32::MAX);
+    if ray.dir.y().abs() < 1e-8 {
+        return if ray.origin.y() < height {
+            (0.0, max_t)
+        } else {
+            (0.0, 0.0)
+        };
+    }
+    let crossing = (height - ray.origin.y()) / ray.dir.y();
+    if ray.dir.y() > 0.0 {
+        return (0.0, crossing.clamp(0.0, max_t));
+    }
+    (crossing.clamp(0.0, max_t), max_t)
+}
+
+/// Orthonormal basis with `w` as third axis.
+fn basis(w: Vec3) -> [Vec3; 3] {
+    let a = if w.x().abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let v = w.cross(&a).normalize();
+    let u = v.cross(&w);
+    [u, v, w]
+}
+
+/// Index of the first CDF value that is at least `value`.
+fn search_cdf(cdf: &[f32], value: f32) -> usize {
+    cdf.partition_point(|&x| x < value).min(cdf.len() - 1)
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf2 = pdf * pdf;
+    pdf2 / (pdf2 + other_pdf * other_pdf)
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn luminance(color: Vec3) -> f32 {
+    (0.2126 * color.x() + 0.7152 * color.y() + 0.0722 * color.z()).max(0.0)
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_local([u, v, w]: &[Vec3; 3], dir: Vec3) -> Vec3 {
+    Vec3::new(dir.dot(u), dir.dot(v), dir.dot(w))
+}
+
+fn to_world([u, v, w]: &[Vec3; 3], dir: Vec3) -> Vec3 {
+    u.mul(dir.x()) + v.mul(dir.y()) + w.mul(dir.z())
+}
+
+/// Below this roughness the GGX lobes are treated as perfectly smooth.
+const MIN_ROUGHNESS: f32 = 0.03;
+
+fn is_smooth(surface: &Surface) -> bool {
+    surface.roughness < MIN_ROUGHNESS
+}
+
+/// Index of refraction on the far side of the surface relative to the near side.
+fn relative_eta(surface: &Surface) -> f32 {
+    if surface.front_face {
+        surface.refraction_index
+    } else {
+        1.0 / surface.refraction_index
+    }
+}
+
+fn ggx_d(h: Vec3, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let d = h.z() * h.z() * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * d * d)
+}
+
+fn ggx_lambda(w: Vec3, alpha: f32) -> f32 {
+    let tan2 = (w.x() * w.x() + w.y() * w.y()) / (w.z() * w.z());
+    0.5 * ((1.0 + alpha * alpha * tan2).sqrt() - 1.0)
+}
+
+fn ggx_g1(w: Vec3, alpha: f32) -> f32 {
+    1.0 / (1.0 + ggx_lambda(w, alpha))
+}
+
+/// Height correlated masking-shadowing.
+fn ggx_g2(wo: Vec3, wi: Vec3, alpha: f32) -> f32 {
+    1.0 / (1.0 + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha))
+}
+
+/// Samples a microfacet normal from the normals visible from `wo` (Heitz 2018).
+fn sample_ggx_visible(wo: Vec3, alpha: f32, [r1, r2]: [f32; 2]) -> Vec3 {
+    let v = Vec3::new(alpha * wo.x(), alpha * wo.y(), wo.z()).normalize();
+    let length2 = v.x() * v.x() + v.y() * v.y();
+    let t1 = if length2 > 0.0 {
+        Vec3::new(-v.y(), v.x(), 0.0).div(length2.sqrt())
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let t2 = v.cross(&t1);
+    let r = r1.sqrt();
+    let phi = 2.0 * PI * r2;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + v.z());
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let n = t1.mul(p1) + t2.mul(p2) + v.mul((1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt());
+    Vec3::new(alpha * n.x(), alpha * n.y(), n.z().max(0.0)).normalize()
+}
+
+/// Density of `sample_ggx_visible` picking `h`.
+fn ggx_visible_pdf(wo: Vec3, h: Vec3, alpha: f32) -> f32 {
+    ggx_g1(wo, alpha) * wo.dot(&h).max(0.0) * ggx_d(h, alpha) / wo.z()
+}
+
+/// Unpolarized Fresnel reflectance of a dielectric interface, `eta` is the index of refraction
+/// of the far side relative to the near side.
+fn fresnel_dielectric(cos_theta: f32, eta: f32) -> f32 {
+    let cos_i = cos_theta.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+/// Fresnel reflectance of a conductor with the complex index of refraction `eta + i k`, for
+/// one channel.
+fn fresnel_conductor(cos_theta: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = (cos_theta * cos_theta).clamp(0.0, 1.0);
+    let sin2 = 1.0 - cos2;
+    let (eta2, k2) = (eta * eta, k * k);
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos2.sqrt() * a;
+    let perpendicular = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+    0.5 * (parallel + perpendicular)
+}
+
+fn metal_fresnel(surface: &Surface, cos_theta: f32) -> Vec3 {
+    let f0 = surface.base_color;
+    match surface.conductor {
+        Some((eta, k)) => {
+            let channel = |i: usize| fresnel_conductor(cos_theta, eta[i], k[i]);
+            f0 * Vec3::new(channel(0), channel(1), channel(2))
+        }
+        None => {
+            let white = Vec3::new(1.0, 1.0, 1.0);
+            f0 + (white - f0).mul((1.0 - cos_theta.clamp(0.0, 1.0)).powi(5))
+        }
+    }
+}
+
+/// Probabilities of sampling the metal, glass, coat and diffuse lobe, see
+/// `lobe_probabilities` in compute.wgsl.
+fn lobe_probabilities(surface: &Surface, wo: Vec3) -> [f32; 4] {
+    let dielectric = 1.0 - surface.metallic;
+    let glass = dielectric * surface.transmission;
+    let opaque = dielectric - glass;
+    let coat = fresnel_dielectric(wo.z(), surface.refraction_index);
+    let base = (1.0 - coat) * luminance(surface.base_color);
+    let coat_share = if coat > 0.0 {
+        coat / (coat + base)
+    } else {
+        0.0
+    };
+    [
+        surface.metallic,
+        glass,
+        opaque * coat_share,
+        opaque * (1.0 - coat_share),
+    ]
+}
+
+/// BSDF times the cosine at `wi` and the density with which `sample_bsdf` picks `wi`, without
+/// the smooth lobes. Directions are in the shading frame.
+fn eval_bsdf(surface: &Surface, wo: Vec3, wi: Vec3) -> (Vec3, f32) {
+    let [
+        metal_probability,
+        glass_probability,
+        coat_probability,
+        diffuse_probability,
+    ] = lobe_probabilities(surface, wo);
+    let dielectric = 1.0 - surface.metallic;
+    let glass = dielectric * surface.transmission;
+    let opaque = dielectric - glass;
+    let mut value = Vec3::zero();
+    let mut pdf = 0.0;
+
+    if wi.z() > 0.0 {
+        let coat = fresnel_dielectric(wo.z(), surface.refraction_index);
+        value += surface.base_color.mul(opaque * (1.0 - coat) / PI * wi.z());
+        pdf += diffuse_probability * wi.z() / PI;
+    }
+    if is_smooth(surface) || metal_probability + glass_probability + coat_probability == 0.0 {
+        return (value, pdf);
+    }
+
+    let alpha = surface.roughness * surface.roughness;
+    if wi.z() > 0.0 {
+        let h = (wo + wi).normalize();
+        let cos_h = wo.dot(&h);
+        let d = ggx_d(h, alpha);
+        let specular = d * ggx_g2(wo, wi, alpha) / (4.0 * wo.z());
+        let reflection_pdf = ggx_g1(wo, alpha) * d / (4.0 * wo.z());
+        let glass_fresnel = fresnel_dielectric(cos_h, relative_eta(surface));
+        let coat_fresnel = fresnel_dielectric(cos_h, surface.refraction_index);
+        value += metal_fresnel(surface, cos_h).mul(surface.metallic * specular);
+        value += Vec3::new(1.0, 1.0, 1.0)
+            .mul((glass * glass_fresnel + opaque * coat_fresnel) * specular);
+        pdf += (metal_probability + glass_probability * glass_fresnel + coat_probability)
+            * reflection_pdf;
+    } else if glass > 0.0 {
+        // Refraction through the microfacet halfway between `wo` and `wi` weighted by the
+        // indices of refraction, without scaling the radiance
+        let eta = relative_eta(surface);
+        let mut h = (wo + wi.mul(eta)).normalize();
+        if h.z() < 0.0 {
+            h = -h;
+        }
+        let cos_o = wo.dot(&h);
+        let cos_i = wi.dot(&h);
+        if cos_o <= 0.0 || cos_i >= 0.0 {
+            return (value, pdf);
+        }
+        let transmitted = 1.0 - fresnel_dielectric(cos_o, eta);
+        let denominator = (cos_i + cos_o / eta) * (cos_i + cos_o / eta);
+        let d = ggx_d(h, alpha);
+        value += surface.base_color.mul(
+            glass * transmitted * d * ggx_g2(wo, wi, alpha) * (cos_i * cos_o).abs()
+                / (wo.z() * denominator),
+        );
+        pdf += glass_probability * transmitted * ggx_visible_pdf(wo, h, alpha) * cos_i.abs()
+            / denominator;
+    }
+    (value, pdf)
+}
+
+struct BsdfSample {
+    /// In the shading frame.
+    dir: Vec3,
+    /// BSDF times cosine over density.
+    weight: Vec3,
+    /// 0 for smooth lobes.
+    pdf: f32,
+    kind: Bounce,
+}
+
+/// Picks a lobe and a direction from it, see `sample_bsdf` in compute.wgsl. `None` when the
+/// path is absorbed.
+fn sample_bsdf(surface: &Surface, wo: Vec3, rng: &mut SmallRng) -> Option<BsdfSample> {
+    let [metal_probability, glass_probability, coat_probability, _] =
+        lobe_probabilities(surface, wo);
+    let lobe = random_float(rng);
+    let random = [random_float(rng), random_float(rng)];
+    let smooth = is_smooth(surface);
+    let alpha = surface.roughness * surface.roughness;
+    let h = if smooth {
+        Vec3::new(0.0, 0.0, 1.0)
+    } else {
+        sample_ggx_visible(wo, alpha, random)
+    };
+
+    let metal_end = metal_probability;
+    let glass_end = metal_end + glass_probability;
+    let coat_end = glass_end + coat_probability;
+    let (wi, kind, smooth_weight) = if lobe < metal_end {
+        (
+            (-wo).reflect(&h),
+            Bounce::Specular,
+            metal_fresnel(surface, wo.z()),
+        )
+    } else if lobe < glass_end {
+        // The position within the glass lobe's share picks reflection or refraction
+        let eta = relative_eta(surface);
+        let fresnel = fresnel_dielectric(wo.dot(&h), eta);
+        if (lobe - metal_end) / glass_probability < fresnel {
+            (
+                (-wo).reflect(&h),
+                Bounce::Specular,
+                Vec3::new(1.0, 1.0, 1.0),
+            )
+        } else {
+            (
+                (-wo).refract(&h, 1.0 / eta),
+                Bounce::Transmission,
+                surface.base_color,
+            )
+        }
+    } else if lobe < coat_end {
+        let opaque = (1.0 - surface.metallic) * (1.0 - surface.transmission);
+        let coat = fresnel_dielectric(wo.z(), surface.refraction_index);
+        let weight = opaque * coat / coat_probability;
+        (
+            (-wo).reflect(&h),
+            Bounce::Specular,
+            Vec3::new(weight, weight, weight),
+        )
+    } else {
+        // Cosine weighted hemisphere
+        let r = random[0].sqrt();
+        let phi = 2.0 * PI * random[1];
+        let wi = Vec3::new(
+            r * phi.cos(),
+            r * phi.sin(),
+            (1.0 - random[0]).max(0.0).sqrt(),
+        );
+        (wi, Bounce::Diffuse, Vec3::zero())
+    };
+
+    // Reflections below the surface and refractions above it are absorbed
+    let transmission = matches!(kind, Bounce::Transmission);
+    if transmission != (wi.z() < 0.0) || wi.z() == 0.0 {
+        return None;
+    }
+    if smooth && !matches!(kind, Bounce::Diffuse) {
+        return Some(BsdfSample {
+            dir: wi,
+            weight: smooth_weight,
+            pdf: 0.0,
+            kind,
+        });
+    }
+    let (value, pdf) = eval_bsdf(surface, wo, wi);
+    if pdf <= 0.0 {
+        return None;
+    }
+    Some(BsdfSample {
+        dir: wi,
+        weight: value.div(pdf),
+        pdf,
+        kind,
+    })
+}
@@ -0,0 +1,126 @@
+use serde::Deserialize;
+
+/// Operator compressing the unbounded scene radiance into the displayable range.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ToneMapping {
+    /// Clamps to [0, 1], highlights clip.
+    None = 0,
+    Reinhard = 1,
+    /// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms.
+    #[default]
+    Aces = 2,
+    /// Troy Sobotka's AgX with the default look, desaturates bright colors towards white.
+    Agx = 3,
+}
+
+impl ToneMapping {
+    /// The following operator, wrapping around, to cycle through them at runtime.
+    pub fn next(self) -> Self {
+        match self {
+            Self::None => Self::Reinhard,
+            Self::Reinhard => Self::Aces,
+            Self::Aces => Self::Agx,
+            Self::Agx => Self::None,
+        }
+    }
+
+    /// Maps linear scene radiance to linear display values in [0, 1], mirrors `tone_map` in
+    /// render.wgsl.
+    pub fn apply(self, color: [f32; 3]) -> [f32; 3] {
+        let color = color.map(|channel| channel.max(0.0));
+        let color = match self {
+            Self::None => color,
+            Self::Reinhard => color.map(|channel| channel / (1.0 + channel)),
+            Self::Aces => aces(color),
+            Self::Agx => agx(color),
+        };
+        color.map(|channel| channel.clamp(0.0, 1.0))
+    }
+}
+
+/// How the linear radiance of the render target is turned into displayed pixels.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct DisplaySettings {
+    /// Exposure compensation in stops, every stop doubles the brightness.
+    pub exposure: f32,
+    pub tone_mapping: ToneMapping,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            tone_mapping: ToneMapping::default(),
+        }
+    }
+}
+
+impl DisplaySettings {
+    /// Exposed and tone mapped linear display color.
+    pub fn apply(&self, [r, g, b]: [f32; 3]) -> [f32; 3] {
+        let scale = self.exposure.exp2();
+        self.tone_mapping.apply([r * scale, g * scale, b * scale])
+    }
+
+    /// ## WGSL schema:
+    /// struct DisplaySettings {
+    ///     exposure: f32, // linear scale, 2^stops
+    ///     tone_mapping: u32,
+    ///     encode_srgb: u32, // 1 when the surface format doesn't encode on write
+    /// }
+    pub(crate) fn to_bytes(self, encode_srgb: bool) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(&self.exposure.exp2().to_le_bytes());
+        bytes[4..8].copy_from_slice(&(self.tone_mapping as u32).to_le_bytes());
+        bytes[8..12].copy_from_slice(&(encode_srgb as u32).to_le_bytes());
+        bytes
+    }
+}
+
+/// `m * v` for a matrix given as its columns, the layout of WGSL's `mat3x3`.
+fn mul(m: [[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|row| m[0][row] * v[0] + m[1][row] * v[1] + m[2][row] * v[2])
+}
+
+fn aces(color: [f32; 3]) -> [f32; 3] {
+    const INPUT: [[f32; 3]; 3] = [
+        [0.59719, 0.07600, 0.02840],
+        [0.35458, 0.90834, 0.13383],
+        [0.04823, 0.01566, 0.83777],
+    ];
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.60475, -0.10208, -0.00327],
+        [-0.53108, 1.10813, -0.07276],
+        [-0.07367, -0.00605, 1.07602],
+    ];
+    let color = mul(INPUT, color)
+        .map(|v| (v * (v + 0.0245786) - 9.0537e-05) / (v * (0.983729 * v + 0.432951) + 0.238081));
+    mul(OUTPUT, color)
+}
+
+fn agx(color: [f32; 3]) -> [f32; 3] {
+    const INSET: [[f32; 3]; 3] = [
+        [0.8424791, 0.04232824, 0.04237565],
+        [0.0784336, 0.8784686, 0.0784336],
+        [0.07922375, 0.07916613, 0.879143],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196879, -0.05289685, -0.05297164],
+        [-0.09802088, 1.151903, -0.09804345],
+        [-0.09902974, -0.09896118, 1.151074],
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let color = mul(INSET, color).map(|v| {
+        let x = (v.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        // Polynomial approximation of the default contrast curve
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+    mul(OUTSET, color).map(|v| v.max(0.0).powf(2.2))
+}
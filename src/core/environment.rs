@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::*;
+use image::{ImageReader, Rgba32FImage};
+use serde::Deserialize;
+
+use crate::core::material::default_intensity;
+
+/// Equirectangular HDR image (Radiance `.hdr` or OpenEXR) lighting the scene from infinitely
+/// far away, replacing the sky gradient.
+#[derive(Deserialize)]
+pub struct Environment {
+    pub path: PathBuf,
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+    /// Rotation around the vertical axis in degrees.
+    #[serde(default)]
+    pub rotation: f32,
+    #[serde(skip)]
+    pub image: Option<Rgba32FImage>,
+}
+
+impl Environment {
+    /// Loads the image, `path` is resolved relative to `base_dir`.
+    pub(crate) fn load(&mut self, base_dir: &Path) -> Result<()> {
+        let path = base_dir.join(&self.path);
+        let image = ImageReader::open(&path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(Error::from)
+            .and_then(|reader| Ok(reader.decode()?))
+            .with_context(|| format!("failed to load environment map {}", path.display()))?;
+        self.image = Some(image.to_rgba32f());
+        Ok(())
+    }
+
+    /// Tabulated distribution for importance sampling the image by luminance.
+    ///
+    /// Every texel is weighted by its luminance times `sin(theta)`, the area it covers on the
+    /// sphere. The first `width * height` values are the normalized CDFs of the texels within
+    /// each row, the following `height` values the CDF over the rows. Also returns the sum of
+    /// all weights, which the shader needs to evaluate the density of a direction.
+    pub(crate) fn distribution(environment: Option<&Self>) -> (Vec<f32>, f32) {
+        let Some(image) = environment.and_then(|environment| environment.image.as_ref()) else {
+            return (vec![1.0, 1.0], 1.0);
+        };
+        let (width, height) = image.dimensions();
+
+        let mut cdf = Vec::with_capacity((width * height + height) as usize);
+        let mut row_weights = Vec::with_capacity(height as usize);
+        let mut weight_sum = 0.0;
+        for y in 0..height {
+            let sin_theta = (std::f32::consts::PI * (y as f32 + 0.5) / height as f32).sin();
+            let row_start = cdf.len();
+            let mut row_sum = 0.0;
+            for x in 0..width {
+                row_sum += luminance(image.get_pixel(x, y).0) * sin_theta;
+                cdf.push(row_sum);
+            }
+            normalize_cdf(&mut cdf[row_start..]);
+            row_weights.push(row_sum);
+            weight_sum += row_sum;
+        }
+
+        let mut row_cdf: Vec<f32> = row_weights
+            .iter()
+            .scan(0.0, |sum, weight| {
+                *sum += weight;
+                Some(*sum)
+            })
+            .collect();
+        normalize_cdf(&mut row_cdf);
+        cdf.extend(row_cdf);
+
+        (cdf, weight_sum.max(f32::MIN_POSITIVE))
+    }
+
+    /// ## WGSL schema:
+    /// struct EnvironmentData {
+    ///     intensity: f32,
+    ///     rotation: f32, // radians
+    ///     weight_sum: f32,
+    ///     enabled: u32,
+    /// }
+    pub(crate) fn to_bytes(environment: Option<&Self>, weight_sum: f32) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        if let Some(environment) = environment {
+            bytes[0..4].copy_from_slice(&environment.intensity.to_le_bytes());
+            bytes[4..8].copy_from_slice(&environment.rotation.to_radians().to_le_bytes());
+            bytes[8..12].copy_from_slice(&weight_sum.to_le_bytes());
+            bytes[12..16].copy_from_slice(&1u32.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+fn luminance([r, g, b, _]: [f32; 4]) -> f32 {
+    (0.2126 * r + 0.7152 * g + 0.0722 * b).max(0.0)
+}
+
+/// Scales a running sum to end at 1, an all zero sum becomes uniform.
+fn normalize_cdf(cdf: &mut [f32]) {
+    let total = cdf.last().copied().unwrap_or(0.0);
+    let count = cdf.len() as f32;
+    for (i, value) in cdf.iter_mut().enumerate() {
+        *value = if total > 0.0 {
+            *value / total
+        } else {
+            (i + 1) as f32 / count
+        };
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use anyhow::*;
+use image::{Rgba32FImage, RgbaImage};
+
+use crate::{core::display::DisplaySettings, util::linear_to_srgb};
+
+/// A rendered image in linear RGBA, independent of the renderer that produced it.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 4]>,
+}
+
+impl Frame {
+    pub fn new(width: u32, height: u32, pixels: Vec<[f32; 4]>) -> Self {
+        assert!(pixels.len() == (width * height) as usize);
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Converts to 8 bit sRGB with exposure and tone mapping applied, the same way the window
+    /// displays the image.
+    pub fn to_rgba8(&self, display: &DisplaySettings) -> RgbaImage {
+        let bytes = self
+            .pixels
+            .iter()
+            .flat_map(|&[r, g, b, a]| {
+                let [r, g, b] = display.apply([r, g, b]);
+                [
+                    linear_to_srgb(r),
+                    linear_to_srgb(g),
+                    linear_to_srgb(b),
+                    a.clamp(0.0, 1.0),
+                ]
+            })
+            .map(|channel| (channel * 255.0).round() as u8)
+            .collect();
+        RgbaImage::from_raw(self.width, self.height, bytes).unwrap()
+    }
+
+    pub fn to_rgba32f(&self) -> Rgba32FImage {
+        let floats = self.pixels.iter().flatten().copied().collect();
+        Rgba32FImage::from_raw(self.width, self.height, floats).unwrap()
+    }
+
+    /// Writes the frame to disk. `.exr` and `.hdr` files keep linear floating point values,
+    /// everything else is tone mapped with `display` and written as 8 bit sRGB in the format
+    /// implied by the extension.
+    pub fn save(&self, path: impl AsRef<Path>, display: &DisplaySettings) -> Result<()> {
+        self.save_with_metadata(path, display, &[])
+    }
+
+    /// Like `save`, but also stores `metadata` as text, in `tEXt` chunks of PNG files and as
+    /// header attributes of OpenEXR files. Other formats are written without it.
+    pub fn save_with_metadata(
+        &self,
+        path: impl AsRef<Path>,
+        display: &DisplaySettings,
+        metadata: &[(&str, String)],
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("exr") => self.save_exr(path, metadata),
+            Some("png") => self.save_png(path, display, metadata),
+            Some("hdr") => Ok(image::DynamicImage::ImageRgba32F(self.to_rgba32f())
+                .to_rgb32f()
+                .save(path)?),
+            _ => Ok(self.to_rgba8(display).save(path)?),
+        }
+        .with_context(|| format!("failed to write image {}", path.display()))
+    }
+
+    fn save_png(
+        &self,
+        path: &Path,
+        display: &DisplaySettings,
+        metadata: &[(&str, String)],
+    ) -> Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+        for (key, value) in metadata {
+            encoder.add_text_chunk(key.to_string(), value.clone())?;
+        }
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(self.to_rgba8(display).as_raw())?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    fn save_exr(&self, path: &Path, metadata: &[(&str, String)]) -> Result<()> {
+        use exr::prelude::{AttributeValue, Image, SpecificChannels, Text, Vec2, WritableImage};
+
+        let width = self.width as usize;
+        let channels = SpecificChannels::rgba(|Vec2(x, y)| {
+            let [r, g, b, a] = self.pixels[y * width + x];
+            (r, g, b, a)
+        });
+        let mut image = Image::from_channels((width, self.height as usize), channels);
+        for (key, value) in metadata {
+            image.attributes.other.insert(
+                Text::from(*key),
+                AttributeValue::Text(Text::from(value.as_str())),
+            );
+        }
+
+        image.write().to_file(path)?;
+        Ok(())
+    }
+}
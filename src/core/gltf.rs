@@ -0,0 +1,257 @@
+use std::{collections::HashMap, path::Path};
+
+use ::gltf::image::Format;
+use anyhow::*;
+use image::{DynamicImage, ImageBuffer, RgbaImage};
+
+use crate::{
+    core::{
+        material::{EmissiveMaterial, Material, PbrMaterial},
+        mesh::{MeshInstance, Triangle, Vertex},
+        scene::Scene,
+    },
+    math::{mat::Mat4, vec::Vec3},
+};
+
+/// Loads the default scene of a glTF 2.0 file (`.gltf` or `.glb`) into the scene's vertex and
+/// triangle lists.
+///
+/// The node hierarchy is flattened by baking every node's world transform into its vertices.
+/// Metallic-roughness materials become `PbrMaterial`s, including the index of refraction and
+/// transmission of the `KHR_materials_ior` and `KHR_materials_transmission` extensions.
+/// Emissive materials become `EmissiveMaterial`s, and
+/// the textures are appended to the scene's textures. When the instance asks for it, the first
+/// perspective camera replaces the scene camera.
+pub(crate) fn load_gltf(scene: &mut Scene, instance: &MeshInstance, base_dir: &Path) -> Result<()> {
+    let path = base_dir.join(&instance.path);
+    let (document, buffers, images) = ::gltf::import(&path)
+        .with_context(|| format!("failed to load glTF file {}", path.display()))?;
+
+    let mut loader = Loader {
+        scene,
+        instance,
+        buffers: &buffers,
+        images: &images,
+        materials: HashMap::new(),
+        textures: HashMap::new(),
+        camera_found: false,
+    };
+
+    let gltf_scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .with_context(|| format!("glTF file {} contains no scene", path.display()))?;
+    let matrix = instance.transform.matrix();
+    for node in gltf_scene.nodes() {
+        loader.load_node(&node, matrix)?;
+    }
+
+    Ok(())
+}
+
+struct Loader<'a> {
+    scene: &'a mut Scene,
+    instance: &'a MeshInstance,
+    buffers: &'a [::gltf::buffer::Data],
+    images: &'a [::gltf::image::Data],
+    /// glTF material index (`None` for the default material) to scene material.
+    materials: HashMap<Option<usize>, u32>,
+    /// glTF image index to scene texture.
+    textures: HashMap<usize, u32>,
+    camera_found: bool,
+}
+
+impl Loader<'_> {
+    fn load_node(&mut self, node: &::gltf::Node, parent: Mat4) -> Result<()> {
+        let matrix = parent * Mat4::from_columns(node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                self.load_primitive(&primitive, matrix)?;
+            }
+        }
+
+        if self.instance.camera
+            && !self.camera_found
+            && let Some(camera) = node.camera()
+            && let ::gltf::camera::Projection::Perspective(perspective) = camera.projection()
+        {
+            // glTF cameras look down their local -Z axis
+            let position = matrix.transform_point(Vec3::zero());
+            let forward = matrix
+                .transform_vector(Vec3::new(0.0, 0.0, -1.0))
+                .normalize();
+            self.scene.camera.position = position;
+            self.scene.camera.look_at = position + forward;
+            self.scene.camera.vfov = perspective.yfov().to_degrees();
+            self.camera_found = true;
+        }
+
+        for child in node.children() {
+            self.load_node(&child, matrix)?;
+        }
+        Ok(())
+    }
+
+    fn load_primitive(&mut self, primitive: &::gltf::Primitive, matrix: Mat4) -> Result<()> {
+        if primitive.mode() != ::gltf::mesh::Mode::Triangles {
+            return Ok(());
+        }
+
+        let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+        let Some(positions) = reader.read_positions() else {
+            return Ok(());
+        };
+        let positions: Vec<[f32; 3]> = positions.collect();
+        let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(Iterator::collect);
+        let uvs: Option<Vec<[f32; 2]>> = reader
+            .read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().collect());
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let material = match self.instance.material {
+            Some(material) => material,
+            None => self.material(primitive.material()),
+        };
+
+        let first_vertex = self.scene.vertices.len() as u32;
+        for (i, &position) in positions.iter().enumerate() {
+            let normal = normals.as_ref().map_or(Vec3::zero(), |normals| {
+                matrix.transform_normal(Vec3::from(normals[i]))
+            });
+            let uv = uvs.as_ref().map_or([0.0, 0.0], |uvs| uvs[i]);
+
+            self.scene.vertices.push(Vertex {
+                position: matrix.transform_point(Vec3::from(position)),
+                normal,
+                uv,
+            });
+        }
+
+        for face in indices.chunks_exact(3) {
+            ensure!(
+                face.iter().all(|&index| (index as usize) < positions.len()),
+                "glTF primitive references a vertex out of range"
+            );
+            self.scene.triangles.push(Triangle {
+                vertices: [
+                    first_vertex + face[0],
+                    first_vertex + face[1],
+                    first_vertex + face[2],
+                ],
+                material,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn material(&mut self, material: ::gltf::Material) -> u32 {
+        if let Some(&material) = self.materials.get(&material.index()) {
+            return material;
+        }
+
+        // Emission replaces the surface's reflection
+        let emission = Vec3::from(material.emissive_factor());
+        if emission.length_squared() > 0.0 {
+            let emission_texture = material
+                .emissive_texture()
+                .and_then(|info| self.texture(info.texture()));
+            let index = self
+                .scene
+                .push_material(Material::Emissive(EmissiveMaterial {
+                    color: emission,
+                    intensity: 1.0,
+                    emission_texture,
+                }));
+            self.materials.insert(material.index(), index);
+            return index;
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let base_color_texture = pbr
+            .base_color_texture()
+            .and_then(|info| self.texture(info.texture()));
+        let metallic_roughness_texture = pbr
+            .metallic_roughness_texture()
+            .and_then(|info| self.texture(info.texture()));
+        let normal_texture = material
+            .normal_texture()
+            .and_then(|normal| self.texture(normal.texture()));
+
+        let index = self.scene.push_material(Material::Pbr(PbrMaterial {
+            base_color: Vec3::new(r, g, b),
+            metallic: pbr.metallic_factor(),
+            roughness: pbr.roughness_factor(),
+            refraction_index: material.ior().unwrap_or(1.5),
+            transmission: material
+                .transmission()
+                .map_or(0.0, |transmission| transmission.transmission_factor()),
+            base_color_texture,
+            roughness_texture: metallic_roughness_texture,
+            metallic_texture: metallic_roughness_texture,
+            normal_texture,
+        }));
+        self.materials.insert(material.index(), index);
+        index
+    }
+
+    /// Index of the texture among the scene's textures, `None` for unsupported formats.
+    fn texture(&mut self, texture: ::gltf::Texture) -> Option<u32> {
+        let index = texture.source().index();
+        if let Some(&texture) = self.textures.get(&index) {
+            return Some(texture);
+        }
+
+        let texture = self.scene.push_texture(to_rgba8(&self.images[index])?);
+        self.textures.insert(index, texture);
+        Some(texture)
+    }
+}
+
+fn to_rgba8(data: &::gltf::image::Data) -> Option<RgbaImage> {
+    let (width, height) = (data.width, data.height);
+    let pixels = data.pixels.clone();
+    let u16_pixels = || -> Vec<u16> {
+        data.pixels
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect()
+    };
+    let f32_pixels = || -> Vec<f32> {
+        data.pixels
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect()
+    };
+
+    let image = match data.format {
+        Format::R8 => DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, pixels)?),
+        Format::R8G8 => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, pixels)?),
+        Format::R8G8B8 => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, pixels)?),
+        Format::R8G8B8A8 => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, pixels)?),
+        Format::R16 => {
+            DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, u16_pixels())?)
+        }
+        Format::R16G16 => {
+            DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, u16_pixels())?)
+        }
+        Format::R16G16B16 => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, u16_pixels())?)
+        }
+        Format::R16G16B16A16 => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, u16_pixels())?)
+        }
+        Format::R32G32B32FLOAT => {
+            DynamicImage::ImageRgb32F(ImageBuffer::from_raw(width, height, f32_pixels())?)
+        }
+        Format::R32G32B32A32FLOAT => {
+            DynamicImage::ImageRgba32F(ImageBuffer::from_raw(width, height, f32_pixels())?)
+        }
+    };
+    Some(image.to_rgba8())
+}
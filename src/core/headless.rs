@@ -0,0 +1,84 @@
+use anyhow::*;
+
+use crate::core::{
+    adapter::request_adapter, camera::Camera, frame::Frame, scene::Scene, settings::RenderSettings,
+    tracer::Tracer,
+};
+
+/// Renders scenes without a window or surface, e.g. on CI machines without a display.
+pub struct HeadlessRenderer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pub camera: Camera,
+    tracer: Tracer,
+}
+
+impl HeadlessRenderer {
+    pub async fn new(scene: &Scene, settings: &RenderSettings) -> Result<Self> {
+        let (width, height) = (settings.width, settings.height);
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            ..Default::default()
+        });
+
+        let adapter = request_adapter(&instance, None, settings.adapter.as_deref()).await?;
+
+        let (device, queue) = adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                label: None,
+                required_features: wgpu::Features::empty(),
+                experimental_features: wgpu::ExperimentalFeatures::default(),
+                required_limits: adapter.limits(),
+                memory_hints: Default::default(),
+                trace: wgpu::Trace::Off,
+            })
+            .await?;
+
+        let camera = Camera::new(
+            width,
+            height,
+            scene.camera.vfov,
+            scene.camera.position,
+            scene.camera.look_at,
+            scene.camera.defocus_angle,
+            scene.camera.focus_distance(),
+        );
+
+        let tracer = Tracer::new(&device, &queue, width, height, scene, &camera, settings)?;
+        Ok(Self {
+            device,
+            queue,
+            camera,
+            tracer,
+        })
+    }
+
+    /// Accumulates until `samples` samples per pixel are reached and returns the averaged
+    /// image.
+    pub fn render(&mut self, samples: u32) -> Result<Frame> {
+        self.tracer.set_sample_budget(Some(samples));
+        while !self.tracer.is_converged() {
+            self.tracer.update(&self.queue, &mut self.camera);
+
+            let mut encoder =
+                self.device
+                    .create_command_encoder(&wgpu::wgt::CommandEncoderDescriptor {
+                        label: Some("headless_encoder"),
+                    });
+            self.tracer.dispatch(&mut encoder);
+            self.queue.submit(std::iter::once(encoder.finish()));
+        }
+
+        self.tracer.read_accumulation(&self.device, &self.queue)
+    }
+
+    /// Seeds the random numbers, see `Tracer::set_seed`.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.tracer.set_seed(seed);
+    }
+
+    /// Number of samples per pixel accumulated so far.
+    pub fn samples(&self) -> u32 {
+        self.tracer.samples()
+    }
+}
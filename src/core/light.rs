@@ -0,0 +1,175 @@
+use serde::Deserialize;
+
+use crate::{
+    core::{buffer::BufferObject, material::default_intensity},
+    math::vec::Vec3,
+};
+
+fn default_inner_angle() -> f32 {
+    30.0
+}
+
+fn default_outer_angle() -> f32 {
+    45.0
+}
+
+/// Light source of the scene file, emitting `color * intensity`.
+///
+/// Sphere and quad lights are area lights: they are added to the scene as emissive shapes,
+/// so they are visible and also found by rays that hit them by chance. Point, spot and
+/// directional lights are infinitely small and only reached through light sampling.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Light {
+    Point {
+        position: Vec3,
+        color: Vec3,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+    /// Point light restricted to a cone around `direction`, fading out between the inner and
+    /// outer angle (half angles in degrees).
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        color: Vec3,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+        #[serde(default = "default_inner_angle")]
+        inner_angle: f32,
+        #[serde(default = "default_outer_angle")]
+        outer_angle: f32,
+    },
+    /// Parallel light travelling along `direction`, like the sun.
+    Directional {
+        direction: Vec3,
+        color: Vec3,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+    Sphere {
+        center: Vec3,
+        radius: f32,
+        color: Vec3,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+    /// Parallelogram spanned by `edge_u` and `edge_v` from `corner`, emitting towards
+    /// `edge_u × edge_v`.
+    Quad {
+        corner: Vec3,
+        edge_u: Vec3,
+        edge_v: Vec3,
+        color: Vec3,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LightKind {
+    Point = 0,
+    Spot = 1,
+    Directional = 2,
+    Sphere = 3,
+    Triangle = 4,
+    Environment = 5,
+    Planar = 6,
+}
+
+/// Entry of the list of lights sampled by next event estimation.
+///
+/// Area lights reference their emissive sphere, triangle, quad or disk and take position and emission
+/// from there, the environment light samples the environment map. The other fields are only
+/// used by point, spot and directional lights.
+pub(crate) struct LightSource {
+    pub kind: LightKind,
+    pub index: u32,
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub emission: Vec3,
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+}
+
+impl LightSource {
+    pub fn area(kind: LightKind, index: u32) -> Self {
+        Self {
+            kind,
+            index,
+            position: Vec3::zero(),
+            direction: Vec3::zero(),
+            emission: Vec3::zero(),
+            cos_inner: 0.0,
+            cos_outer: 0.0,
+        }
+    }
+
+    /// The light sampled for a point, spot or directional light, `None` for area lights.
+    pub fn from_light(light: &Light) -> Option<Self> {
+        let source = match *light {
+            Light::Point {
+                position,
+                color,
+                intensity,
+            } => Self {
+                position,
+                emission: color.mul(intensity),
+                ..Self::area(LightKind::Point, 0)
+            },
+            Light::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                inner_angle,
+                outer_angle,
+            } => Self {
+                position,
+                direction: direction.normalize(),
+                emission: color.mul(intensity),
+                cos_inner: inner_angle.to_radians().cos(),
+                cos_outer: outer_angle.to_radians().cos(),
+                ..Self::area(LightKind::Spot, 0)
+            },
+            Light::Directional {
+                direction,
+                color,
+                intensity,
+            } => Self {
+                direction: direction.normalize(),
+                emission: color.mul(intensity),
+                ..Self::area(LightKind::Directional, 0)
+            },
+            Light::Sphere { .. } | Light::Quad { .. } => return None,
+        };
+        Some(source)
+    }
+}
+
+impl BufferObject for LightSource {
+    const SIZE: usize = 64;
+
+    /// ## WGSL schema:
+    /// struct Light {
+    ///     position: vec3<f32>,
+    ///     kind: u32,
+    ///     direction: vec3<f32>,
+    ///     index: u32,
+    ///     emission: vec3<f32>,
+    ///     cos_inner: f32,
+    ///     cos_outer: f32,
+    /// }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.position.to_bytes()[0..12]);
+        bytes.extend_from_slice(&(self.kind as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.direction.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.index.to_le_bytes());
+        bytes.extend_from_slice(&self.emission.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.cos_inner.to_le_bytes());
+        bytes.extend_from_slice(&self.cos_outer.to_le_bytes());
+        bytes.resize(Self::SIZE, 0);
+        bytes
+    }
+}
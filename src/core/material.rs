@@ -0,0 +1,279 @@
+use serde::Deserialize;
+
+use crate::{core::buffer::BufferObject, math::vec::Vec3};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MaterialKind {
+    Diffuse = 0,
+    Metallic = 1,
+    Dielectric = 2,
+    Pbr = 3,
+    Emissive = 4,
+    Volume = 5,
+}
+
+/// Material of the scene file, referenced by its index in `Scene::materials`.
+///
+/// All kinds but emissive and volume are special cases of one principled BSDF, a Lambertian
+/// base under a dielectric GGX coat, blended with a GGX metal and a rough glass lobe. Every
+/// kind is packed into the parameters of that BSDF, so the shader reads all materials from one
+/// buffer and only tells emitters and volume boundaries apart by the kind.
+///
+/// The `*_texture` fields index the scene's texture sources, in the scene file its `textures`.
+/// Color images are sRGB encoded, procedural colors linear. Roughness is read from the green
+/// and metallic from the blue channel, so a glTF metallic-roughness texture serves as both and
+/// grayscale textures work as either. Normal textures are tangent space normal maps.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Material {
+    Diffuse(DiffuseMaterial),
+    Metallic(MetallicMaterial),
+    Dielectric(DielectricMaterial),
+    Pbr(PbrMaterial),
+    Emissive(EmissiveMaterial),
+    Volume(VolumeMaterial),
+}
+
+impl Material {
+    pub(crate) fn kind(&self) -> MaterialKind {
+        match self {
+            Material::Diffuse(_) => MaterialKind::Diffuse,
+            Material::Metallic(_) => MaterialKind::Metallic,
+            Material::Dielectric(_) => MaterialKind::Dielectric,
+            Material::Pbr(_) => MaterialKind::Pbr,
+            Material::Emissive(_) => MaterialKind::Emissive,
+            Material::Volume(_) => MaterialKind::Volume,
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        matches!(self, Material::Emissive(_))
+    }
+
+    /// Textures of the color (albedo, base color or emission), roughness, metallic and
+    /// normal channel.
+    pub fn textures(&self) -> [Option<u32>; 4] {
+        match self {
+            Material::Diffuse(material) => {
+                [material.albedo_texture, None, None, material.normal_texture]
+            }
+            Material::Metallic(material) => [
+                material.albedo_texture,
+                material.roughness_texture,
+                None,
+                material.normal_texture,
+            ],
+            Material::Dielectric(material) => [
+                None,
+                material.roughness_texture,
+                None,
+                material.normal_texture,
+            ],
+            Material::Pbr(material) => [
+                material.base_color_texture,
+                material.roughness_texture,
+                material.metallic_texture,
+                material.normal_texture,
+            ],
+            Material::Emissive(material) => [material.emission_texture, None, None, None],
+            Material::Volume(_) => [None; 4],
+        }
+    }
+}
+
+impl BufferObject for Material {
+    const SIZE: usize = 80;
+
+    /// ## WGSL schema:
+    /// struct Material {
+    ///     color: vec3<f32>, // base color or emitted color
+    ///     kind: u32,
+    ///     metallic: f32,
+    ///     roughness: f32,
+    ///     refraction_index: f32, // of the coat and the glass lobe, 1 disables the coat
+    ///     intensity: f32, // emitted radiance scale, density of volumes
+    ///     color_texture: i32, // -1 without texture
+    ///     roughness_texture: i32,
+    ///     normal_texture: i32,
+    ///     transmission: f32,
+    ///     eta: vec3<f32>, // zero for Schlick's approximation of the metal's Fresnel
+    ///     metallic_texture: i32,
+    ///     k: vec3<f32>,
+    ///     anisotropy: f32, // of the phase function of volumes
+    /// }
+    ///
+    /// Diffuse materials are a base without coat, metallic ones are all metal, dielectrics are
+    /// all glass with a white base. Volumes store their albedo in `color`. The refraction index
+    /// of kinds without coat or glass is 1, the other fields a kind doesn't use are zero.
+    fn to_bytes(&self) -> Vec<u8> {
+        let texture_index = |texture: Option<u32>| texture.map_or(-1, |index| index as i32);
+
+        let color;
+        let (mut metallic, mut roughness, mut refraction_index, mut intensity) =
+            (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+        let (mut transmission, mut anisotropy) = (0.0f32, 0.0f32);
+        let (mut eta, mut k) = (Vec3::zero(), Vec3::zero());
+        match self {
+            Material::Diffuse(material) => {
+                color = material.albedo;
+                roughness = 1.0;
+                refraction_index = 1.0;
+            }
+            Material::Metallic(material) => {
+                color = material.albedo;
+                metallic = 1.0;
+                roughness = material.roughness;
+                refraction_index = 1.0;
+                if let (Some(material_eta), Some(material_k)) = (material.eta, material.k) {
+                    eta = material_eta;
+                    k = material_k;
+                }
+            }
+            Material::Dielectric(material) => {
+                color = Vec3::new(1.0, 1.0, 1.0);
+                roughness = material.roughness;
+                refraction_index = material.refraction_index;
+                transmission = 1.0;
+            }
+            Material::Pbr(material) => {
+                color = material.base_color;
+                metallic = material.metallic;
+                roughness = material.roughness;
+                refraction_index = material.refraction_index;
+                transmission = material.transmission;
+            }
+            Material::Emissive(material) => {
+                color = material.color;
+                intensity = material.intensity;
+            }
+            Material::Volume(material) => {
+                color = material.albedo;
+                intensity = material.density;
+                anisotropy = material.anisotropy;
+            }
+        }
+
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&color.to_bytes()[0..12]);
+        bytes.extend_from_slice(&(self.kind() as u32).to_le_bytes());
+        for parameter in [metallic, roughness, refraction_index, intensity] {
+            bytes.extend_from_slice(&parameter.to_le_bytes());
+        }
+        let [
+            color_texture,
+            roughness_texture,
+            metallic_texture,
+            normal_texture,
+        ] = self.textures();
+        for texture in [color_texture, roughness_texture, normal_texture] {
+            bytes.extend_from_slice(&texture_index(texture).to_le_bytes());
+        }
+        bytes.extend_from_slice(&transmission.to_le_bytes());
+        bytes.extend_from_slice(&eta.to_bytes()[0..12]);
+        bytes.extend_from_slice(&texture_index(metallic_texture).to_le_bytes());
+        bytes.extend_from_slice(&k.to_bytes()[0..12]);
+        bytes.extend_from_slice(&anisotropy.to_le_bytes());
+        bytes.resize(Self::SIZE, 0);
+        bytes
+    }
+}
+
+/// Lambertian reflector.
+#[derive(Deserialize)]
+pub struct DiffuseMaterial {
+    pub albedo: Vec3,
+    pub albedo_texture: Option<u32>,
+    pub normal_texture: Option<u32>,
+}
+
+pub(crate) fn white() -> Vec3 {
+    Vec3::new(1.0, 1.0, 1.0)
+}
+
+/// Conductor with a GGX microfacet lobe whose `roughness` is perceptually linear, the GGX
+/// alpha is its square. Its reflectance at normal incidence is `albedo` under Schlick's
+/// approximation, unless the complex index of refraction `eta + i k` of the metal is given per
+/// RGB channel, then the exact conductor Fresnel equations apply and `albedo` tints them.
+#[derive(Deserialize)]
+pub struct MetallicMaterial {
+    #[serde(default = "white")]
+    pub albedo: Vec3,
+    #[serde(default)]
+    pub roughness: f32,
+    pub eta: Option<Vec3>,
+    pub k: Option<Vec3>,
+    pub albedo_texture: Option<u32>,
+    pub roughness_texture: Option<u32>,
+    pub normal_texture: Option<u32>,
+}
+
+/// Glass that reflects and refracts through GGX microfacets, perfectly smooth at roughness 0.
+#[derive(Deserialize)]
+pub struct DielectricMaterial {
+    pub refraction_index: f32,
+    #[serde(default)]
+    pub roughness: f32,
+    pub roughness_texture: Option<u32>,
+    pub normal_texture: Option<u32>,
+}
+
+fn default_roughness() -> f32 {
+    1.0
+}
+
+fn default_refraction_index() -> f32 {
+    1.5
+}
+
+/// Principled metallic-roughness material in the style of glTF, Disney and OpenPBR.
+///
+/// `metallic` blends a metal lobe using the base color as Schlick reflectance over a
+/// dielectric, which `transmission` blends between a diffuse base under a GGX coat and a rough
+/// glass lobe tinted by the base color. Coat and glass have the index of refraction
+/// `refraction_index`.
+#[derive(Deserialize)]
+pub struct PbrMaterial {
+    pub base_color: Vec3,
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+    #[serde(default = "default_refraction_index")]
+    pub refraction_index: f32,
+    #[serde(default)]
+    pub transmission: f32,
+    pub base_color_texture: Option<u32>,
+    pub roughness_texture: Option<u32>,
+    pub metallic_texture: Option<u32>,
+    pub normal_texture: Option<u32>,
+}
+
+pub(crate) fn default_intensity() -> f32 {
+    1.0
+}
+
+/// Light emitting surface, emits `color * intensity` from its front side and scatters nothing.
+/// An `emission_texture` multiplies the color.
+#[derive(Deserialize)]
+pub struct EmissiveMaterial {
+    pub color: Vec3,
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+    pub emission_texture: Option<u32>,
+}
+
+/// Boundary of a homogeneous participating medium, like smoke or murky water. Rays pass
+/// through the surface unchanged and inside collide with the medium with probability `density`
+/// per unit length, scattering a fraction `albedo` of the light and absorbing the rest. The
+/// direction of scattered light follows the Henyey–Greenstein phase function, isotropic at
+/// `anisotropy` 0, mostly forward towards 1 and backward towards -1.
+///
+/// Volumes have to be closed and must not overlap each other or enclose the camera.
+#[derive(Deserialize)]
+pub struct VolumeMaterial {
+    pub density: f32,
+    #[serde(default = "white")]
+    pub albedo: Vec3,
+    #[serde(default)]
+    pub anisotropy: f32,
+}
@@ -0,0 +1,49 @@
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::{core::material::white, math::vec::Vec3};
+
+/// Homogeneous medium filling the space between all surfaces, the scene file's `[fog]`.
+///
+/// Scatters like a `VolumeMaterial` with the same parameters. Fog filling all of space hides
+/// the sky and blocks directional light, so it can be limited to the layer below `height`
+/// instead, through which the sky shows and the sun shines from above.
+#[derive(Deserialize)]
+pub struct Fog {
+    pub density: f32,
+    #[serde(default = "white")]
+    pub albedo: Vec3,
+    #[serde(default)]
+    pub anisotropy: f32,
+    pub height: Option<f32>,
+}
+
+impl Fog {
+    /// ## WGSL schema:
+    /// struct FogData {
+    ///     albedo: vec3<f32>,
+    ///     density: f32, // 0 without fog
+    ///     anisotropy: f32,
+    ///     height: f32, // the largest f32 for fog without top
+    /// }
+    pub(crate) fn to_bytes(fog: Option<&Self>) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        if let Some(fog) = fog {
+            bytes[0..12].copy_from_slice(&fog.albedo.to_bytes()[0..12]);
+            bytes[12..16].copy_from_slice(&fog.density.to_le_bytes());
+            bytes[16..20].copy_from_slice(&fog.anisotropy.to_le_bytes());
+            bytes[20..24].copy_from_slice(&fog.height.unwrap_or(f32::MAX).to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Checks the parameters shared by volumes and fog.
+pub(crate) fn validate(density: f32, anisotropy: f32) -> Result<()> {
+    ensure!(density >= 0.0, "density must not be negative");
+    ensure!(
+        anisotropy > -1.0 && anisotropy < 1.0,
+        "anisotropy must be in (-1, 1)"
+    );
+    Ok(())
+}
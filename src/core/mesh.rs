@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::{
+    core::{
+        aabb::Aabb,
+        buffer::BufferObject,
+        material::{
+            DielectricMaterial, DiffuseMaterial, EmissiveMaterial, Material, MetallicMaterial,
+        },
+        scene::Scene,
+        texture,
+    },
+    math::{
+        mat::Mat4,
+        vec::{Radians, Vec3},
+    },
+};
+
+/// Translation, rotation (Euler angles in degrees) and uniform scale applied to a mesh.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Vec3,
+    pub scale: f32,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::zero(),
+            rotation: Vec3::zero(),
+            scale: 1.0,
+        }
+    }
+}
+
+impl Transform {
+    pub fn matrix(&self) -> Mat4 {
+        let to_radians = std::f32::consts::PI / 180.0;
+        let rotation = Mat4::rotation(Radians::new(
+            self.rotation.x() * to_radians,
+            self.rotation.y() * to_radians,
+            self.rotation.z() * to_radians,
+        ));
+        Mat4::translation(self.translation) * rotation * Mat4::scale(self.scale)
+    }
+}
+
+/// A Wavefront OBJ or glTF file placed in the scene, the format follows the file extension.
+#[derive(Deserialize)]
+pub struct MeshInstance {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub transform: Transform,
+    /// Overrides the materials from the MTL or glTF file.
+    pub material: Option<u32>,
+    /// Use the first camera of a glTF file as the scene camera.
+    #[serde(default)]
+    pub camera: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct Vertex {
+    pub position: Vec3,
+    /// Zero when the mesh has no normals, the geometric normal is used instead.
+    pub normal: Vec3,
+    pub uv: [f32; 2],
+}
+
+impl BufferObject for Vertex {
+    const SIZE: usize = 32;
+
+    /// ## WGSL schema:
+    /// struct Vertex {
+    ///     position: vec3<f32>,
+    ///     u: f32,
+    ///     normal: vec3<f32>,
+    ///     v: f32,
+    /// }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.position.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.uv[0].to_le_bytes());
+        bytes.extend_from_slice(&self.normal.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.uv[1].to_le_bytes());
+        bytes
+    }
+}
+
+pub struct Triangle {
+    pub vertices: [u32; 3],
+    pub material: u32,
+}
+
+impl Triangle {
+    pub fn bounds(&self, vertices: &[Vertex]) -> Aabb {
+        self.vertices.iter().fold(Aabb::empty(), |bounds, &i| {
+            bounds.grow(&vertices[i as usize].position)
+        })
+    }
+}
+
+impl BufferObject for Triangle {
+    const SIZE: usize = 16;
+
+    /// ## WGSL schema:
+    /// struct Triangle {
+    ///     v0: u32,
+    ///     v1: u32,
+    ///     v2: u32,
+    ///     material: u32,
+    /// }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        for vertex in self.vertices {
+            bytes.extend_from_slice(&vertex.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.material.to_le_bytes());
+        bytes
+    }
+}
+
+/// Loads an OBJ file into the scene's vertex and triangle lists.
+///
+/// MTL materials are mapped onto the closest built in material kind: materials with a non-black
+/// emission `Ke` become emissive, transparent materials
+/// (`d < 1` or illumination models 4, 6, 7) become dielectrics with `Ni` as refraction index,
+/// mirror-like ones (illumination models 3, 5) become metals whose roughness follows the
+/// shininess `Ns`, everything else is diffuse with `Kd` as albedo. The textures `map_Kd` of
+/// diffuse and metallic and `map_Ke` of emissive materials are added to the scene's textures.
+/// OBJ texture coordinates start at the bottom of the image, they are flipped to the top-down
+/// convention of glTF and the texture array.
+pub(crate) fn load_obj(scene: &mut Scene, instance: &MeshInstance, base_dir: &Path) -> Result<()> {
+    let path = base_dir.join(&instance.path);
+    let (models, materials) = tobj::load_obj(&path, &tobj::GPU_LOAD_OPTIONS)
+        .with_context(|| format!("failed to load mesh {}", path.display()))?;
+
+    let materials: Vec<u32> = match (&instance.material, materials) {
+        (Some(_), _) => Vec::new(),
+        (None, Result::Ok(materials)) => {
+            let mut loader = MtlLoader {
+                scene,
+                dir: path.parent().unwrap_or(Path::new(".")),
+                textures: HashMap::new(),
+            };
+            materials
+                .iter()
+                .map(|material| loader.material(material))
+                .collect::<Result<_>>()?
+        }
+        (None, Err(_)) => Vec::new(),
+    };
+    let mut default_material = instance.material;
+
+    let matrix = instance.transform.matrix();
+
+    for model in models {
+        let mesh = &model.mesh;
+        let material = match mesh.material_id.and_then(|id| materials.get(id).copied()) {
+            Some(material) => material,
+            None => *default_material.get_or_insert_with(|| {
+                scene.push_material(Material::Diffuse(DiffuseMaterial {
+                    albedo: Vec3::new(0.8, 0.8, 0.8),
+                    albedo_texture: None,
+                    normal_texture: None,
+                }))
+            }),
+        };
+
+        let first_vertex = scene.vertices.len() as u32;
+        for i in 0..mesh.positions.len() / 3 {
+            let position = Vec3::new(
+                mesh.positions[3 * i],
+                mesh.positions[3 * i + 1],
+                mesh.positions[3 * i + 2],
+            );
+            let normal = if mesh.normals.is_empty() {
+                Vec3::zero()
+            } else {
+                let normal = Vec3::new(
+                    mesh.normals[3 * i],
+                    mesh.normals[3 * i + 1],
+                    mesh.normals[3 * i + 2],
+                );
+                matrix.transform_normal(normal)
+            };
+            let uv = if mesh.texcoords.is_empty() {
+                [0.0, 0.0]
+            } else {
+                [mesh.texcoords[2 * i], 1.0 - mesh.texcoords[2 * i + 1]]
+            };
+
+            scene.vertices.push(Vertex {
+                position: matrix.transform_point(position),
+                normal,
+                uv,
+            });
+        }
+
+        for face in mesh.indices.chunks_exact(3) {
+            scene.triangles.push(Triangle {
+                vertices: [
+                    first_vertex + face[0],
+                    first_vertex + face[1],
+                    first_vertex + face[2],
+                ],
+                material,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Turns MTL materials into scene materials, loading every texture file once.
+struct MtlLoader<'a> {
+    scene: &'a mut Scene,
+    /// Directory texture paths are relative to.
+    dir: &'a Path,
+    textures: HashMap<String, u32>,
+}
+
+impl MtlLoader<'_> {
+    fn material(&mut self, material: &tobj::Material) -> Result<u32> {
+        let diffuse = Vec3::from(material.diffuse.unwrap_or([0.8, 0.8, 0.8]));
+        let illumination_model = material.illumination_model.unwrap_or(2);
+        let albedo_texture = self.texture(material.diffuse_texture.as_deref())?;
+
+        let emission = material.unknown_param.get("Ke").and_then(|ke| {
+            let components: Vec<f32> = ke
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .ok()?;
+            <[f32; 3]>::try_from(components).ok().map(Vec3::from)
+        });
+        if let Some(color) = emission.filter(|color| color.length_squared() > 0.0) {
+            let emission_texture =
+                self.texture(material.unknown_param.get("map_Ke").map(String::as_str))?;
+            return Ok(self
+                .scene
+                .push_material(Material::Emissive(EmissiveMaterial {
+                    color,
+                    intensity: 1.0,
+                    emission_texture,
+                })));
+        }
+
+        let transparent = material.dissolve.is_some_and(|dissolve| dissolve < 1.0)
+            || matches!(illumination_model, 4 | 6 | 7);
+        if transparent {
+            return Ok(self
+                .scene
+                .push_material(Material::Dielectric(DielectricMaterial {
+                    refraction_index: material.optical_density.unwrap_or(1.5),
+                    roughness: 0.0,
+                    roughness_texture: None,
+                    normal_texture: None,
+                })));
+        }
+
+        if matches!(illumination_model, 3 | 5) {
+            let albedo = material.specular.map(Vec3::from).unwrap_or(diffuse);
+            // The usual Blinn-Phong to GGX mapping alpha = sqrt(2 / (Ns + 2)), roughness is the
+            // square root of alpha. Ns = 1000 is almost a mirror
+            let shininess = material.shininess.unwrap_or(1000.0).max(0.0);
+            let roughness = (2.0 / (shininess + 2.0)).powf(0.25).clamp(0.0, 1.0);
+            return Ok(self
+                .scene
+                .push_material(Material::Metallic(MetallicMaterial {
+                    albedo,
+                    roughness,
+                    eta: None,
+                    k: None,
+                    albedo_texture,
+                    roughness_texture: None,
+                    normal_texture: None,
+                })));
+        }
+
+        Ok(self.scene.push_material(Material::Diffuse(DiffuseMaterial {
+            albedo: diffuse,
+            albedo_texture,
+            normal_texture: None,
+        })))
+    }
+
+    /// Index of a texture file among the scene's textures.
+    fn texture(&mut self, path: Option<&str>) -> Result<Option<u32>> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        if let Some(&texture) = self.textures.get(path) {
+            return Ok(Some(texture));
+        }
+        let image = texture::load_image(&self.dir.join(path))?;
+        let texture = self.scene.push_texture(image);
+        self.textures.insert(path.to_owned(), texture);
+        Ok(Some(texture))
+    }
+}
@@ -1,8 +1,37 @@
+mod aabb;
+mod adapter;
 mod app;
 mod buffer;
+mod bvh;
 mod camera;
+mod cpu_renderer;
+mod display;
+mod environment;
+mod frame;
+mod gltf;
+mod headless;
+mod light;
+mod material;
+mod medium;
+mod mesh;
+mod primitive;
 mod render_state;
+mod scene;
+mod screenshot;
+mod settings;
+mod shape;
+mod sphere;
 mod texture;
+mod tracer;
 
+pub use adapter::list_adapters;
 pub use app::RayTracer;
+pub use cpu_renderer::CpuRenderer;
+pub use display::{DisplaySettings, ToneMapping};
+pub use frame::Frame;
+pub use headless::HeadlessRenderer;
+pub use scene::Scene;
+pub use settings::{
+    BounceLimits, MAX_RENDER_SCALE, MAX_SAMPLES_PER_FRAME, RenderSettings, Sampler,
+};
 // pub(crate) use texture::Texture;
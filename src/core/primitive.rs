@@ -0,0 +1,28 @@
+use crate::core::buffer::BufferObject;
+
+#[derive(Clone, Copy)]
+pub(crate) enum PrimitiveKind {
+    Sphere = 0,
+    Triangle = 1,
+    Planar = 2,
+}
+
+/// Reference to a primitive in the buffer of its kind, stored in the leaves of the BVH.
+#[derive(Clone, Copy)]
+pub(crate) struct PrimitiveRef {
+    pub kind: PrimitiveKind,
+    pub index: u32,
+}
+
+impl BufferObject for PrimitiveRef {
+    const SIZE: usize = 8;
+
+    /// ## WGSL schema:
+    /// vec2<u32>(primitive type, primitive index)
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&(self.kind as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.index.to_le_bytes());
+        bytes
+    }
+}
@@ -1,67 +1,61 @@
-use std::{
-    iter,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
-};
-use winit::{dpi::PhysicalSize, window::Window};
+use std::{fs, iter, path::PathBuf, sync::Arc};
 
-use wgpu::{
-    // SurfaceTexture,
-    util::DeviceExt,
-    // wgc::device::queue,
-    // wgt::instance,
-};
+use anyhow::*;
+use wgpu::util::DeviceExt;
+use winit::{dpi::PhysicalSize, window::Window};
 
+use crate::core::adapter::request_adapter;
 use crate::core::camera::Camera;
-use crate::core::texture::Texture;
-use crate::math::vec::Vec3;
+use crate::core::display::DisplaySettings;
+use crate::core::scene::Scene;
+use crate::core::screenshot::screenshot_path;
+use crate::core::settings::{MAX_RENDER_SCALE, RenderSettings, Sampler};
+use crate::core::tracer::Tracer;
 
 pub struct RenderState<'window> {
     surface: wgpu::Surface<'window>,
-    pub window_size: PhysicalSize<u32>,
+    window_size: PhysicalSize<u32>,
+    config: wgpu::SurfaceConfiguration,
+    render_scale: f32,
     device: wgpu::Device,
     queue: wgpu::Queue,
     pub camera: Camera,
-    camera_buffer: wgpu::Buffer,
-    compute_pipeline: wgpu::ComputePipeline,
-    compute_bind_group: wgpu::BindGroup,
-    util_bind_group: wgpu::BindGroup,
+    tracer: Tracer,
     render_pipeline: wgpu::RenderPipeline,
+    render_bind_group_layout: wgpu::BindGroupLayout,
     render_bind_group: wgpu::BindGroup,
-    util_data: UtilData,
-    util_buffer: wgpu::Buffer,
-    _random_texture: Texture,
+    sampler: wgpu::Sampler,
+    display: DisplaySettings,
+    display_buffer: wgpu::Buffer,
+    encode_srgb: bool,
 }
 
 impl<'window> RenderState<'window> {
-    pub async fn new(window: Arc<Window>) -> Self {
+    pub async fn new(
+        window: Arc<Window>,
+        scene: &Scene,
+        settings: &RenderSettings,
+    ) -> Result<Self> {
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
             backends: wgpu::Backends::PRIMARY,
             ..Default::default()
         });
 
-        let surface = instance.create_surface(window.clone()).unwrap();
+        let surface = instance.create_surface(window.clone())?;
 
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptionsBase {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                force_fallback_adapter: false,
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .unwrap();
+        let adapter =
+            request_adapter(&instance, Some(&surface), settings.adapter.as_deref()).await?;
 
         let (device, queue) = adapter
             .request_device(&wgpu::DeviceDescriptor {
                 label: None,
                 required_features: wgpu::Features::empty(),
                 experimental_features: wgpu::ExperimentalFeatures::default(),
-                required_limits: wgpu::Limits::default(),
+                required_limits: adapter.limits(),
                 memory_hints: Default::default(),
                 trace: wgpu::Trace::Off,
             })
-            .await
-            .unwrap();
+            .await?;
 
         let surface_capabilities = surface.get_capabilities(&adapter);
 
@@ -72,13 +66,14 @@ impl<'window> RenderState<'window> {
             .find(|f| f.is_srgb())
             .unwrap_or(surface_capabilities.formats[0]);
 
+        // Physical pixels, the window was created with a logical size
         let window_size = window.inner_size();
 
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
-            width: window_size.width,
-            height: window_size.height,
+            width: window_size.width.max(1),
+            height: window_size.height.max(1),
             present_mode: surface_capabilities.present_modes[0],
             alpha_mode: surface_capabilities.alpha_modes[0],
             view_formats: vec![],
@@ -87,168 +82,36 @@ impl<'window> RenderState<'window> {
 
         surface.configure(&device, &config);
 
-        let compute_shader =
-            device.create_shader_module(wgpu::include_wgsl!("../shaders/compute.wgsl"));
-
-        // Texture that will be used to render results of compute shader
-        let render_texture = device.create_texture(&wgpu::wgt::TextureDescriptor {
-            label: Some("render_texture"),
-            size: wgpu::Extent3d {
-                width: window_size.width,
-                height: window_size.height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1, // TODO: here we can setup that the texture can be multi sampled
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8Unorm,
-            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
-            view_formats: &[],
+        // Bilinear filtering averages the 2x2 texels behind every pixel at the largest render
+        // scale and smooths the upscaling below 1
+        let sampler = device.create_sampler(&wgpu::wgt::SamplerDescriptor {
+            label: Some("display_sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
         });
 
-        let texture_view = render_texture.create_view(&wgpu::wgt::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::wgt::SamplerDescriptor::default());
-
+        let render_scale = settings.render_scale;
+        let (width, height) = render_size(window_size, render_scale);
         let camera = Camera::new(
-            window_size.width,
-            window_size.height,
-            60.0,
-            Vec3::new(0.0, 0.0, 0.0),
-            Vec3::new(0.0, 0.0, -1.0),
+            width,
+            height,
+            scene.camera.vfov,
+            scene.camera.position,
+            scene.camera.look_at,
+            scene.camera.defocus_angle,
+            scene.camera.focus_distance(),
         );
 
-        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("camera_buffer"),
-            contents: &camera.to_bytes(),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
+        let tracer = Tracer::new(&device, &queue, width, height, scene, &camera, settings)?;
 
-        let compute_bind_group_layot =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("compute_bg_layout"),
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::StorageTexture {
-                            access: wgpu::StorageTextureAccess::WriteOnly,
-                            format: wgpu::TextureFormat::Rgba8Unorm,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                ],
-            });
-
-        let compute_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("compute_bg"),
-            layout: &compute_bind_group_layot,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: camera_buffer.as_entire_binding(),
-                },
-            ],
-        });
-
-        let util_data = UtilData::new();
-
-        let util_buffer: wgpu::Buffer =
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("util_buffer"),
-                contents: &util_data.to_bytes(),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            });
-
-        // let bytes = include_bytes!("../assets/textures/random_noise.png");
-        let random_texture = Texture::from_image(
-            "./assets/textures/random_noise.png",
-            "random_noise",
-            &device,
-            &queue,
-        )
-        .unwrap();
-
-        let util_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("util_bind_group_layout"),
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Texture {
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            multisampled: false,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-            });
-
-        let util_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("util_bind_data"),
-            layout: &util_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: util_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&random_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::Sampler(&random_texture.sampler),
-                },
-            ],
-        });
-
-        let compute_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("compute_pipeline_layout"),
-                bind_group_layouts: &[&compute_bind_group_layot, &util_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-
-        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("compute_pipeline"),
-            layout: Some(&compute_pipeline_layout),
-            module: &compute_shader,
-            entry_point: Some("main"),
-            compilation_options: Default::default(),
-            cache: None,
+        // Surfaces without an sRGB format expect the shader to encode
+        let encode_srgb = !surface_format.is_srgb();
+        let display = scene.display;
+        let display_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("display_buffer"),
+            contents: &display.to_bytes(encode_srgb),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
         let render_shader =
@@ -274,23 +137,26 @@ impl<'window> RenderState<'window> {
                         ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
             });
 
-        let render_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("render_bg"),
-            layout: &render_bind_group_layot,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&sampler),
-                },
-            ],
-        });
+        let render_bind_group = create_render_bind_group(
+            &device,
+            &render_bind_group_layot,
+            &tracer.texture_view,
+            &sampler,
+            &display_buffer,
+        );
 
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -325,42 +191,154 @@ impl<'window> RenderState<'window> {
             cache: None,
         });
 
-        Self {
+        Ok(Self {
             surface,
             window_size,
+            config,
+            render_scale,
             device,
             queue,
             camera,
-            camera_buffer,
-            compute_pipeline,
-            compute_bind_group,
-            util_bind_group,
+            tracer,
             render_pipeline,
+            render_bind_group_layout: render_bind_group_layot,
             render_bind_group,
-            util_data,
-            util_buffer,
-            _random_texture: random_texture,
+            sampler,
+            display,
+            display_buffer,
+            encode_srgb,
+        })
+    }
+
+    /// Exposure and tone mapping the image is displayed with.
+    pub fn display(&self) -> DisplaySettings {
+        self.display
+    }
+
+    /// Changes how the image is displayed, the accumulated samples stay valid.
+    pub fn set_display(&mut self, display: DisplaySettings) {
+        self.display = display;
+        self.queue.write_buffer(
+            &self.display_buffer,
+            0,
+            &self.display.to_bytes(self.encode_srgb),
+        );
+    }
+
+    /// Adapts the surface, the render targets and the camera's aspect ratio to a new window
+    /// size in physical pixels, which restarts the accumulation. Minimized windows have a size
+    /// of zero and are ignored.
+    pub fn resize(&mut self, window_size: PhysicalSize<u32>) {
+        if window_size.width == 0 || window_size.height == 0 {
+            return;
+        }
+        self.window_size = window_size;
+        self.config.width = window_size.width;
+        self.config.height = window_size.height;
+        self.surface.configure(&self.device, &self.config);
+
+        let (width, height) = render_size(window_size, self.render_scale);
+        if (width, height) != (self.tracer.width, self.tracer.height) {
+            self.tracer.resize(&self.device, width, height);
+            self.camera.resize(width, height);
+            self.render_bind_group = create_render_bind_group(
+                &self.device,
+                &self.render_bind_group_layout,
+                &self.tracer.texture_view,
+                &self.sampler,
+                &self.display_buffer,
+            );
         }
     }
 
-    pub fn update(&mut self) {
-        // self.fps_counter.update();
+    pub fn render_scale(&self) -> f32 {
+        self.render_scale
+    }
 
-        self.util_data.update();
-        self.queue
-            .write_buffer(&self.util_buffer, 0, &self.util_data.to_bytes());
+    /// Changes the render resolution relative to the window and restarts the accumulation.
+    pub fn set_render_scale(&mut self, render_scale: f32) {
+        self.render_scale = render_scale.clamp(0.1, MAX_RENDER_SCALE);
+        self.resize(self.window_size);
+    }
 
-        self.camera.update();
-        self.queue
-            .write_buffer(&self.camera_buffer, 0, &self.camera.to_bytes());
+    /// Size of the rendered image in pixels.
+    pub fn render_resolution(&self) -> (u32, u32) {
+        (self.tracer.width, self.tracer.height)
     }
 
-    pub fn render(&self) {
-        let frame = self.surface.get_current_texture();
-        if frame.is_err() {
-            return;
+    /// Number of samples per pixel accumulated so far.
+    pub fn samples(&self) -> u32 {
+        self.tracer.samples()
+    }
+
+    pub fn samples_per_frame(&self) -> u32 {
+        self.tracer.samples_per_frame()
+    }
+
+    /// Changes how many samples per pixel every frame adds, keeping the accumulated ones.
+    pub fn set_samples_per_frame(&mut self, samples_per_frame: u32) {
+        self.tracer.set_samples_per_frame(samples_per_frame);
+    }
+
+    /// Saves the accumulated image at full precision to a new timestamped file with the given
+    /// extension, `png` or `exr`, along with the camera and sampling parameters. Returns the
+    /// path written to.
+    pub fn save_screenshot(&self, extension: &str) -> Result<PathBuf> {
+        let frame = self.tracer.read_accumulation(&self.device, &self.queue)?;
+
+        let mut metadata = self.camera.metadata();
+        metadata.extend([
+            ("samples", self.tracer.samples().to_string()),
+            ("sampler", self.tracer.sampler().to_string()),
+            ("seed", self.tracer.seed().to_string()),
+            ("max_bounces", self.tracer.max_bounces().to_string()),
+            ("exposure", self.display.exposure.to_string()),
+            ("tone_mapping", format!("{:?}", self.display.tone_mapping)),
+        ]);
+
+        let path = screenshot_path(extension);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory {}", dir.display()))?;
         }
-        let frame = frame.unwrap();
+        frame.save_with_metadata(&path, &self.display, &metadata)?;
+        Ok(path)
+    }
+
+    pub fn sampler(&self) -> Sampler {
+        self.tracer.sampler()
+    }
+
+    /// Changes the distribution of the random numbers and restarts the accumulation.
+    pub fn set_sampler(&mut self, sampler: Sampler) {
+        self.tracer.set_sampler(sampler);
+    }
+
+    pub fn max_bounces(&self) -> u32 {
+        self.tracer.max_bounces()
+    }
+
+    /// Changes the path length and restarts the accumulation.
+    pub fn set_max_bounces(&mut self, max_bounces: u32) {
+        self.tracer.set_max_bounces(max_bounces);
+    }
+
+    pub fn update(&mut self) {
+        // self.fps_counter.update();
+
+        self.tracer.update(&self.queue, &mut self.camera);
+    }
+
+    pub fn render(&mut self) {
+        let frame = match self.surface.get_current_texture() {
+            Result::Ok(frame) => frame,
+            // The surface no longer matches the window, e.g. during a resize
+            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                self.surface.configure(&self.device, &self.config);
+                return;
+            }
+            Err(_) => return,
+        };
         let view = frame.texture.create_view(&Default::default());
 
         let mut encoder =
@@ -369,17 +347,8 @@ impl<'window> RenderState<'window> {
                     label: Some("render_encoder"),
                 });
 
-        {
-            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                label: Some("compute_pass"),
-                timestamp_writes: None,
-            });
-
-            pass.set_pipeline(&self.compute_pipeline);
-            pass.set_bind_group(0, &self.compute_bind_group, &[]);
-            pass.set_bind_group(1, &self.util_bind_group, &[]);
-            pass.dispatch_workgroups(self.window_size.width, self.window_size.height, 1);
-        }
+        // Does nothing once the sample budget is spent, the converged image stays on screen
+        self.tracer.dispatch(&mut encoder);
 
         {
             let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -408,37 +377,35 @@ impl<'window> RenderState<'window> {
     }
 }
 
-struct UtilData {
-    time: u32,
-    _pad0: u32,
-    _pad1: u32,
-    _pad2: u32,
-}
-
-fn get_time() -> u32 {
-    let dur = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
-    dur.as_micros() as u32
+/// Render resolution for a window size in physical pixels.
+fn render_size(window_size: PhysicalSize<u32>, render_scale: f32) -> (u32, u32) {
+    let scale = |size: u32| ((size as f32 * render_scale).round() as u32).max(1);
+    (scale(window_size.width), scale(window_size.height))
 }
 
-impl UtilData {
-    fn new() -> Self {
-        Self {
-            time: get_time(),
-            _pad0: 0,
-            _pad1: 0,
-            _pad2: 0,
-        }
-    }
-
-    fn update(&mut self) {
-        self.time = get_time();
-    }
-
-    fn to_bytes(&self) -> [u8; 16] {
-        let mut bytes = [0u8; 16];
-        bytes[0..4].copy_from_slice(&self.time.to_le_bytes());
-        bytes
-    }
+fn create_render_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    texture_view: &wgpu::TextureView,
+    sampler: &wgpu::Sampler,
+    display_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("render_bg"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(texture_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: display_buffer.as_entire_binding(),
+            },
+        ],
+    })
 }
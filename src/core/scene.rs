@@ -0,0 +1,634 @@
+use std::{fs, path::Path};
+
+use anyhow::*;
+use image::RgbaImage;
+use serde::Deserialize;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    core::{
+        aabb::Aabb,
+        buffer::to_storage_bytes,
+        bvh::Bvh,
+        display::DisplaySettings,
+        environment::Environment,
+        gltf,
+        light::{Light, LightKind, LightSource},
+        material::{EmissiveMaterial, Material},
+        medium::{self, Fog},
+        mesh::{self, MeshInstance, Triangle, Vertex},
+        primitive::{PrimitiveKind, PrimitiveRef},
+        shape::{Planar, PlanarKind, Shape},
+        sphere::Sphere,
+        texture::{self, Texture, TextureNode, TextureSource},
+    },
+    math::vec::Vec3,
+};
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct SceneCamera {
+    pub position: Vec3,
+    pub look_at: Vec3,
+    pub vfov: f32,
+    /// Cone angle in degrees of the rays through a pixel, 0 disables depth of field.
+    pub defocus_angle: f32,
+    /// Distance of the sharp plane, defaults to the distance to `look_at`.
+    pub focus_distance: Option<f32>,
+}
+
+impl Default for SceneCamera {
+    fn default() -> Self {
+        Self {
+            position: Vec3::new(0.0, 0.0, 0.0),
+            look_at: Vec3::new(0.0, 0.0, -1.0),
+            vfov: 60.0,
+            defocus_angle: 0.0,
+            focus_distance: None,
+        }
+    }
+}
+
+impl SceneCamera {
+    pub fn focus_distance(&self) -> f32 {
+        self.focus_distance
+            .unwrap_or_else(|| (self.position - self.look_at).length())
+    }
+}
+
+/// Scene description loaded from a TOML file.
+///
+/// Spheres, shapes and meshes reference materials by their index in `materials`, which also
+/// collects the materials of mesh files and lights. Shapes are broken down into the quads,
+/// disks and planes of `planars`. Mesh and texture files are resolved relative to the scene
+/// file. Meshes are loaded into `vertices` and `triangles`. Materials reference textures by
+/// their index in `texture_sources`, which starts with the `texture_nodes` (`textures` in the
+/// scene file) followed by the textures of mesh materials, images among them are loaded into
+/// the layers of `textures`. Sphere and quad lights become emissive spheres and quads.
+/// `display` holds the exposure and tone mapping the image is viewed with.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Scene {
+    pub camera: SceneCamera,
+    pub display: DisplaySettings,
+    pub spheres: Vec<Sphere>,
+    pub shapes: Vec<Shape>,
+    pub meshes: Vec<MeshInstance>,
+    pub lights: Vec<Light>,
+    pub environment: Option<Environment>,
+    pub fog: Option<Fog>,
+    pub materials: Vec<Material>,
+    #[serde(rename = "textures")]
+    pub texture_nodes: Vec<TextureNode>,
+    #[serde(skip)]
+    pub textures: Vec<RgbaImage>,
+    #[serde(skip)]
+    pub(crate) texture_sources: Vec<TextureSource>,
+    #[serde(skip)]
+    pub planars: Vec<Planar>,
+    #[serde(skip)]
+    pub vertices: Vec<Vertex>,
+    #[serde(skip)]
+    pub triangles: Vec<Triangle>,
+}
+
+impl Scene {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("failed to read scene file {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        let scene = Self::parse(&source, base_dir)
+            .with_context(|| format!("failed to load scene file {}", path.display()))?;
+        Ok(scene)
+    }
+
+    /// Parses a scene, mesh and texture paths are resolved relative to `base_dir`.
+    pub fn parse(source: &str, base_dir: impl AsRef<Path>) -> Result<Self> {
+        let mut scene: Self = toml::from_str(source)?;
+        scene.validate()?;
+
+        for node in &scene.texture_nodes {
+            let layer = scene.textures.len() as u32;
+            if let TextureNode::Image { path } = node {
+                let image = texture::load_image(&base_dir.as_ref().join(path))?;
+                scene.textures.push(image);
+            }
+            scene.texture_sources.push(TextureSource::new(node, layer));
+        }
+
+        for shape in &scene.shapes {
+            shape.build(&mut scene.planars);
+        }
+        let meshes = std::mem::take(&mut scene.meshes);
+        for instance in &meshes {
+            let extension = instance
+                .path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .map(str::to_ascii_lowercase);
+            match extension.as_deref() {
+                Some("gltf" | "glb") => gltf::load_gltf(&mut scene, instance, base_dir.as_ref())?,
+                _ => mesh::load_obj(&mut scene, instance, base_dir.as_ref())?,
+            }
+        }
+        scene.meshes = meshes;
+        scene.add_area_lights();
+        if let Some(environment) = &mut scene.environment {
+            environment.load(base_dir.as_ref())?;
+        }
+
+        Ok(scene)
+    }
+
+    /// Adds a material and returns its index.
+    pub(crate) fn push_material(&mut self, material: Material) -> u32 {
+        self.materials.push(material);
+        self.materials.len() as u32 - 1
+    }
+
+    /// Adds an image texture and returns the index materials reference it by.
+    pub(crate) fn push_texture(&mut self, texture: RgbaImage) -> u32 {
+        self.textures.push(texture);
+        let layer = self.textures.len() as u32 - 1;
+        self.texture_sources.push(TextureSource::image(layer));
+        self.texture_sources.len() as u32 - 1
+    }
+
+    fn validate_material(&self, material: u32, owner: &str) -> Result<()> {
+        ensure!(
+            (material as usize) < self.materials.len(),
+            "{} references material {}, but only {} are defined",
+            owner,
+            material,
+            self.materials.len()
+        );
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.camera.focus_distance() > 0.0,
+            "camera has a non-positive focus distance"
+        );
+        ensure!(
+            (0.0..180.0).contains(&self.camera.defocus_angle),
+            "camera defocus angle must be in [0, 180) degrees"
+        );
+        for (i, node) in self.texture_nodes.iter().enumerate() {
+            node.validate()
+                .with_context(|| format!("texture {} is invalid", i))?;
+        }
+        for (i, material) in self.materials.iter().enumerate() {
+            for texture in material.textures().into_iter().flatten() {
+                ensure!(
+                    (texture as usize) < self.texture_nodes.len(),
+                    "material {} references texture {}, but only {} are defined",
+                    i,
+                    texture,
+                    self.texture_nodes.len()
+                );
+            }
+            let (roughness, refraction_index) = match material {
+                Material::Diffuse(_) | Material::Emissive(_) => continue,
+                Material::Volume(volume) => {
+                    medium::validate(volume.density, volume.anisotropy)
+                        .with_context(|| format!("material {} is invalid", i))?;
+                    continue;
+                }
+                Material::Metallic(metallic) => {
+                    ensure!(
+                        metallic.eta.is_some() == metallic.k.is_some(),
+                        "material {} needs both eta and k for its complex index of refraction",
+                        i
+                    );
+                    (metallic.roughness, None)
+                }
+                Material::Dielectric(dielectric) => {
+                    (dielectric.roughness, Some(dielectric.refraction_index))
+                }
+                Material::Pbr(pbr) => {
+                    ensure!(
+                        (0.0..=1.0).contains(&pbr.metallic)
+                            && (0.0..=1.0).contains(&pbr.transmission),
+                        "material {} has a metallic or transmission outside of [0, 1]",
+                        i
+                    );
+                    (pbr.roughness, Some(pbr.refraction_index))
+                }
+            };
+            ensure!(
+                (0.0..=1.0).contains(&roughness),
+                "material {} has a roughness outside of [0, 1]",
+                i
+            );
+            ensure!(
+                refraction_index.is_none_or(|index| index > 0.0),
+                "material {} has a non-positive refraction index",
+                i
+            );
+        }
+        if let Some(fog) = &self.fog {
+            medium::validate(fog.density, fog.anisotropy).context("fog is invalid")?;
+        }
+        for (i, sphere) in self.spheres.iter().enumerate() {
+            self.validate_material(sphere.material, &format!("sphere {}", i))?;
+            ensure!(
+                sphere.radius > 0.0,
+                "sphere {} has a non-positive radius",
+                i
+            );
+        }
+        for (i, shape) in self.shapes.iter().enumerate() {
+            self.validate_material(shape.material(), &format!("shape {}", i))?;
+            match shape {
+                Shape::Quad { edge_u, edge_v, .. } => ensure!(
+                    edge_u.cross(edge_v).length_squared() > 0.0,
+                    "shape {} is a degenerate quad",
+                    i
+                ),
+                Shape::Box { min, max, .. } => ensure!(
+                    min.x() < max.x() && min.y() < max.y() && min.z() < max.z(),
+                    "shape {} is a box whose min corner isn't below its max corner",
+                    i
+                ),
+                Shape::Plane {
+                    normal, uv_scale, ..
+                } => {
+                    ensure!(normal.length_squared() > 0.0, "shape {} has no normal", i);
+                    ensure!(*uv_scale > 0.0, "shape {} has a non-positive uv scale", i);
+                }
+                Shape::Disk { normal, radius, .. } => {
+                    ensure!(normal.length_squared() > 0.0, "shape {} has no normal", i);
+                    ensure!(*radius > 0.0, "shape {} has a non-positive radius", i);
+                }
+            }
+        }
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            if let Some(material) = mesh.material {
+                self.validate_material(material, &format!("mesh {}", i))?;
+            }
+        }
+        for (i, light) in self.lights.iter().enumerate() {
+            match light {
+                Light::Spot {
+                    direction,
+                    inner_angle,
+                    outer_angle,
+                    ..
+                } => {
+                    ensure!(
+                        direction.length_squared() > 0.0,
+                        "light {} has no direction",
+                        i
+                    );
+                    ensure!(
+                        inner_angle <= outer_angle,
+                        "light {} has an inner angle larger than its outer angle",
+                        i
+                    );
+                }
+                Light::Directional { direction, .. } => ensure!(
+                    direction.length_squared() > 0.0,
+                    "light {} has no direction",
+                    i
+                ),
+                Light::Sphere { radius, .. } => {
+                    ensure!(*radius > 0.0, "light {} has a non-positive radius", i)
+                }
+                Light::Quad { edge_u, edge_v, .. } => ensure!(
+                    edge_u.cross(edge_v).length_squared() > 0.0,
+                    "light {} has a degenerate quad",
+                    i
+                ),
+                Light::Point { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the geometry of sphere and quad lights with an emissive material each.
+    fn add_area_lights(&mut self) {
+        for i in 0..self.lights.len() {
+            match self.lights[i] {
+                Light::Sphere {
+                    center,
+                    radius,
+                    color,
+                    intensity,
+                } => {
+                    let material = self.push_material(Material::Emissive(EmissiveMaterial {
+                        color,
+                        intensity,
+                        emission_texture: None,
+                    }));
+                    self.spheres.push(Sphere {
+                        center,
+                        radius,
+                        material,
+                    });
+                }
+                Light::Quad {
+                    corner,
+                    edge_u,
+                    edge_v,
+                    color,
+                    intensity,
+                } => {
+                    let material = self.push_material(Material::Emissive(EmissiveMaterial {
+                        color,
+                        intensity,
+                        emission_texture: None,
+                    }));
+                    self.planars
+                        .push(Planar::quad(corner, edge_u, edge_v, material));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Every light sampled by next event estimation: the point, spot and directional lights,
+    /// all spheres, triangles, quads and disks with an emissive material and the environment
+    /// map. Emissive planes are infinitely large and only found by rays that hit them.
+    pub(crate) fn light_sources(&self) -> Vec<LightSource> {
+        let lights = self.lights.iter().filter_map(LightSource::from_light);
+        let spheres = self
+            .spheres
+            .iter()
+            .enumerate()
+            .filter(|(_, sphere)| self.materials[sphere.material as usize].is_emissive())
+            .map(|(i, _)| LightSource::area(LightKind::Sphere, i as u32));
+        let triangles = self
+            .triangles
+            .iter()
+            .enumerate()
+            .filter(|(_, triangle)| self.materials[triangle.material as usize].is_emissive())
+            .map(|(i, _)| LightSource::area(LightKind::Triangle, i as u32));
+        let planars = self
+            .planars
+            .iter()
+            .enumerate()
+            .filter(|(_, planar)| {
+                self.materials[planar.material as usize].is_emissive()
+                    && planar.kind != PlanarKind::Plane
+            })
+            .map(|(i, _)| LightSource::area(LightKind::Planar, i as u32));
+        let environment = self
+            .environment
+            .iter()
+            .map(|_| LightSource::area(LightKind::Environment, 0));
+        lights
+            .chain(spheres)
+            .chain(triangles)
+            .chain(planars)
+            .chain(environment)
+            .collect()
+    }
+
+    /// Every primitive of the scene together with its bounds, in the order the BVH is built.
+    fn primitives(&self) -> (Vec<PrimitiveRef>, Vec<Aabb>) {
+        let spheres = self.spheres.iter().enumerate().map(|(i, sphere)| {
+            let primitive = PrimitiveRef {
+                kind: PrimitiveKind::Sphere,
+                index: i as u32,
+            };
+            (primitive, sphere.bounds())
+        });
+        let triangles = self.triangles.iter().enumerate().map(|(i, triangle)| {
+            let primitive = PrimitiveRef {
+                kind: PrimitiveKind::Triangle,
+                index: i as u32,
+            };
+            (primitive, triangle.bounds(&self.vertices))
+        });
+        let planars = self.planars.iter().enumerate().map(|(i, planar)| {
+            let primitive = PrimitiveRef {
+                kind: PrimitiveKind::Planar,
+                index: i as u32,
+            };
+            (primitive, planar.bounds())
+        });
+        spheres.chain(triangles).chain(planars).unzip()
+    }
+
+    /// Builds the BVH over all primitives. The primitives are returned in leaf order, so the
+    /// leaves index them directly.
+    pub(crate) fn bvh(&self) -> (Bvh, Vec<PrimitiveRef>) {
+        let (primitives, bounds) = self.primitives();
+        let bvh = Bvh::build(&bounds);
+        let primitives = bvh
+            .indices
+            .iter()
+            .map(|&i| primitives[i as usize])
+            .collect();
+        (bvh, primitives)
+    }
+
+    pub(crate) fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let mut entries: Vec<wgpu::BindGroupLayoutEntry> = (0..8)
+            .map(|binding| wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            })
+            .collect();
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 8,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+                multisampled: false,
+            },
+            count: None,
+        });
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 9,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        });
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 10,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        });
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 11,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 12,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 13,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 14,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("scene_bg_layout"),
+            entries: &entries,
+        })
+    }
+
+    /// ## WGSL schema:
+    /// @group(2) @binding(0) var<storage, read> spheres: array<Sphere>;
+    /// @group(2) @binding(1) var<storage, read> materials: array<Material>;
+    /// @group(2) @binding(2) var<storage, read> bvh_nodes: array<BvhNode>;
+    /// @group(2) @binding(3) var<storage, read> primitives: array<vec2<u32>>;
+    /// @group(2) @binding(4) var<storage, read> vertices: array<Vertex>;
+    /// @group(2) @binding(5) var<storage, read> triangles: array<Triangle>;
+    /// @group(2) @binding(6) var<storage, read> planars: array<Planar>;
+    /// @group(2) @binding(7) var<storage, read> lights: array<Light>;
+    /// @group(2) @binding(8) var textures: texture_2d_array<f32>;
+    /// @group(2) @binding(9) var texture_sampler: sampler;
+    /// @group(2) @binding(10) var environment_texture: texture_2d<f32>;
+    /// @group(2) @binding(11) var<storage, read> environment_cdf: array<f32>;
+    /// @group(2) @binding(12) var<uniform> environment: EnvironmentData;
+    /// @group(2) @binding(13) var<storage, read> texture_sources: array<TextureSource>;
+    /// @group(2) @binding(14) var<uniform> fog: FogData;
+    pub(crate) fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> wgpu::BindGroup {
+        let (bvh, primitives) = self.bvh();
+
+        let contents = [
+            ("spheres_buffer", to_storage_bytes(&self.spheres)),
+            ("materials_buffer", to_storage_bytes(&self.materials)),
+            ("bvh_nodes_buffer", to_storage_bytes(&bvh.nodes)),
+            ("primitives_buffer", to_storage_bytes(&primitives)),
+            ("vertices_buffer", to_storage_bytes(&self.vertices)),
+            ("triangles_buffer", to_storage_bytes(&self.triangles)),
+            ("planars_buffer", to_storage_bytes(&self.planars)),
+            ("lights_buffer", to_storage_bytes(&self.light_sources())),
+        ];
+
+        let buffers: Vec<wgpu::Buffer> = contents
+            .iter()
+            .map(|(label, bytes)| {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(label),
+                    contents: bytes,
+                    usage: wgpu::BufferUsages::STORAGE,
+                })
+            })
+            .collect();
+
+        let textures = Texture::from_layers(&self.textures, "scene_textures", device, queue);
+
+        let environment = self.environment.as_ref();
+        let environment_texture = Texture::from_hdr_image(
+            environment.and_then(|environment| environment.image.as_ref()),
+            "environment",
+            device,
+            queue,
+        );
+        let (environment_cdf, weight_sum) = Environment::distribution(environment);
+        let environment_cdf_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("environment_cdf_buffer"),
+            contents: bytemuck::cast_slice(&environment_cdf),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let environment_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("environment_buffer"),
+            contents: &Environment::to_bytes(environment, weight_sum),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let fog_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("fog_buffer"),
+            contents: &Fog::to_bytes(self.fog.as_ref()),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let texture_sources_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("texture_sources_buffer"),
+            contents: &to_storage_bytes(&self.texture_sources),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let mut entries: Vec<wgpu::BindGroupEntry> = buffers
+            .iter()
+            .enumerate()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        entries.push(wgpu::BindGroupEntry {
+            binding: 8,
+            resource: wgpu::BindingResource::TextureView(&textures.view),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 9,
+            resource: wgpu::BindingResource::Sampler(&textures.sampler),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 10,
+            resource: wgpu::BindingResource::TextureView(&environment_texture.view),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 11,
+            resource: environment_cdf_buffer.as_entire_binding(),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 12,
+            resource: environment_buffer.as_entire_binding(),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 13,
+            resource: texture_sources_buffer.as_entire_binding(),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 14,
+            resource: fog_buffer.as_entire_binding(),
+        });
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("scene_bg"),
+            layout,
+            entries: &entries,
+        })
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Directory screenshots are written to, relative to the working directory.
+const SCREENSHOT_DIR: &str = "screenshots";
+
+/// Unused path for a screenshot taken now, named after the UTC time, e.g.
+/// `screenshots/2026-10-18_14-03-27.png`.
+pub(crate) fn screenshot_path(extension: &str) -> PathBuf {
+    let timestamp = utc_timestamp(SystemTime::now());
+    let dir = PathBuf::from(SCREENSHOT_DIR);
+    let mut path = dir.join(format!("{timestamp}.{extension}"));
+    // Several screenshots within a second get a counter
+    let mut counter = 1;
+    while path.exists() {
+        counter += 1;
+        path = dir.join(format!("{timestamp}_{counter}.{extension}"));
+    }
+    path
+}
+
+/// `YYYY-MM-DD_hh-mm-ss` in UTC.
+fn utc_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+
+    // Civil date from days since the epoch, Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}_{:02}-{:02}-{:02}",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn timestamp(seconds: u64) -> String {
+        utc_timestamp(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(timestamp(0), "1970-01-01_00-00-00");
+    }
+
+    #[test]
+    fn leap_day() {
+        assert_eq!(timestamp(1709210096), "2024-02-29_12-34-56");
+    }
+
+    #[test]
+    fn year_and_century_boundaries() {
+        assert_eq!(timestamp(946684799), "1999-12-31_23-59-59");
+        assert_eq!(timestamp(951868800), "2000-03-01_00-00-00");
+        assert_eq!(timestamp(4102444800), "2100-01-01_00-00-00");
+    }
+}
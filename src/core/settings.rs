@@ -0,0 +1,139 @@
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use anyhow::*;
+
+/// Largest `RenderSettings::render_scale`, supersampling beyond it costs more than it shows.
+pub const MAX_RENDER_SCALE: f32 = 2.0;
+
+/// Largest `RenderSettings::samples_per_frame`, longer dispatches risk the driver's timeout.
+pub const MAX_SAMPLES_PER_FRAME: u32 = 1024;
+
+/// How a scene is rendered, as opposed to what is rendered. Set from the command line.
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    /// Samples per pixel after which the accumulation stops, `None` refines indefinitely.
+    pub samples_per_pixel: Option<u32>,
+    /// Samples per pixel traced by one dispatch. More converge faster but keep the GPU busy
+    /// longer per frame, which makes an interactive session less responsive.
+    pub samples_per_frame: u32,
+    /// Render resolution relative to the window's size in physical pixels, below 1 renders
+    /// fewer pixels for a faster interactive preview. Headless renders ignore it.
+    pub render_scale: f32,
+    /// Surface interactions a path goes through before it is terminated.
+    pub max_bounces: u32,
+    pub bounce_limits: BounceLimits,
+    pub sampler: Sampler,
+    /// Tile of blue noise for `Sampler::BlueNoise`, every channel an independent pattern.
+    /// `None` uses the tile built into the binary.
+    pub noise_texture: Option<PathBuf>,
+    pub seed: u32,
+    /// Part of the name of the graphics adapter to use, ignoring case. `None` picks the most
+    /// powerful one.
+    pub adapter: Option<String>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 600,
+            samples_per_pixel: None,
+            samples_per_frame: 36,
+            render_scale: 1.0,
+            max_bounces: 10,
+            bounce_limits: BounceLimits::default(),
+            sampler: Sampler::default(),
+            noise_texture: None,
+            seed: 0,
+            adapter: None,
+        }
+    }
+}
+
+/// Limits on the bounces of a path per kind of scattering, on top of `max_bounces`, and the depth
+/// from which paths are terminated by Russian roulette.
+///
+/// Past `russian_roulette_depth` a path survives every bounce with a probability given by its
+/// throughput and is reweighted accordingly, so paths that carry little energy end early without
+/// biasing the image.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BounceLimits {
+    /// Bounces off diffuse materials and the diffuse lobe of PBR materials.
+    pub diffuse: u32,
+    /// Mirror and glossy reflections off metals, glass and PBR materials.
+    pub specular: u32,
+    /// Refractions through glass.
+    pub transmission: u32,
+    /// Bounces before Russian roulette starts, `u32::MAX` disables it.
+    pub russian_roulette_depth: u32,
+}
+
+impl Default for BounceLimits {
+    fn default() -> Self {
+        Self {
+            diffuse: 6,
+            specular: 10,
+            transmission: 10,
+            russian_roulette_depth: 3,
+        }
+    }
+}
+
+/// How the random numbers of the samples in a pixel are distributed.
+///
+/// All samplers are unbiased and converge to the same image. The low discrepancy ones spread
+/// the samples more evenly than independent random numbers, which lowers the noise at low
+/// sample counts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Sampler {
+    /// Independent random numbers.
+    Random = 0,
+    /// Jittered strata, a grid of them per frame for two dimensional samples.
+    Stratified = 1,
+    /// Owen scrambled Sobol sequence, progressive over frames.
+    #[default]
+    Sobol = 2,
+    /// Blue noise tiles over the pixels, so the remaining error looks like fine grain.
+    BlueNoise = 3,
+}
+
+impl Sampler {
+    /// The following sampler, wrapping around, to cycle through them at runtime.
+    pub fn next(self) -> Self {
+        match self {
+            Self::Random => Self::Stratified,
+            Self::Stratified => Self::Sobol,
+            Self::Sobol => Self::BlueNoise,
+            Self::BlueNoise => Self::Random,
+        }
+    }
+}
+
+impl FromStr for Sampler {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "random" => Ok(Self::Random),
+            "stratified" => Ok(Self::Stratified),
+            "sobol" => Ok(Self::Sobol),
+            "blue-noise" => Ok(Self::BlueNoise),
+            _ => bail!(
+                "unknown sampler \"{name}\", expected random, stratified, sobol or blue-noise"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Random => "random",
+            Self::Stratified => "stratified",
+            Self::Sobol => "sobol",
+            Self::BlueNoise => "blue-noise",
+        })
+    }
+}
@@ -0,0 +1,248 @@
+use std::f32::consts::PI;
+
+use serde::Deserialize;
+
+use crate::{
+    core::{aabb::Aabb, buffer::BufferObject, mesh::Transform},
+    math::vec::Vec3,
+};
+
+/// Radius of the disk an infinite plane is cut down to for the BVH. Rays end at `T_MAX`, so
+/// this only cuts planes off far outside of anything the camera can reach.
+const PLANE_EXTENT: f32 = 1.0e5;
+
+fn default_uv_scale() -> f32 {
+    1.0
+}
+
+/// Flat primitive of the scene file, turned into one or more `Planar`s when the scene is
+/// loaded.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Shape {
+    /// Parallelogram spanned by `edge_u` and `edge_v` from `corner`, facing `edge_u × edge_v`.
+    /// The uv coordinates run from 0 to 1 along the edges.
+    Quad {
+        corner: Vec3,
+        edge_u: Vec3,
+        edge_v: Vec3,
+        material: u32,
+    },
+    /// Six outward facing quads between the corners `min` and `max`, rotated around the center
+    /// of the box by `rotation` (Euler angles in degrees).
+    Box {
+        min: Vec3,
+        max: Vec3,
+        #[serde(default)]
+        rotation: Vec3,
+        material: u32,
+    },
+    /// Infinite plane through `point`, textures repeat every `uv_scale` units.
+    Plane {
+        point: Vec3,
+        normal: Vec3,
+        #[serde(default = "default_uv_scale")]
+        uv_scale: f32,
+        material: u32,
+    },
+    /// Disk facing `normal`, textures are mapped onto the square around it.
+    Disk {
+        center: Vec3,
+        normal: Vec3,
+        radius: f32,
+        material: u32,
+    },
+}
+
+impl Shape {
+    pub fn material(&self) -> u32 {
+        match self {
+            Shape::Quad { material, .. }
+            | Shape::Box { material, .. }
+            | Shape::Plane { material, .. }
+            | Shape::Disk { material, .. } => *material,
+        }
+    }
+
+    /// Appends the planar primitives the shape is made of.
+    pub fn build(&self, planars: &mut Vec<Planar>) {
+        match *self {
+            Shape::Quad {
+                corner,
+                edge_u,
+                edge_v,
+                material,
+            } => planars.push(Planar::quad(corner, edge_u, edge_v, material)),
+            Shape::Box {
+                min,
+                max,
+                rotation,
+                material,
+            } => {
+                let center = (min + max).mul(0.5);
+                let rotation = Transform {
+                    rotation,
+                    ..Default::default()
+                }
+                .matrix();
+                let extent = max - min;
+                let dx = Vec3::new(extent.x(), 0.0, 0.0);
+                let dy = Vec3::new(0.0, extent.y(), 0.0);
+                let dz = Vec3::new(0.0, 0.0, extent.z());
+                let faces = [
+                    (Vec3::new(min.x(), min.y(), max.z()), dx, dy),
+                    (Vec3::new(max.x(), min.y(), max.z()), -dz, dy),
+                    (Vec3::new(max.x(), min.y(), min.z()), -dx, dy),
+                    (min, dz, dy),
+                    (Vec3::new(min.x(), max.y(), max.z()), dx, -dz),
+                    (min, dx, dz),
+                ];
+                for (corner, edge_u, edge_v) in faces {
+                    planars.push(Planar::quad(
+                        center + rotation.transform_vector(corner - center),
+                        rotation.transform_vector(edge_u),
+                        rotation.transform_vector(edge_v),
+                        material,
+                    ));
+                }
+            }
+            Shape::Plane {
+                point,
+                normal,
+                uv_scale,
+                material,
+            } => {
+                let (axis_u, axis_v) = plane_axes(normal.normalize());
+                planars.push(Planar {
+                    kind: PlanarKind::Plane,
+                    origin: point,
+                    edge_u: axis_u.mul(uv_scale),
+                    edge_v: axis_v.mul(uv_scale),
+                    material,
+                });
+            }
+            Shape::Disk {
+                center,
+                normal,
+                radius,
+                material,
+            } => {
+                let (axis_u, axis_v) = plane_axes(normal.normalize());
+                planars.push(Planar {
+                    kind: PlanarKind::Disk,
+                    origin: center,
+                    edge_u: axis_u.mul(radius),
+                    edge_v: axis_v.mul(radius),
+                    material,
+                });
+            }
+        }
+    }
+}
+
+/// Two unit vectors that complete `normal` to a right handed orthonormal basis.
+fn plane_axes(normal: Vec3) -> (Vec3, Vec3) {
+    let helper = if normal.x().abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let axis_v = normal.cross(&helper).normalize();
+    let axis_u = axis_v.cross(&normal);
+    (axis_u, axis_v)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PlanarKind {
+    Quad = 0,
+    Disk = 1,
+    Plane = 2,
+}
+
+/// Quad, disk or infinite plane, the points `origin + a * edge_u + b * edge_v` with `a` and
+/// `b` in [0, 1] for quads, `a² + b² <= 1` for disks and any `a` and `b` for planes. The
+/// front side faces `edge_u × edge_v`. Disks and planes have orthogonal edges of equal length.
+#[derive(Clone, Copy)]
+pub struct Planar {
+    pub(crate) kind: PlanarKind,
+    pub origin: Vec3,
+    pub edge_u: Vec3,
+    pub edge_v: Vec3,
+    pub material: u32,
+}
+
+impl Planar {
+    pub fn quad(corner: Vec3, edge_u: Vec3, edge_v: Vec3, material: u32) -> Self {
+        Self {
+            kind: PlanarKind::Quad,
+            origin: corner,
+            edge_u,
+            edge_v,
+            material,
+        }
+    }
+
+    pub fn normal(&self) -> Vec3 {
+        self.edge_u.cross(&self.edge_v).normalize()
+    }
+
+    /// Surface area, 0 for planes since they can't be sampled as lights.
+    pub fn area(&self) -> f32 {
+        let parallelogram = self.edge_u.cross(&self.edge_v).length();
+        match self.kind {
+            PlanarKind::Quad => parallelogram,
+            PlanarKind::Disk => PI * parallelogram,
+            PlanarKind::Plane => 0.0,
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        match self.kind {
+            PlanarKind::Quad => [self.edge_u, self.edge_v, self.edge_u + self.edge_v]
+                .iter()
+                .fold(Aabb::new(self.origin, self.origin), |bounds, &edge| {
+                    bounds.grow(&(self.origin + edge))
+                }),
+            PlanarKind::Disk | PlanarKind::Plane => {
+                let scale = if self.kind == PlanarKind::Plane {
+                    PLANE_EXTENT / self.edge_u.length()
+                } else {
+                    1.0
+                };
+                // Extent of the ellipse `cos(θ) * edge_u + sin(θ) * edge_v` along each axis
+                let extent = Vec3::new(
+                    self.edge_u.x().hypot(self.edge_v.x()),
+                    self.edge_u.y().hypot(self.edge_v.y()),
+                    self.edge_u.z().hypot(self.edge_v.z()),
+                )
+                .mul(scale);
+                Aabb::new(self.origin - extent, self.origin + extent)
+            }
+        }
+    }
+}
+
+impl BufferObject for Planar {
+    const SIZE: usize = 48;
+
+    /// ## WGSL schema:
+    /// struct Planar {
+    ///     origin: vec3<f32>,
+    ///     kind: u32,
+    ///     edge_u: vec3<f32>,
+    ///     area: f32,
+    ///     edge_v: vec3<f32>,
+    ///     material: u32,
+    /// }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.origin.to_bytes()[0..12]);
+        bytes.extend_from_slice(&(self.kind as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.edge_u.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.area().to_le_bytes());
+        bytes.extend_from_slice(&self.edge_v.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.material.to_le_bytes());
+        bytes.resize(Self::SIZE, 0);
+        bytes
+    }
+}
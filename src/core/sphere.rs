@@ -1,4 +1,40 @@
-struct Sphere {
-    radius: f32,
-    position: f32,
+use serde::Deserialize;
+
+use crate::{
+    core::{aabb::Aabb, buffer::BufferObject},
+    math::vec::Vec3,
+};
+
+#[derive(Deserialize)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+    /// Index into the scene's materials.
+    pub material: u32,
+}
+
+impl Sphere {
+    pub fn bounds(&self) -> Aabb {
+        let radius = Vec3::new(self.radius, self.radius, self.radius);
+        Aabb::new(self.center - radius, self.center + radius)
+    }
+}
+
+impl BufferObject for Sphere {
+    const SIZE: usize = 32;
+
+    /// ## WGSL schema:
+    /// struct Sphere {
+    ///     center: vec3<f32>,
+    ///     radius: f32,
+    ///     material: u32,
+    /// }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.center.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.radius.to_le_bytes());
+        bytes.extend_from_slice(&self.material.to_le_bytes());
+        bytes.resize(Self::SIZE, 0);
+        bytes
+    }
 }
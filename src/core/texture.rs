@@ -1,5 +1,283 @@
+use std::path::{Path, PathBuf};
+
 use anyhow::*;
-use image::{GenericImageView, ImageReader};
+use image::{ImageReader, Rgba32FImage, RgbaImage, imageops};
+use serde::Deserialize;
+
+use crate::{
+    core::{buffer::BufferObject, material::white},
+    math::vec::Vec3,
+};
+
+/// Width and height of every layer of a texture array, larger or smaller images are resized.
+pub(crate) const TEXTURE_LAYER_SIZE: u32 = 1024;
+
+/// Most octaves the noise of a procedural texture may sum.
+pub(crate) const MAX_OCTAVES: u32 = 16;
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_octaves() -> u32 {
+    1
+}
+
+fn default_marble_octaves() -> u32 {
+    7
+}
+
+fn default_distortion() -> f32 {
+    10.0
+}
+
+fn black() -> Vec3 {
+    Vec3::zero()
+}
+
+/// Texture of the scene file's `textures`, which materials reference by index.
+///
+/// Besides image files there are procedural patterns, evaluated in the shader. They blend two
+/// linear colors and are looked up by the uv coordinates of the surface or, as solid textures,
+/// by the world position of the hit, either one multiplied by `scale`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TextureNode {
+    Image {
+        path: PathBuf,
+    },
+    /// Alternating `even` and `odd` squares, or cubes for solid mapping, of size 1 / `scale`.
+    /// Uv mapped unless `mapping` says otherwise.
+    Checker {
+        even: Vec3,
+        odd: Vec3,
+        #[serde(default = "default_scale")]
+        scale: f32,
+        mapping: Option<TextureMapping>,
+    },
+    /// Gradient noise summed over `octaves` octaves of doubling frequency (fBm), `low` at -1
+    /// and `high` at 1. Turbulence sums the absolute values instead, from `low` at 0. Solid
+    /// unless `mapping` says otherwise.
+    Noise {
+        #[serde(default = "black")]
+        low: Vec3,
+        #[serde(default = "white")]
+        high: Vec3,
+        #[serde(default = "default_scale")]
+        scale: f32,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default)]
+        turbulence: bool,
+        mapping: Option<TextureMapping>,
+    },
+    /// Bands of `low` and `high` along x, or u for uv mapping, a sine wave whose phase is
+    /// shifted by `distortion` times the turbulence of `octaves` octaves. Solid unless
+    /// `mapping` says otherwise.
+    Marble {
+        #[serde(default = "black")]
+        low: Vec3,
+        #[serde(default = "white")]
+        high: Vec3,
+        #[serde(default = "default_scale")]
+        scale: f32,
+        #[serde(default = "default_marble_octaves")]
+        octaves: u32,
+        #[serde(default = "default_distortion")]
+        distortion: f32,
+        mapping: Option<TextureMapping>,
+    },
+}
+
+impl TextureNode {
+    pub(crate) fn validate(&self) -> Result<()> {
+        let (scale, octaves) = match *self {
+            TextureNode::Image { .. } => return Ok(()),
+            TextureNode::Checker { scale, .. } => (scale, 1),
+            TextureNode::Noise { scale, octaves, .. }
+            | TextureNode::Marble { scale, octaves, .. } => (scale, octaves),
+        };
+        ensure!(scale > 0.0, "scale must be positive");
+        ensure!(
+            (1..=MAX_OCTAVES).contains(&octaves),
+            "octaves must be in [1, {}]",
+            MAX_OCTAVES
+        );
+        Ok(())
+    }
+}
+
+/// Coordinates a procedural texture is looked up by.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureMapping {
+    Uv = 0,
+    Solid = 1,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TextureKind {
+    Image = 0,
+    Checker = 1,
+    Noise = 2,
+    Turbulence = 3,
+    Marble = 4,
+}
+
+/// Entry of the list of textures materials reference, a layer of the texture array or the
+/// parameters of a procedural pattern.
+///
+/// `color_a` is the color of the even squares or at the low end of the noise, `color_b` the
+/// other one.
+pub(crate) struct TextureSource {
+    pub kind: TextureKind,
+    pub layer: u32,
+    pub mapping: TextureMapping,
+    pub scale: f32,
+    pub octaves: u32,
+    pub distortion: f32,
+    pub color_a: Vec3,
+    pub color_b: Vec3,
+}
+
+impl TextureSource {
+    pub fn image(layer: u32) -> Self {
+        Self {
+            kind: TextureKind::Image,
+            layer,
+            mapping: TextureMapping::Uv,
+            scale: 1.0,
+            octaves: 0,
+            distortion: 0.0,
+            color_a: Vec3::zero(),
+            color_b: Vec3::zero(),
+        }
+    }
+
+    /// The texture of a scene file entry, images are expected in texture array layer `layer`.
+    pub fn new(node: &TextureNode, layer: u32) -> Self {
+        match *node {
+            TextureNode::Image { .. } => Self::image(layer),
+            TextureNode::Checker {
+                even,
+                odd,
+                scale,
+                mapping,
+            } => Self {
+                kind: TextureKind::Checker,
+                mapping: mapping.unwrap_or(TextureMapping::Uv),
+                scale,
+                color_a: even,
+                color_b: odd,
+                ..Self::image(0)
+            },
+            TextureNode::Noise {
+                low,
+                high,
+                scale,
+                octaves,
+                turbulence,
+                mapping,
+            } => Self {
+                kind: if turbulence {
+                    TextureKind::Turbulence
+                } else {
+                    TextureKind::Noise
+                },
+                mapping: mapping.unwrap_or(TextureMapping::Solid),
+                scale,
+                octaves,
+                color_a: low,
+                color_b: high,
+                ..Self::image(0)
+            },
+            TextureNode::Marble {
+                low,
+                high,
+                scale,
+                octaves,
+                distortion,
+                mapping,
+            } => Self {
+                kind: TextureKind::Marble,
+                mapping: mapping.unwrap_or(TextureMapping::Solid),
+                scale,
+                octaves,
+                distortion,
+                color_a: low,
+                color_b: high,
+                ..Self::image(0)
+            },
+        }
+    }
+}
+
+impl BufferObject for TextureSource {
+    const SIZE: usize = 48;
+
+    /// ## WGSL schema:
+    /// struct TextureSource {
+    ///     color_a: vec3<f32>,
+    ///     kind: u32,
+    ///     color_b: vec3<f32>,
+    ///     layer: u32,
+    ///     scale: f32,
+    ///     octaves: u32,
+    ///     distortion: f32,
+    ///     mapping: u32,
+    /// }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.color_a.to_bytes()[0..12]);
+        bytes.extend_from_slice(&(self.kind as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.color_b.to_bytes()[0..12]);
+        bytes.extend_from_slice(&self.layer.to_le_bytes());
+        bytes.extend_from_slice(&self.scale.to_le_bytes());
+        bytes.extend_from_slice(&self.octaves.to_le_bytes());
+        bytes.extend_from_slice(&self.distortion.to_le_bytes());
+        bytes.extend_from_slice(&(self.mapping as u32).to_le_bytes());
+        bytes.resize(Self::SIZE, 0);
+        bytes
+    }
+}
+
+/// Loads an image as 8 bit RGBA, for the scene's texture array.
+pub(crate) fn load_image(path: &Path) -> Result<RgbaImage> {
+    let image = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(Error::from)
+        .and_then(|reader| Ok(reader.decode()?))
+        .with_context(|| format!("failed to load texture {}", path.display()))?;
+    Ok(image.to_rgba8())
+}
+
+/// The image resized to a layer of the texture array, followed by every mip level down to a
+/// single texel, each a box filtered half of the previous one.
+pub(crate) fn mip_chain(image: &RgbaImage) -> Vec<RgbaImage> {
+    let mut levels = vec![imageops::resize(
+        image,
+        TEXTURE_LAYER_SIZE,
+        TEXTURE_LAYER_SIZE,
+        imageops::FilterType::Triangle,
+    )];
+    let mut size = TEXTURE_LAYER_SIZE;
+    while size > 1 {
+        size /= 2;
+        let previous = levels.last().unwrap();
+        let level = RgbaImage::from_fn(size, size, |x, y| {
+            let mut sum = [0u32; 4];
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let texel = previous.get_pixel(2 * x + dx, 2 * y + dy);
+                for (sum, channel) in sum.iter_mut().zip(texel.0) {
+                    *sum += channel as u32;
+                }
+            }
+            image::Rgba(sum.map(|sum| ((sum + 2) / 4) as u8))
+        });
+        levels.push(level);
+    }
+    levels
+}
 
 pub struct Texture {
     pub _texture: wgpu::Texture,
@@ -8,16 +286,15 @@ pub struct Texture {
 }
 
 impl Texture {
+    /// Uploads an image as a 2D texture. The texels are stored as is (no sRGB decoding), as
+    /// data like noise needs them.
     pub fn from_image(
-        path: &str,
+        rgba: &RgbaImage,
         label: &str,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-    ) -> Result<Self> {
-        let image = ImageReader::open(path)?.decode()?;
-
-        let rgba = image.to_rgba8();
-        let dimensions = image.dimensions();
+    ) -> Self {
+        let dimensions = rgba.dimensions();
 
         let size = wgpu::Extent3d {
             width: dimensions.0,
@@ -25,7 +302,7 @@ impl Texture {
             depth_or_array_layers: 1,
         };
 
-        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+        let format = wgpu::TextureFormat::Rgba8Unorm;
         let mut texture_label = label.to_owned();
         texture_label.push_str("_texture");
         let texture = device.create_texture(&wgpu::wgt::TextureDescriptor {
@@ -46,7 +323,7 @@ impl Texture {
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            &rgba,
+            rgba,
             wgpu::TexelCopyBufferLayout {
                 offset: 0,
                 bytes_per_row: Some(4 * dimensions.0),
@@ -69,10 +346,162 @@ impl Texture {
             ..Default::default()
         });
 
-        Ok(Self {
+        Self {
+            _texture: texture,
+            view,
+            sampler,
+        }
+    }
+
+    /// Uploads the images as layers of a single 2D texture array with the mip levels of
+    /// `mip_chain`.
+    ///
+    /// All layers share `TEXTURE_LAYER_SIZE`, so UV coordinates stay valid after resizing. The
+    /// texels are stored as is (no sRGB decoding) because color and data textures share the
+    /// array. Without images a single white layer is created, since bindings can't be empty.
+    pub fn from_layers(
+        images: &[RgbaImage],
+        label: &str,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Self {
+        let layer_count = images.len().max(1) as u32;
+        let size = wgpu::Extent3d {
+            width: TEXTURE_LAYER_SIZE,
+            height: TEXTURE_LAYER_SIZE,
+            depth_or_array_layers: layer_count,
+        };
+
+        let mut texture_label = label.to_owned();
+        texture_label.push_str("_texture");
+        let texture = device.create_texture(&wgpu::wgt::TextureDescriptor {
+            label: Some(&texture_label),
+            size,
+            mip_level_count: TEXTURE_LAYER_SIZE.ilog2() + 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        let white = RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+        let layers = if images.is_empty() {
+            std::slice::from_ref(&white)
+        } else {
+            images
+        };
+        for (layer, image) in layers.iter().enumerate() {
+            for (mip_level, level) in mip_chain(image).iter().enumerate() {
+                queue.write_texture(
+                    wgpu::TexelCopyTextureInfo {
+                        aspect: wgpu::TextureAspect::All,
+                        texture: &texture,
+                        mip_level: mip_level as u32,
+                        origin: wgpu::Origin3d {
+                            x: 0,
+                            y: 0,
+                            z: layer as u32,
+                        },
+                    },
+                    level,
+                    wgpu::TexelCopyBufferLayout {
+                        offset: 0,
+                        bytes_per_row: Some(4 * level.width()),
+                        rows_per_image: Some(level.height()),
+                    },
+                    wgpu::Extent3d {
+                        width: level.width(),
+                        height: level.height(),
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+
+        let view = texture.create_view(&wgpu::wgt::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let mut sampler_label = label.to_owned();
+        sampler_label.push_str("_sampler");
+        let sampler = device.create_sampler(&wgpu::wgt::SamplerDescriptor {
+            label: Some(&sampler_label),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            _texture: texture,
+            view,
+            sampler,
+        }
+    }
+
+    /// Uploads a linear HDR image as `Rgba32Float`, or a single black texel without image.
+    ///
+    /// 32 bit float textures aren't filterable everywhere, so they're meant to be read with
+    /// `textureLoad` and the sampler uses nearest filtering.
+    pub fn from_hdr_image(
+        image: Option<&Rgba32FImage>,
+        label: &str,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Self {
+        let black = Rgba32FImage::new(1, 1);
+        let image = image.unwrap_or(&black);
+        let size = wgpu::Extent3d {
+            width: image.width(),
+            height: image.height(),
+            depth_or_array_layers: 1,
+        };
+
+        let mut texture_label = label.to_owned();
+        texture_label.push_str("_texture");
+        let texture = device.create_texture(&wgpu::wgt::TextureDescriptor {
+            label: Some(&texture_label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(image.as_raw()),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(16 * image.width()),
+                rows_per_image: Some(image.height()),
+            },
+            size,
+        );
+
+        let view = texture.create_view(&wgpu::wgt::TextureViewDescriptor::default());
+        let mut sampler_label = label.to_owned();
+        sampler_label.push_str("_sampler");
+        let sampler = device.create_sampler(&wgpu::wgt::SamplerDescriptor {
+            label: Some(&sampler_label),
+            ..Default::default()
+        });
+
+        Self {
             _texture: texture,
             view,
             sampler,
-        })
+        }
     }
 }
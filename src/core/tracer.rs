@@ -0,0 +1,508 @@
+use wgpu::util::DeviceExt;
+
+use anyhow::*;
+
+use crate::core::buffer::read_buffer;
+use crate::core::camera::Camera;
+use crate::core::frame::Frame;
+use crate::core::scene::Scene;
+use crate::core::settings::{BounceLimits, MAX_SAMPLES_PER_FRAME, RenderSettings, Sampler};
+use crate::core::texture::{self, Texture};
+
+pub(crate) const RENDER_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Edge length of the square pixel tiles of one workgroup, matches `WORKGROUP_SIZE` in
+/// compute.wgsl.
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Blue noise tile used when `RenderSettings::noise_texture` is `None`.
+const BLUE_NOISE: &[u8] = include_bytes!("../../assets/textures/blue_noise.png");
+
+/// Compute side of the renderer: traces the scene into `render_texture`.
+///
+/// Shared by the windowed `RenderState` and the `HeadlessRenderer`, which only differ in what
+/// they do with the texture afterwards.
+///
+/// Every dispatch runs one invocation per pixel, which traces `samples_per_frame` samples and
+/// adds them to a floating point accumulation buffer, so a still camera converges over frames.
+/// The accumulation restarts whenever the camera moves and stops once the sample budget, if
+/// any, is reached.
+pub(crate) struct Tracer {
+    pub width: u32,
+    pub height: u32,
+    _render_texture: wgpu::Texture,
+    pub texture_view: wgpu::TextureView,
+    accumulation_buffer: wgpu::Buffer,
+    accumulated_frames: u32,
+    accumulated_samples: u32,
+    samples_per_frame: u32,
+    sample_budget: Option<u32>,
+    camera_buffer: wgpu::Buffer,
+    compute_pipeline: wgpu::ComputePipeline,
+    compute_bind_group_layout: wgpu::BindGroupLayout,
+    compute_bind_group: wgpu::BindGroup,
+    util_bind_group: wgpu::BindGroup,
+    scene_bind_group: wgpu::BindGroup,
+    util_data: UtilData,
+    util_buffer: wgpu::Buffer,
+    _noise_texture: Texture,
+}
+
+impl Tracer {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        width: u32,
+        height: u32,
+        scene: &Scene,
+        camera: &Camera,
+        settings: &RenderSettings,
+    ) -> Result<Self> {
+        let compute_shader =
+            device.create_shader_module(wgpu::include_wgsl!("../shaders/compute.wgsl"));
+
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("camera_buffer"),
+            contents: &camera.to_bytes(),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let compute_bind_group_layot =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("compute_bg_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::StorageTexture {
+                            access: wgpu::StorageTextureAccess::WriteOnly,
+                            format: RENDER_TEXTURE_FORMAT,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let (render_texture, texture_view, accumulation_buffer, compute_bind_group) =
+            create_render_targets(
+                device,
+                &compute_bind_group_layot,
+                &camera_buffer,
+                width,
+                height,
+            );
+
+        let util_data = UtilData::new();
+
+        let util_buffer: wgpu::Buffer =
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("util_buffer"),
+                contents: &util_data.to_bytes(),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let noise_image = match &settings.noise_texture {
+            Some(path) => texture::load_image(path)?,
+            None => image::load_from_memory(BLUE_NOISE)
+                .context("failed to decode the built-in blue noise tile")?
+                .to_rgba8(),
+        };
+        let noise_texture = Texture::from_image(&noise_image, "noise", device, queue);
+
+        let util_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("util_bind_group_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let util_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("util_bind_data"),
+            layout: &util_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: util_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&noise_texture.view),
+                },
+            ],
+        });
+
+        let scene_bind_group_layout = Scene::create_bind_group_layout(device);
+        let scene_bind_group = scene.create_bind_group(device, queue, &scene_bind_group_layout);
+
+        let compute_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("compute_pipeline_layout"),
+                bind_group_layouts: &[
+                    &compute_bind_group_layot,
+                    &util_bind_group_layout,
+                    &scene_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("compute_pipeline"),
+            layout: Some(&compute_pipeline_layout),
+            module: &compute_shader,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        let mut tracer = Self {
+            width,
+            height,
+            _render_texture: render_texture,
+            texture_view,
+            accumulation_buffer,
+            accumulated_frames: 0,
+            accumulated_samples: 0,
+            samples_per_frame: 1,
+            sample_budget: None,
+            camera_buffer,
+            compute_pipeline,
+            compute_bind_group_layout: compute_bind_group_layot,
+            compute_bind_group,
+            util_bind_group,
+            scene_bind_group,
+            util_data,
+            util_buffer,
+            _noise_texture: noise_texture,
+        };
+        tracer.set_seed(settings.seed);
+        tracer.set_max_bounces(settings.max_bounces);
+        tracer.set_bounce_limits(settings.bounce_limits);
+        tracer.set_samples_per_frame(settings.samples_per_frame);
+        tracer.set_sample_budget(settings.samples_per_pixel);
+        tracer.set_sampler(settings.sampler);
+        Ok(tracer)
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: &mut Camera) {
+        if camera.needs_update() {
+            self.reset_accumulation();
+        }
+        camera.update();
+        queue.write_buffer(&self.camera_buffer, 0, &camera.to_bytes());
+
+        self.util_data.update(
+            self.accumulated_frames,
+            self.frame_samples(),
+            self.accumulated_samples,
+        );
+        queue.write_buffer(&self.util_buffer, 0, &self.util_data.to_bytes());
+    }
+
+    /// Recreates the render texture and the accumulation buffer for a new image size and
+    /// restarts the accumulation. `texture_view` changes, bind groups using it must be rebuilt.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        let (render_texture, texture_view, accumulation_buffer, compute_bind_group) =
+            create_render_targets(
+                device,
+                &self.compute_bind_group_layout,
+                &self.camera_buffer,
+                width,
+                height,
+            );
+        self.width = width;
+        self.height = height;
+        self._render_texture = render_texture;
+        self.texture_view = texture_view;
+        self.accumulation_buffer = accumulation_buffer;
+        self.compute_bind_group = compute_bind_group;
+        self.reset_accumulation();
+    }
+
+    /// Discards the accumulated samples, the next dispatch starts a fresh image.
+    pub fn reset_accumulation(&mut self) {
+        self.accumulated_frames = 0;
+        self.accumulated_samples = 0;
+    }
+
+    /// Number of samples per pixel accumulated so far.
+    pub fn samples(&self) -> u32 {
+        self.accumulated_samples
+    }
+
+    pub fn samples_per_frame(&self) -> u32 {
+        self.samples_per_frame
+    }
+
+    /// Changes how many samples per pixel one dispatch traces, up to `MAX_SAMPLES_PER_FRAME`.
+    /// The accumulated samples stay valid.
+    pub fn set_samples_per_frame(&mut self, samples_per_frame: u32) {
+        self.samples_per_frame = samples_per_frame.clamp(1, MAX_SAMPLES_PER_FRAME);
+    }
+
+    /// Limits the accumulation to `budget` samples per pixel, `None` accumulates indefinitely.
+    pub fn set_sample_budget(&mut self, budget: Option<u32>) {
+        self.sample_budget = budget;
+    }
+
+    /// Whether the sample budget is used up, further dispatches do nothing.
+    pub fn is_converged(&self) -> bool {
+        self.frame_samples() == 0
+    }
+
+    /// Samples per pixel the next dispatch traces, the last frame within the budget may trace
+    /// fewer.
+    fn frame_samples(&self) -> u32 {
+        self.sample_budget.map_or(self.samples_per_frame, |budget| {
+            self.samples_per_frame
+                .min(budget.saturating_sub(self.accumulated_samples))
+        })
+    }
+
+    pub fn max_bounces(&self) -> u32 {
+        self.util_data.max_bounces
+    }
+
+    /// Changes the seed of the random numbers and restarts the accumulation. Renders with the
+    /// same seed on the same adapter are identical.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.util_data.seed = seed;
+        self.reset_accumulation();
+    }
+
+    /// Changes the number of bounces after which paths are terminated and restarts the
+    /// accumulation.
+    pub fn set_max_bounces(&mut self, max_bounces: u32) {
+        self.util_data.max_bounces = max_bounces;
+        self.reset_accumulation();
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.util_data.seed
+    }
+
+    pub fn sampler(&self) -> Sampler {
+        self.util_data.sampler
+    }
+
+    /// Changes how the random numbers are distributed and restarts the accumulation.
+    pub fn set_sampler(&mut self, sampler: Sampler) {
+        self.util_data.sampler = sampler;
+        self.reset_accumulation();
+    }
+
+    /// Changes the per material type bounce limits and restarts the accumulation.
+    pub fn set_bounce_limits(&mut self, bounce_limits: BounceLimits) {
+        self.util_data.bounce_limits = bounce_limits;
+        self.reset_accumulation();
+    }
+
+    /// Traces the samples `update` prepared, call `update` before every dispatch.
+    pub fn dispatch(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let samples = self.util_data.samples_per_frame;
+        if samples == 0 {
+            return;
+        }
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("compute_pass"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(&self.compute_pipeline);
+        pass.set_bind_group(0, &self.compute_bind_group, &[]);
+        pass.set_bind_group(1, &self.util_bind_group, &[]);
+        pass.set_bind_group(2, &self.scene_bind_group, &[]);
+        pass.dispatch_workgroups(
+            self.width.div_ceil(WORKGROUP_SIZE),
+            self.height.div_ceil(WORKGROUP_SIZE),
+            1,
+        );
+        drop(pass);
+
+        self.accumulated_frames += 1;
+        self.accumulated_samples += samples;
+    }
+
+    /// Reads the accumulated image back at full precision.
+    pub fn read_accumulation(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Frame> {
+        let bytes = read_buffer(device, queue, &self.accumulation_buffer)?;
+        let pixels = bytes
+            .chunks_exact(16)
+            .map(|texel| {
+                let [r, g, b, samples] =
+                    [0, 4, 8, 12].map(|i| f32::from_le_bytes(texel[i..i + 4].try_into().unwrap()));
+                if samples == 0.0 {
+                    return [0.0, 0.0, 0.0, 1.0];
+                }
+                [r / samples, g / samples, b / samples, 1.0]
+            })
+            .collect();
+        Ok(Frame::new(self.width, self.height, pixels))
+    }
+}
+
+/// Creates the size dependent resources: the render texture, its view, the accumulation buffer
+/// and the bind group pointing the shader at them.
+fn create_render_targets(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    camera_buffer: &wgpu::Buffer,
+    width: u32,
+    height: u32,
+) -> (
+    wgpu::Texture,
+    wgpu::TextureView,
+    wgpu::Buffer,
+    wgpu::BindGroup,
+) {
+    // Texture that will be used to render results of compute shader
+    let render_texture = device.create_texture(&wgpu::wgt::TextureDescriptor {
+        label: Some("render_texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1, // TODO: here we can setup that the texture can be multi sampled
+        dimension: wgpu::TextureDimension::D2,
+        format: RENDER_TEXTURE_FORMAT,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+
+    let texture_view = render_texture.create_view(&wgpu::wgt::TextureViewDescriptor::default());
+
+    // Running sum of samples per pixel, rgb holds the summed radiance and w the sample count
+    let accumulation_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("accumulation_buffer"),
+        size: width as u64 * height as u64 * 16,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+
+    let compute_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("compute_bg"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&texture_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: camera_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: accumulation_buffer.as_entire_binding(),
+            },
+        ],
+    });
+
+    (
+        render_texture,
+        texture_view,
+        accumulation_buffer,
+        compute_bind_group,
+    )
+}
+
+struct UtilData {
+    frame: u32,
+    seed: u32,
+    max_bounces: u32,
+    samples_per_frame: u32,
+    sample_offset: u32,
+    sampler: Sampler,
+    bounce_limits: BounceLimits,
+}
+
+impl UtilData {
+    fn new() -> Self {
+        Self {
+            frame: 0,
+            seed: 0,
+            max_bounces: 10,
+            samples_per_frame: 0,
+            sample_offset: 0,
+            sampler: Sampler::default(),
+            bounce_limits: BounceLimits::default(),
+        }
+    }
+
+    fn update(&mut self, frame: u32, samples_per_frame: u32, sample_offset: u32) {
+        self.frame = frame;
+        self.samples_per_frame = samples_per_frame;
+        self.sample_offset = sample_offset;
+    }
+
+    /// ## WGSL schema:
+    /// struct UtilData {
+    ///     frame: u32, // frames accumulated before this one, 0 restarts the accumulation
+    ///     seed: u32,
+    ///     max_bounces: u32,
+    ///     samples_per_frame: u32, // samples per pixel traced by this dispatch
+    ///     russian_roulette_depth: u32,
+    ///     sample_offset: u32, // samples per pixel accumulated before this dispatch
+    ///     sampler_kind: u32,
+    ///     bounce_limits: vec3<u32>, // diffuse, specular, transmission
+    /// }
+    fn to_bytes(&self) -> [u8; 48] {
+        let limits = &self.bounce_limits;
+        let mut bytes = [0u8; 48];
+        bytes[0..4].copy_from_slice(&self.frame.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.seed.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.max_bounces.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.samples_per_frame.to_le_bytes());
+        bytes[16..20].copy_from_slice(&limits.russian_roulette_depth.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.sample_offset.to_le_bytes());
+        bytes[24..28].copy_from_slice(&(self.sampler as u32).to_le_bytes());
+        // bounce_limits is aligned to 16 bytes
+        bytes[32..36].copy_from_slice(&limits.diffuse.to_le_bytes());
+        bytes[36..40].copy_from_slice(&limits.specular.to_le_bytes());
+        bytes[40..44].copy_from_slice(&limits.transmission.to_le_bytes());
+        bytes
+    }
+}
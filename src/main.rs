@@ -1,11 +1,58 @@
-use raytracer::core::RayTracer;
+mod cli;
+
+use clap::Parser;
+use raytracer::core::{CpuRenderer, HeadlessRenderer, RayTracer, Scene, list_adapters};
 use winit::event_loop::{ControlFlow, EventLoop};
 
-fn main() {
+use crate::cli::Cli;
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    if cli.list_adapters {
+        for adapter in list_adapters() {
+            println!("{adapter}");
+        }
+        return Ok(());
+    }
+
+    let settings = cli.settings()?;
+    let mut scene = Scene::load(&cli.scene)?;
+    cli.apply_camera(&mut scene)?;
+
+    if let Some(output_path) = cli.output() {
+        let samples = settings
+            .samples_per_pixel
+            .unwrap_or(settings.samples_per_frame);
+
+        let gpu_renderer = if cli.cpu {
+            None
+        } else {
+            match pollster::block_on(HeadlessRenderer::new(&scene, &settings)) {
+                Ok(renderer) => Some(renderer),
+                Err(error) if settings.adapter.is_none() => {
+                    eprintln!("{error:#}, falling back to the CPU renderer");
+                    None
+                }
+                Err(error) => return Err(error),
+            }
+        };
+
+        let (frame, metadata) = match gpu_renderer {
+            Some(mut renderer) => (renderer.render(samples)?, renderer.camera.metadata()),
+            None => {
+                let mut renderer = CpuRenderer::new(&scene, &settings);
+                (renderer.render(samples)?, renderer.camera.metadata())
+            }
+        };
+        frame.save_with_metadata(output_path, &scene.display, &metadata)?;
+        return Ok(());
+    }
+
     let event_loop = EventLoop::new().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let mut ray_tracer = RayTracer::empty();
+    let mut ray_tracer = RayTracer::new(scene, settings);
 
     let _ = event_loop.run_app(&mut ray_tracer);
+    Ok(())
 }
@@ -1,128 +1,89 @@
 use std::ops;
 
-use crate::math::vec::{Radians, Vec4};
-
-#[inline]
-fn mul_and_add_slices3(a: [f32; 3], b: [f32; 3]) -> f32 {
-    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
-}
+use crate::math::vec::{Radians, Vec3, Vec4};
 
 #[inline]
 fn mul_and_add_slices4(a: [f32; 4], b: [f32; 4]) -> f32 {
     a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3]
 }
 
-pub struct Mat3 {
-    items: [f32; 9],
+#[derive(Clone, Copy)]
+pub struct Mat4 {
+    items: [f32; 16],
 }
 
-impl Mat3 {
-    pub fn zero() -> Self {
-        Self { items: [0.0; 9] }
-    }
-
-    fn from_slice(slice: [f32; 9]) -> Self {
+impl Mat4 {
+    fn from_slice(slice: [f32; 16]) -> Self {
         Self { items: slice }
     }
 
-    // #[inline]
-    // fn from_slices(slices: [[f32; 3]; 3]) -> Self {
-    //     Self {
-    //         items: bytemuck::cast(slices),
-    //     }
-    // }
-
-    #[inline]
-    fn rotation(angles: Radians) -> Self {
-        let cos_alpha = angles.alpha().cos();
-        let sin_alpha = angles.alpha().sin();
-
-        let cos_beta = angles.beta().cos();
-        let sin_beta = angles.beta().sin();
-
-        let cos_gamma = angles.gamma().cos();
-        let sin_gamma = angles.gamma().sin();
-
-        Self::from_slice([
-            (cos_beta * cos_gamma),
-            (sin_alpha * sin_beta * cos_gamma - cos_alpha * sin_gamma),
-            (cos_alpha * sin_beta * cos_gamma + sin_alpha * sin_gamma),
-            (cos_beta * sin_gamma),
-            (sin_alpha * sin_beta * sin_gamma + cos_alpha * cos_gamma),
-            (cos_alpha * sin_beta * sin_gamma - sin_alpha * cos_gamma),
-            (-sin_beta),
-            (sin_alpha * cos_beta),
-            (cos_alpha * cos_beta),
-        ])
-    }
-
-    #[inline]
-    fn get_row(&self, idx: usize) -> [f32; 3] {
-        assert!(idx < 3);
-        let row_idx = idx * 3;
-        [
-            self.items[row_idx + 0],
-            self.items[row_idx + 1],
-            self.items[row_idx + 2],
-        ]
-    }
-
-    #[inline]
-    fn get_column(&self, idx: usize) -> [f32; 3] {
-        assert!(idx < 3);
-        [
-            self.items[idx + 0 * 3],
-            self.items[idx + 1 * 3],
-            self.items[idx + 2 * 3],
-        ]
+    pub fn identity() -> Self {
+        Self::scale(1.0)
     }
-}
-
-impl ops::Mul for Mat3 {
-    type Output = Self;
-
-    #[inline]
-    fn mul(self, rhs: Self) -> Self::Output {
-        let mut slice = [0.0; 9];
 
-        for i in 0..3 {
-            let row = self.get_row(i);
-            for j in 0..3 {
-                slice[i + j * 3] = mul_and_add_slices3(row, rhs.get_column(j));
+    /// Builds a matrix from column vectors, the layout glTF uses.
+    pub fn from_columns(columns: [[f32; 4]; 4]) -> Self {
+        let mut slice = [0.0; 16];
+        for (j, column) in columns.iter().enumerate() {
+            for (i, item) in column.iter().enumerate() {
+                slice[i * 4 + j] = *item;
             }
         }
-
         Self::from_slice(slice)
     }
-}
-
-impl ops::MulAssign for Mat3 {
-    #[inline]
-    fn mul_assign(&mut self, rhs: Self) {
-        let mut slice = [0.0; 9];
 
-        for i in 0..3 {
-            let row = self.get_row(i);
-            for j in 0..3 {
-                slice[i + j * 3] = mul_and_add_slices3(row, rhs.get_column(j));
-            }
-        }
+    pub fn translation(offset: Vec3) -> Self {
+        Self::from_slice([
+            1.0,
+            0.0,
+            0.0,
+            offset.x(),
+            0.0,
+            1.0,
+            0.0,
+            offset.y(),
+            0.0,
+            0.0,
+            1.0,
+            offset.z(),
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        ])
+    }
 
-        self.items = slice;
+    pub fn scale(factor: f32) -> Self {
+        Self::from_slice([
+            factor, 0.0, 0.0, 0.0, 0.0, factor, 0.0, 0.0, 0.0, 0.0, factor, 0.0, 0.0, 0.0, 0.0, 1.0,
+        ])
     }
-}
 
-pub struct Mat4 {
-    items: [f32; 16],
-}
+    pub fn transform_point(&self, point: Vec3) -> Vec3 {
+        (*self * Vec4::from_vec3(point, 1.0)).get_vec3()
+    }
 
-impl Mat4 {
-    pub fn zero() -> Self {
-        Self { items: [0.0; 16] }
+    pub fn transform_vector(&self, vector: Vec3) -> Vec3 {
+        (*self * Vec4::from_vec3(vector, 0.0)).get_vec3()
     }
 
-    fn from_slice(slice: [f32; 16]) -> Self {
-        Self { items: slice }
+    /// Transforms a normal with the cofactor matrix of the upper 3x3 block, which keeps it
+    /// perpendicular to the surface under non-uniform scaling.
+    pub fn transform_normal(&self, normal: Vec3) -> Vec3 {
+        let columns = [0, 1, 2].map(|j| {
+            let column = self.get_column(j);
+            Vec3::new(column[0], column[1], column[2])
+        });
+        let cofactor = [
+            columns[1].cross(&columns[2]),
+            columns[2].cross(&columns[0]),
+            columns[0].cross(&columns[1]),
+        ];
+        let transformed =
+            cofactor[0].mul(normal.x()) + cofactor[1].mul(normal.y()) + cofactor[2].mul(normal.z());
+        // Mirroring transforms have a negative determinant and would flip the normal
+        let determinant = columns[0].dot(&cofactor[0]);
+        transformed.normalize().mul(determinant.signum())
     }
 
     #[inline]
@@ -161,7 +122,7 @@ impl Mat4 {
         assert!(idx < 4);
         let row_idx = idx * 4;
         [
-            self.items[row_idx + 0],
+            self.items[row_idx],
             self.items[row_idx + 1],
             self.items[row_idx + 2],
             self.items[row_idx + 3],
@@ -172,10 +133,10 @@ impl Mat4 {
     fn get_column(&self, idx: usize) -> [f32; 4] {
         assert!(idx < 4);
         [
-            self.items[idx + 0 * 4],
-            self.items[idx + 1 * 4],
-            self.items[idx + 2 * 4],
-            self.items[idx + 3 * 4],
+            self.items[idx],
+            self.items[idx + 4],
+            self.items[idx + 8],
+            self.items[idx + 12],
         ]
     }
 }
@@ -190,7 +151,7 @@ impl ops::Mul for Mat4 {
         for i in 0..4 {
             let row = self.get_row(i);
             for j in 0..4 {
-                slice[i + j * 4] = mul_and_add_slices4(row, rhs.get_column(j));
+                slice[i * 4 + j] = mul_and_add_slices4(row, rhs.get_column(j));
             }
         }
 
@@ -206,7 +167,7 @@ impl ops::MulAssign for Mat4 {
         for i in 0..4 {
             let row = self.get_row(i);
             for j in 0..4 {
-                slice[i + j * 4] = mul_and_add_slices4(row, rhs.get_column(j));
+                slice[i * 4 + j] = mul_and_add_slices4(row, rhs.get_column(j));
             }
         }
 
@@ -1,8 +1,12 @@
 use std::ops;
 
+use rand::Rng;
+use serde::Deserialize;
+
 use crate::util::random_float_range;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Deserialize)]
+#[serde(from = "[f32; 3]")]
 pub struct Vec3 {
     items: [f32; 3],
 }
@@ -31,7 +35,6 @@ impl Vec3 {
         Self { items: [x, y, z] }
     }
 
-    #[allow(unused)]
     pub fn zero() -> Self {
         Self { items: [0.0; 3] }
     }
@@ -64,7 +67,6 @@ impl Vec3 {
         Self::new(self.x() * scalar, self.y() * scalar, self.z() * scalar)
     }
 
-    #[allow(unused)]
     pub fn dot(&self, other: &Self) -> f32 {
         self.x() * other.x() + self.y() * other.y() + self.z() * other.z()
     }
@@ -81,25 +83,42 @@ impl Vec3 {
         self.div(self.length())
     }
 
+    /// Component-wise minimum.
+    pub fn min(&self, other: &Self) -> Self {
+        Self::new(
+            self.x().min(other.x()),
+            self.y().min(other.y()),
+            self.z().min(other.z()),
+        )
+    }
+
+    /// Component-wise maximum.
+    pub fn max(&self, other: &Self) -> Self {
+        Self::new(
+            self.x().max(other.x()),
+            self.y().max(other.y()),
+            self.z().max(other.z()),
+        )
+    }
+
     // pub fn random() -> Self {
     //     Self::new(random_float(), random_float(), random_float())
     // }
 
-    #[allow(unused)]
-    pub fn random_range(min: f32, max: f32) -> Self {
+    pub fn random_range(rng: &mut impl Rng, min: f32, max: f32) -> Self {
         Self::new(
-            random_float_range(min, max),
-            random_float_range(min, max),
-            random_float_range(min, max),
+            random_float_range(rng, min, max),
+            random_float_range(rng, min, max),
+            random_float_range(rng, min, max),
         )
     }
 
-    #[allow(unused)]
-    pub fn random_unit() -> Self {
+    /// Uniformly distributed on the unit sphere.
+    pub fn random_unit(rng: &mut impl Rng) -> Self {
         loop {
-            let vec = Vec3::random_range(-1.0, 1.0);
+            let vec = Vec3::random_range(rng, -1.0, 1.0);
             let lensq = vec.length_squared();
-            if lensq >= 1e-160 && lensq <= 1.0 {
+            if (1e-160..=1.0).contains(&lensq) {
                 return vec.div(f32::sqrt(lensq));
             }
         }
@@ -110,32 +129,23 @@ impl Vec3 {
     //     if vec.dot(normal) >= 0.0 { vec } else { -vec }
     // }
 
-    #[allow(unused)]
-    pub fn near_zero(&self) -> bool {
-        let s = 1e-8;
-        self.x().abs() < s && self.y().abs() < s && self.z().abs() < s
-    }
-
-    #[allow(unused)]
     pub fn reflect(&self, normal: &Vec3) -> Self {
-        self.clone() - normal.mul(self.dot(&normal) * 2.0)
+        *self - normal.mul(self.dot(normal) * 2.0)
     }
 
-    #[allow(unused)]
     pub fn refract(&self, normal: &Vec3, factor: f32) -> Self {
-        let cos_theta = f32::min((-self.clone()).dot(normal), 1.0);
-        let out_perp = (self.clone() + normal.mul(cos_theta)).mul(factor);
+        let cos_theta = f32::min((-*self).dot(normal), 1.0);
+        let out_perp = (*self + normal.mul(cos_theta)).mul(factor);
         let out_parallel = normal.mul(-f32::sqrt((1.0 - out_perp.length_squared()).abs()));
 
         out_perp + out_parallel
     }
 
-    #[allow(unused)]
-    pub fn random_in_unit_disk() -> Self {
+    pub fn random_in_unit_disk(rng: &mut impl Rng) -> Self {
         loop {
             let vec = Vec3::new(
-                random_float_range(-1.0, 1.0),
-                random_float_range(-1.0, 1.0),
+                random_float_range(rng, -1.0, 1.0),
+                random_float_range(rng, -1.0, 1.0),
                 0.0,
             );
             if vec.length_squared() <= 1.0 {
@@ -144,7 +154,7 @@ impl Vec3 {
         }
     }
 
-    pub fn to_bytes(&self) -> [u8; 16] {
+    pub fn to_bytes(self) -> [u8; 16] {
         let mut bytes = [0u8; 16];
         bytes[0..4].copy_from_slice(&self.items[0].to_le_bytes());
         bytes[4..8].copy_from_slice(&self.items[1].to_le_bytes());
@@ -153,6 +163,26 @@ impl Vec3 {
     }
 }
 
+impl Default for Vec3 {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl From<[f32; 3]> for Vec3 {
+    fn from(items: [f32; 3]) -> Self {
+        Self { items }
+    }
+}
+
+impl ops::Index<usize> for Vec3 {
+    type Output = f32;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.items[index]
+    }
+}
+
 impl ops::Add for Vec3 {
     type Output = Self;
 
@@ -254,11 +284,6 @@ impl Vec4 {
         Vec3::new(self.x(), self.y(), self.z())
     }
 
-    #[allow(unused)]
-    pub fn zero() -> Self {
-        Self { items: [0.0; 4] }
-    }
-
     pub const fn x(&self) -> f32 {
         self.items[0]
     }
@@ -275,50 +300,10 @@ impl Vec4 {
         self.items[3]
     }
 
-    pub fn length_squared(&self) -> f32 {
-        self.x() * self.x() + self.y() * self.y() + self.z() * self.z() + self.w() + self.w()
-    }
-
-    pub fn length(&self) -> f32 {
-        f32::sqrt(self.length_squared())
-    }
-
-    pub fn div(&self, scalar: f32) -> Self {
-        Self::new(
-            self.x() / scalar,
-            self.y() / scalar,
-            self.z() / scalar,
-            self.w() / scalar,
-        )
-    }
-
-    pub fn mul(&self, scalar: f32) -> Self {
-        Self::new(
-            self.x() * scalar,
-            self.y() * scalar,
-            self.z() * scalar,
-            self.w() * scalar,
-        )
-    }
-
-    #[allow(unused)]
     #[inline]
     pub fn dot(&self, other: &Self) -> f32 {
         self.x() * other.x() + self.y() * other.y() + self.z() * other.z() + self.w() * other.w()
     }
-
-    pub fn normalize(&self) -> Self {
-        self.div(self.length())
-    }
-
-    pub fn to_bytes(&self) -> [u8; 16] {
-        let mut bytes = [0u8; 16];
-        bytes[0..4].copy_from_slice(&self.items[0].to_le_bytes());
-        bytes[4..8].copy_from_slice(&self.items[1].to_le_bytes());
-        bytes[8..12].copy_from_slice(&self.items[2].to_le_bytes());
-        bytes[12..16].copy_from_slice(&self.items[3].to_le_bytes());
-        bytes
-    }
 }
 
 impl ops::Add for Vec4 {
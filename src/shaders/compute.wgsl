@@ -1,58 +1,228 @@
-
-
-@group(0) @binding(0) var outputTex: texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(0) var outputTex: texture_storage_2d<rgba16float, write>;
 @group(0) @binding(1) var<uniform> camera: Camera;
+// Running sum per pixel: rgb is the summed radiance, w the number of samples
+@group(0) @binding(2) var<storage, read_write> accumulation: array<vec4<f32>>;
 struct Camera {
     first_pixel_pos: vec3<f32>,
     pixel_delta_u: vec3<f32>,
     pixel_delta_v: vec3<f32>,
     position: vec3<f32>,
+    // Radius vectors of the lens disk ray origins are spread over, zero for a pinhole camera
+    defocus_disk_u: vec3<f32>,
+    defocus_disk_v: vec3<f32>,
 }
 
 @group(1) @binding(0) var<uniform> util: UtilData;
 struct UtilData {
-    time: u32,
-    _pad0: u32,
-    _pad1: u32,
-    _pad2: u32,
+    frame: u32, // frames accumulated before this one, 0 restarts the accumulation
+    seed: u32,
+    max_bounces: u32,
+    samples_per_frame: u32, // samples per pixel traced by this dispatch
+    russian_roulette_depth: u32,
+    sample_offset: u32, // samples per pixel accumulated before this dispatch
+    sampler_kind: u32,
+    bounce_limits: vec3<u32>, // diffuse, specular, transmission
 };
 
-@group(1) @binding(1) var random_texture: texture_2d<f32>;
-@group(1) @binding(2) var random_sampler: sampler;
+// Tile of blue noise, one independent pattern per channel
+@group(1) @binding(1) var noise_texture: texture_2d<f32>;
+
+// PCG hash (Jarzynski and Olano, "Hash Functions for GPU Rendering")
+fn pcg_hash(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// Uniform in [0, 1), one step of the PCG RXS-M-XS generator
+fn pcg_random(state: ptr<function, u32>) -> f32 {
+    let old = *state;
+    *state = old * 747796405u + 2891336453u;
+    let word = ((old >> ((old >> 28u) + 4u)) ^ old) * 277803737u;
+    return to_unit_float(((word >> 22u) ^ word));
+}
 
-var<workgroup> RANDOM_COUNTER_X: u32;
-var<workgroup> RANDOM_COUNTER_Y: u32;
-const RANDOM_IMAGE_WIDTH = 64;
-const RANDOM_IMAGE_HEIGHT = 64;
+// Maps a 32 bit fixed point fraction to [0, 1)
+fn to_unit_float(value: u32) -> f32 {
+    return f32(value >> 8u) / 16777216.0;
+}
 
-fn random_vec3(p: vec2<f32>) -> vec3<f32> {
-    var x = bitcast<u32>(p.x);
-    var y = bitcast<u32>(p.y);
+const SAMPLER_RANDOM = 0u;
+const SAMPLER_STRATIFIED = 1u;
+const SAMPLER_SOBOL = 2u;
+const SAMPLER_BLUE_NOISE = 3u;
+
+// Dimensions of the camera ray (pixel jitter and lens), followed by a fixed number per bounce,
+// so a bounce draws the same dimensions whichever branches the previous bounces took
+const CAMERA_DIMENSIONS = 2u;
+const BOUNCE_DIMENSIONS = 16u;
+
+// Random numbers of one sample. Every draw, 1D or 2D, takes the next dimension of the sample's
+// point in the sequence of the selected sampler
+struct Sampler {
+    pixel: vec2<u32>,
+    sample_id: u32, // sample within this dispatch
+    dimension: u32,
+    rng: u32, // PCG state, for the random sampler and the jitter within strata
+}
 
-    // PCG Hash steps
-    var state = x * 747796405u + 2891336453u;
-    var word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
-    let rx = f32((word >> 22u) ^ word) / 4294967295.0;
+fn init_sampler(pixel: vec2<u32>, sample_id: u32) -> Sampler {
+    let pixel_index = pixel.y * textureDimensions(outputTex).x + pixel.x;
+    let rng = pcg_hash(pixel_index ^ pcg_hash(sample_id ^ pcg_hash(util.frame ^ pcg_hash(util.seed))));
+    return Sampler(pixel, sample_id, 0u, rng);
+}
 
-    state = y * 747796405u + 2891336453u;
-    word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
-    let ry = f32((word >> 22u) ^ word) / 4294967295.0;
+fn start_bounce(state: ptr<function, Sampler>, bounce: u32) {
+    (*state).dimension = CAMERA_DIMENSIONS + bounce * BOUNCE_DIMENSIONS;
+}
 
-    let rz = fract(sin(dot(p, vec2(12.9898, 78.233))) * 43758.5453);
+// Hash of the pixel and a dimension, decorrelates the sequences of neighboring pixels
+fn dimension_hash(state: ptr<function, Sampler>, dimension: u32) -> u32 {
+    let pixel = (*state).pixel;
+    let pixel_index = pixel.y * textureDimensions(outputTex).x + pixel.x;
+    return pcg_hash(pixel_index ^ pcg_hash(dimension ^ pcg_hash(util.seed)));
+}
 
-    // Remap to [-1.0, 1.0] and normalize
-    return normalize(vec3(rx, ry, rz) * 2.0 - 1.0);
+// Uniform in [0, 1)
+fn next_random(state: ptr<function, Sampler>) -> f32 {
+    let dimension = (*state).dimension;
+    (*state).dimension += 1u;
+    let index = util.sample_offset + (*state).sample_id;
+    switch util.sampler_kind {
+        case SAMPLER_STRATIFIED: {
+            let count = util.samples_per_frame;
+            let hash = pcg_hash(dimension_hash(state, dimension) ^ util.frame);
+            let stratum = permute((*state).sample_id, count, hash);
+            return (f32(stratum) + pcg_random(&(*state).rng)) / f32(count);
+        }
+        case SAMPLER_SOBOL: {
+            return sobol(index, dimension_hash(state, dimension)).x;
+        }
+        case SAMPLER_BLUE_NOISE: {
+            let noise = blue_noise(state, dimension).x;
+            return fract(noise + sobol(index, pcg_hash(dimension ^ pcg_hash(util.seed))).x);
+        }
+        default: {
+            return pcg_random(&(*state).rng);
+        }
+    }
+}
+
+// Uniform in [0, 1)^2, stratified in both dimensions together
+fn next_random_2d(state: ptr<function, Sampler>) -> vec2<f32> {
+    let dimension = (*state).dimension;
+    (*state).dimension += 1u;
+    let index = util.sample_offset + (*state).sample_id;
+    switch util.sampler_kind {
+        case SAMPLER_STRATIFIED: {
+            // A square grid with at least one stratum per sample, leftover strata stay empty
+            let side = u32(ceil(sqrt(f32(util.samples_per_frame))));
+            let hash = pcg_hash(dimension_hash(state, dimension) ^ util.frame);
+            let stratum = permute((*state).sample_id, side * side, hash);
+            let jitter = vec2(pcg_random(&(*state).rng), pcg_random(&(*state).rng));
+            return (vec2(f32(stratum % side), f32(stratum / side)) + jitter) / f32(side);
+        }
+        case SAMPLER_SOBOL: {
+            return sobol(index, dimension_hash(state, dimension));
+        }
+        case SAMPLER_BLUE_NOISE: {
+            // The same Sobol points in every pixel, offset by the blue noise, so the error of
+            // neighboring pixels differs as much as possible
+            let noise = blue_noise(state, dimension).xy;
+            return fract(noise + sobol(index, pcg_hash(dimension ^ pcg_hash(util.seed))));
+        }
+        default: {
+            return vec2(pcg_random(&(*state).rng), pcg_random(&(*state).rng));
+        }
+    }
 }
 
-fn next_random(state: ptr<function, u32>) -> f32 {
-    *state = *state * 1664525u + 1013904223u;
-    return f32(*state) / 4294967296.0;
+// Texel of the noise tile for the pixel, every dimension reads it at another offset
+fn blue_noise(state: ptr<function, Sampler>, dimension: u32) -> vec4<f32> {
+    let size = textureDimensions(noise_texture);
+    let hash = pcg_hash(dimension ^ pcg_hash(util.seed));
+    let offset = vec2(hash & 0xffffu, hash >> 16u);
+    return textureLoad(noise_texture, ((*state).pixel + offset) % size, 0);
+}
+
+// Point `index` of the first two Sobol dimensions, every dimension of the sampler is an
+// independently shuffled and scrambled copy of them (Burley, "Practical Hash-based Owen
+// Scrambling")
+fn sobol(index: u32, seed: u32) -> vec2<f32> {
+    let shuffled = nested_uniform_scramble(index, seed);
+    let x = nested_uniform_scramble(reverseBits(shuffled), pcg_hash(seed));
+    let y = nested_uniform_scramble(sobol_second_dimension(shuffled), pcg_hash(seed + 1u));
+    return vec2(to_unit_float(x), to_unit_float(y));
+}
+
+// Second dimension of the Sobol sequence as a 32 bit fraction, the first is reverseBits(index)
+fn sobol_second_dimension(index: u32) -> u32 {
+    var result = 0u;
+    var direction = 1u << 31u;
+    var i = index;
+    while i != 0u {
+        if (i & 1u) != 0u {
+            result ^= direction;
+        }
+        i >>= 1u;
+        direction ^= direction >> 1u;
+    }
+    return result;
+}
+
+// Owen scrambling of the bits of `value`, most significant first
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    var x = reverseBits(value);
+    x += seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return reverseBits(x);
+}
+
+// Position of `index` in a random permutation of [0, length) (Kensler, "Correlated
+// Multi-Jittered Sampling")
+fn permute(index: u32, length: u32, seed: u32) -> u32 {
+    var mask = length - 1u;
+    mask |= mask >> 1u;
+    mask |= mask >> 2u;
+    mask |= mask >> 4u;
+    mask |= mask >> 8u;
+    mask |= mask >> 16u;
+
+    var i = index;
+    loop {
+        i ^= seed;
+        i *= 0xe170893du;
+        i ^= seed >> 16u;
+        i ^= (i & mask) >> 4u;
+        i ^= seed >> 8u;
+        i *= 0x0929eb3fu;
+        i ^= seed >> 23u;
+        i ^= (i & mask) >> 1u;
+        i *= 1u | seed >> 27u;
+        i *= 0x6935fa69u;
+        i ^= (i & mask) >> 11u;
+        i *= 0x74dcb303u;
+        i ^= (i & mask) >> 2u;
+        i *= 0x9e501cc3u;
+        i ^= (i & mask) >> 2u;
+        i *= 0xc860a3dfu;
+        i &= mask;
+        i ^= i >> 5u;
+        if i < length {
+            break;
+        }
+    }
+    return (i + seed) % length;
 }
 
 // Generates a perfectly uniform random unit vector on a sphere surface (Archimedes' Method)
-fn random_unit_vec3(rng: ptr<function, u32>) -> vec3<f32> {
-    let r1 = next_random(rng);
-    let r2 = next_random(rng);
+fn random_unit_vec3(rng: ptr<function, Sampler>) -> vec3<f32> {
+    let random = next_random_2d(rng);
+    let r1 = random.x;
+    let r2 = random.y;
 
     let phi = r1 * 6.283185307179586; // 2 * PI
     let z = r2 * 2.0 - 1.0;           // Height distribution from [-1, 1]
@@ -61,115 +231,184 @@ fn random_unit_vec3(rng: ptr<function, u32>) -> vec3<f32> {
     return vec3<f32>(r_xy * cos(phi), r_xy * sin(phi), z);
 }
 
-fn random_on_hemisphere(normal: vec3<f32>, uv: vec2<f32>) -> vec3<f32> {
-    let random = random_vec3(uv);
-    if dot(random, normal) > 0.0 {
-        return random;
-    } else {
-        return -random;
-    }
+// Uniform point in the unit disk
+fn random_in_unit_disk(rng: ptr<function, Sampler>) -> vec2<f32> {
+    let random = next_random_2d(rng);
+    let r = sqrt(random.x);
+    let phi = 2.0 * PI * random.y;
+    return vec2(r * cos(phi), r * sin(phi));
 }
 
-fn vec2_to_uv(vec: vec2<f32>) -> vec2<f32> {
-    return normalize(vec) / 2.0 + 0.5;
+const SKY_COLOR = vec3(0.5, 0.7, 1.0);
+const PI = 3.141592653589793;
+
+@group(2) @binding(0) var<storage, read> spheres: array<Sphere>;
+@group(2) @binding(1) var<storage, read> materials: array<Material>;
+@group(2) @binding(2) var<storage, read> bvh_nodes: array<BvhNode>;
+@group(2) @binding(3) var<storage, read> primitives: array<vec2<u32>>; // vec2(primitive type, primitive index)
+@group(2) @binding(4) var<storage, read> vertices: array<Vertex>;
+@group(2) @binding(5) var<storage, read> triangles: array<Triangle>;
+@group(2) @binding(6) var<storage, read> planars: array<Planar>;
+@group(2) @binding(7) var<storage, read> lights: array<Light>;
+@group(2) @binding(8) var textures: texture_2d_array<f32>;
+@group(2) @binding(9) var texture_sampler: sampler;
+@group(2) @binding(10) var environment_texture: texture_2d<f32>;
+// Per row CDFs of the environment texels followed by the CDF over the rows
+@group(2) @binding(11) var<storage, read> environment_cdf: array<f32>;
+@group(2) @binding(12) var<uniform> environment: EnvironmentData;
+@group(2) @binding(13) var<storage, read> texture_sources: array<TextureSource>;
+@group(2) @binding(14) var<uniform> fog: FogData;
+
+// Homogeneous medium between the surfaces below `height`
+struct FogData {
+    albedo: vec3<f32>,
+    density: f32, // 0 without fog
+    anisotropy: f32,
+    height: f32, // the largest f32 for fog without top
 }
 
-const SKY_COLOR = vec3(0.5, 0.7, 1.0);
+struct EnvironmentData {
+    intensity: f32,
+    rotation: f32,
+    weight_sum: f32, // sum of luminance * sin(theta) over all texels
+    enabled: u32, // 0 falls back to the sky gradient
+}
+
+const TEXTURE_IMAGE = 0u;
+const TEXTURE_CHECKER = 1u;
+const TEXTURE_NOISE = 2u;
+const TEXTURE_TURBULENCE = 3u;
+const TEXTURE_MARBLE = 4u;
+
+const MAPPING_UV = 0u;
+const MAPPING_SOLID = 1u;
+
+// A layer of `textures` or a procedural pattern blending between two linear colors
+struct TextureSource {
+    color_a: vec3<f32>, // even squares, low end of the noise
+    kind: u32,
+    color_b: vec3<f32>, // odd squares, high end of the noise
+    layer: u32,
+    scale: f32,
+    octaves: u32,
+    distortion: f32, // of marble
+    mapping: u32, // pattern coordinates are the uv coordinates or the world position
+}
+
+const PRIMITIVE_SPHERE = 0u;
+const PRIMITIVE_TRIANGLE = 1u;
+const PRIMITIVE_PLANAR = 2u;
+
+// Interior nodes have count == 0 and their children at left_or_first and left_or_first + 1,
+// leaves reference `count` primitive indices starting at left_or_first
+struct BvhNode {
+    min: vec3<f32>,
+    left_or_first: u32,
+    max: vec3<f32>,
+    count: u32,
+}
 
-const SPHERES_COUNT = 5;
-
-const SPHERES = array<Sphere, 5>(
-    Sphere(
-        vec3(0.0, -100.5, -1.0),
-        100,
-        vec2(0,
-            0),
-    ),
-    Sphere(
-        vec3(0.0, 0.0, -1.2),
-        0.5,
-        vec2(0,
-            1)
-    ),
-    Sphere(
-        vec3(-1.0, 0.0, -1.0),
-        0.5,
-        vec2(2,
-            0)
-    ),
-    Sphere(
-        vec3(-1.0, 0.0, -1.0),
-        0.4,
-        vec2(2,
-            1)
-    ),
-    Sphere(
-        vec3(1.0, 0.0, -1.0),
-        0.5,
-        vec2(1,
-            1)
-    ),
-
-    // Sphere(
-    //     vec3(-4.0, 1.0, 0.0),
-    //     1.0,
-    //     vec3(0.0, 0.0, 1.0),
-    // )
-);
+// Copied in bvh.rs, whose tests check that both agree
+const BVH_STACK_SIZE = 32u;
 
 struct Sphere {
     center: vec3<f32>,
     radius: f32,
-    material: vec2<u32>, // represents: vec2(material type, material index)
+    material: u32, // index into `materials`
 }
 
-struct DiffuseMaterial {
-    alpha: vec3<f32>}
-
-const DIFFUSE_MATERIALS = array<DiffuseMaterial, 2>(
-    DiffuseMaterial(
-        vec3(0.8, 0.8, 0.0)
-    ),
-    DiffuseMaterial(
-        vec3(0.1, 0.2, 0.5)
-    )
-);
+struct Vertex {
+    position: vec3<f32>,
+    u: f32,
+    normal: vec3<f32>, // zero when the mesh has no normals
+    v: f32,
+}
 
-struct MetallicMaterial {
-    alpha: vec3<f32>,
-    fuzz: f32,
+struct Triangle {
+    v0: u32,
+    v1: u32,
+    v2: u32,
+    material: u32,
 }
 
-const METALLIC_MATERIALS = array<MetallicMaterial, 2>(
-    MetallicMaterial(
-        vec3(0.8, 0.8, 0.8), 0.0,
-    ),
-    MetallicMaterial(
-        vec3(0.8, 0.6, 0.2), 0.0,
-    )
-);
+const PLANAR_QUAD = 0u;
+const PLANAR_DISK = 1u;
+const PLANAR_PLANE = 2u;
 
-struct DielectricMaterial {
-    refraction_index: f32,
+// The points origin + a * edge_u + b * edge_v with a and b in [0, 1] for quads, a² + b² <= 1
+// for disks and any a and b for planes, facing cross(edge_u, edge_v)
+struct Planar {
+    origin: vec3<f32>,
+    kind: u32,
+    edge_u: vec3<f32>,
+    area: f32, // 0 for planes
+    edge_v: vec3<f32>,
+    material: u32,
 }
 
-const DIELECTRIC_MATERIALS = array<DielectricMaterial, 2>(
-    DielectricMaterial(
-        1.5,
-    ),
-    DielectricMaterial(
-        1.0 / 1.5,
-    ),
-);
+const MATERIAL_DIFFUSE = 0u;
+const MATERIAL_METALLIC = 1u;
+const MATERIAL_DIELECTRIC = 2u;
+const MATERIAL_PBR = 3u;
+const MATERIAL_EMISSIVE = 4u;
+const MATERIAL_VOLUME = 5u;
+
+// Parameters of the principled BSDF every kind but emissive and volume is packed into, the
+// fields a kind doesn't use are zero. Volumes are the boundaries of a homogeneous medium with
+// albedo `color`, density `intensity` and the Henyey-Greenstein phase function.
+struct Material {
+    color: vec3<f32>, // base color, emitted color of emissive materials
+    kind: u32,
+    metallic: f32,
+    roughness: f32,
+    refraction_index: f32, // of the coat and the glass lobe, 1 disables the coat
+    intensity: f32, // of emissive materials, density of volumes
+    color_texture: i32, // indices into texture_sources, -1 without texture
+    roughness_texture: i32, // roughness in green
+    normal_texture: i32,
+    transmission: f32,
+    eta: vec3<f32>, // complex index of refraction of the metal, zero for Schlick's approximation
+    metallic_texture: i32, // metallic in blue
+    k: vec3<f32>,
+    anisotropy: f32, // of volumes
+}
+
+const LIGHT_POINT = 0u;
+const LIGHT_SPOT = 1u;
+const LIGHT_DIRECTIONAL = 2u;
+const LIGHT_SPHERE = 3u;
+const LIGHT_TRIANGLE = 4u;
+const LIGHT_ENVIRONMENT = 5u;
+const LIGHT_PLANAR = 6u;
+
+// Area lights reference their emissive sphere, triangle, quad or disk through `index`, the remaining
+// fields are only used by point, spot and directional lights
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>, // direction the light travels in
+    index: u32,
+    emission: vec3<f32>,
+    cos_inner: f32,
+    cos_outer: f32,
+}
 
 struct HitResult {
     hit: bool,
     normal: vec3<f32>,
     collision: vec3<f32>,
-    material: vec2<u32>,
+    material: u32,
     front_face: bool,
+    uv: vec2<f32>,
+    // xyz points along increasing u, w is the handedness of the bitangent; zero when the
+    // surface has no usable uv parametrization
+    tangent: vec4<f32>,
+    uv_scale: f32, // uv distance per world distance along the surface, for picking mip levels
+    primitive: vec2<u32>, // vec2(primitive type, primitive index)
 }
 
+const NO_HIT = HitResult(false, vec3(0.0), vec3(0.0), 0u, false, vec2(0.0), vec4(0.0), 0.0, vec2(0));
+
 struct Ray {
     origin: vec3<f32>,
     dir: vec3<f32>,
@@ -184,169 +423,1319 @@ fn sky_color(dir: vec3<f32>) -> vec3<f32> {
     return (1.0 - a) * vec3(1.0, 1.0, 1.0) + a * vec3(0.5, 0.7, 1.0);
 }
 
-fn hit_sphere(ray: Ray) -> HitResult {
-    var closest_t = 9999.9;
-    var closest_i = 0;
-    for (var i = 0; i < SPHERES_COUNT; i++) {
-        let sphere: Sphere = SPHERES[i];
+const T_MIN = 0.001;
+const T_MAX = 9999.9;
+
+// Returns the closest hit distance in (T_MIN, max_t), or max_t on a miss
+fn hit_sphere(sphere: Sphere, ray: Ray, max_t: f32) -> f32 {
+    let oc = ray.origin - sphere.center;
+    let a = dot(ray.dir, ray.dir);
+    let h = dot(oc, ray.dir);
+    let c = dot(oc, oc) - sphere.radius * sphere.radius;
+
+    let discriminant = h * h - a * c;
+
+    if discriminant >= 0.0 {
+        let sqrtd = sqrt(discriminant);
+        var t = (-h - sqrtd) / a;
+
+        if t < T_MIN || t > max_t {
+            t = (-h + sqrtd) / a;
+        }
+
+        if t >= T_MIN && t < max_t {
+            return t;
+        }
+    }
+    return max_t;
+}
+
+// Returns the distance at which the ray enters the box, or T_MAX when it misses it or enters
+// beyond max_t
+fn hit_aabb(ray: Ray, inv_dir: vec3<f32>, box_min: vec3<f32>, box_max: vec3<f32>, max_t: f32) -> f32 {
+    let t0 = (box_min - ray.origin) * inv_dir;
+    let t1 = (box_max - ray.origin) * inv_dir;
+    let t_small = min(t0, t1);
+    let t_big = max(t0, t1);
+    let t_near = max(max(t_small.x, t_small.y), t_small.z);
+    let t_far = min(min(t_big.x, t_big.y), t_big.z);
+
+    if t_near <= t_far && t_far > 0.0 && t_near < max_t {
+        return t_near;
+    }
+    return T_MAX;
+}
+
+// Möller–Trumbore intersection, returns vec3(t, b1, b2) with the barycentric coordinates of
+// the second and third vertex, t is max_t on a miss
+fn hit_triangle(triangle: Triangle, ray: Ray, max_t: f32) -> vec3<f32> {
+    let p0 = vertices[triangle.v0].position;
+    let edge1 = vertices[triangle.v1].position - p0;
+    let edge2 = vertices[triangle.v2].position - p0;
+
+    let p = cross(ray.dir, edge2);
+    let determinant = dot(edge1, p);
+    if abs(determinant) < 1e-9 {
+        return vec3(max_t, 0.0, 0.0);
+    }
+    let inv_determinant = 1.0 / determinant;
+
+    let s = ray.origin - p0;
+    let b1 = dot(s, p) * inv_determinant;
+    if b1 < 0.0 || b1 > 1.0 {
+        return vec3(max_t, 0.0, 0.0);
+    }
+
+    let q = cross(s, edge1);
+    let b2 = dot(ray.dir, q) * inv_determinant;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return vec3(max_t, 0.0, 0.0);
+    }
+
+    let t = dot(edge2, q) * inv_determinant;
+    if t < T_MIN || t >= max_t {
+        return vec3(max_t, 0.0, 0.0);
+    }
+    return vec3(t, b1, b2);
+}
+
+// Returns vec3(t, a, b) with the hit point at origin + a * edge_u + b * edge_v, t is max_t on
+// a miss
+fn hit_planar(planar: Planar, ray: Ray, max_t: f32) -> vec3<f32> {
+    let normal = cross(planar.edge_u, planar.edge_v);
+    let denominator = dot(normal, ray.dir);
+    if abs(denominator) < 1e-9 {
+        return vec3(max_t, 0.0, 0.0);
+    }
+    let t = dot(normal, planar.origin - ray.origin) / denominator;
+    if t < T_MIN || t >= max_t {
+        return vec3(max_t, 0.0, 0.0);
+    }
+
+    let offset = ray_at(ray, t) - planar.origin;
+    let w = normal / dot(normal, normal);
+    let a = dot(w, cross(offset, planar.edge_v));
+    let b = dot(w, cross(planar.edge_u, offset));
+    var inside = true;
+    if planar.kind == PLANAR_QUAD {
+        inside = a >= 0.0 && a <= 1.0 && b >= 0.0 && b <= 1.0;
+    } else if planar.kind == PLANAR_DISK {
+        inside = a * a + b * b <= 1.0;
+    }
+    if !inside {
+        return vec3(max_t, 0.0, 0.0);
+    }
+    return vec3(t, a, b);
+}
+
+fn sphere_hit_result(sphere: Sphere, ray: Ray, t: f32) -> HitResult {
+    let collision = ray_at(ray, t);
 
-        let oc = ray.origin - sphere.center;
-        let a = dot(ray.dir, ray.dir);
-        let h = dot(oc, ray.dir);
-        let c = dot(oc, oc) - sphere.radius * sphere.radius;
+    var normal = (collision - sphere.center) / sphere.radius;
+    let uv = sphere_uv(normal);
+    let front_face = dot(ray.dir, normal) < 0.0;
+    if !front_face {
+        normal *= -1.0;
+    }
 
-        let discriminant = h * h - a * c;
+    // Geometric mean of the scales along u, which wraps around the equator, and along v
+    let uv_scale = 1.0 / (PI * sqrt(2.0) * sphere.radius);
+    return HitResult(true, normal, collision, sphere.material, front_face, uv, vec4(0.0), uv_scale, vec2(0));
+}
 
-        if discriminant >= 0.0 {
-            let sqrtd = sqrt(discriminant);
-            var t = (-h - sqrtd) / a;
+// Longitude and latitude of a point on the unit sphere
+fn sphere_uv(normal: vec3<f32>) -> vec2<f32> {
+    return vec2(
+        atan2(-normal.z, normal.x) / (2.0 * PI) + 0.5,
+        acos(clamp(-normal.y, -1.0, 1.0)) / PI,
+    );
+}
+
+fn planar_hit_result(planar: Planar, ray: Ray, t: f32, coordinates: vec2<f32>) -> HitResult {
+    var normal = normalize(cross(planar.edge_u, planar.edge_v));
+    let front_face = dot(ray.dir, normal) < 0.0;
+    if !front_face {
+        normal *= -1.0;
+    }
 
-            if t < 0.001 || t > closest_t {
-                t = (-h + sqrtd) / a;
+    let uv = planar_uv(planar, coordinates);
+    var uv_scale = inverseSqrt(length(cross(planar.edge_u, planar.edge_v)));
+    if planar.kind == PLANAR_DISK {
+        uv_scale *= 0.5;
+    }
+    let tangent = normalize(planar.edge_u);
+    let handedness = select(1.0, -1.0, dot(cross(normal, tangent), planar.edge_v) < 0.0);
+    return HitResult(true, normal, ray_at(ray, t), planar.material, front_face, uv, vec4(tangent, handedness), uv_scale, vec2(0));
+}
+
+// Disks map their bounding square of edge coordinates [-1, 1] to the unit uv square
+fn planar_uv(planar: Planar, coordinates: vec2<f32>) -> vec2<f32> {
+    if planar.kind == PLANAR_DISK {
+        return 0.5 * coordinates + 0.5;
+    }
+    return coordinates;
+}
+
+fn triangle_hit_result(triangle: Triangle, ray: Ray, t: f32, barycentric: vec2<f32>) -> HitResult {
+    let a = vertices[triangle.v0];
+    let b = vertices[triangle.v1];
+    let c = vertices[triangle.v2];
+    let weights = vec3(1.0 - barycentric.x - barycentric.y, barycentric.x, barycentric.y);
+
+    let geometric_normal = normalize(cross(b.position - a.position, c.position - a.position));
+    var normal = weights.x * a.normal + weights.y * b.normal + weights.z * c.normal;
+    if dot(normal, normal) < 1e-12 {
+        normal = geometric_normal;
+    }
+    normal = normalize(normal);
+
+    let front_face = dot(ray.dir, geometric_normal) < 0.0;
+    if dot(normal, ray.dir) > 0.0 {
+        normal *= -1.0;
+    }
+
+    let uv = weights.x * vec2(a.u, a.v) + weights.y * vec2(b.u, b.v) + weights.z * vec2(c.u, c.v);
+    let tangent = triangle_tangent(a, b, c, normal);
+    let uv_area = abs(cross(vec3(b.u - a.u, b.v - a.v, 0.0), vec3(c.u - a.u, c.v - a.v, 0.0)).z);
+    let uv_scale = sqrt(uv_area / length(cross(b.position - a.position, c.position - a.position)));
+    return HitResult(true, normal, ray_at(ray, t), triangle.material, front_face, uv, tangent, uv_scale, vec2(0));
+}
+
+// Tangent from the uv derivatives of the triangle, orthogonalized against the shading normal
+fn triangle_tangent(a: Vertex, b: Vertex, c: Vertex, normal: vec3<f32>) -> vec4<f32> {
+    let edge1 = b.position - a.position;
+    let edge2 = c.position - a.position;
+    let duv1 = vec2(b.u - a.u, b.v - a.v);
+    let duv2 = vec2(c.u - a.u, c.v - a.v);
+
+    let determinant = duv1.x * duv2.y - duv1.y * duv2.x;
+    if abs(determinant) < 1e-12 {
+        return vec4(0.0);
+    }
+    let tangent = (edge1 * duv2.y - edge2 * duv1.y) / determinant;
+    let bitangent = (edge2 * duv1.x - edge1 * duv2.x) / determinant;
+
+    let orthogonal = tangent - normal * dot(normal, tangent);
+    if dot(orthogonal, orthogonal) < 1e-12 {
+        return vec4(0.0);
+    }
+    let handedness = select(1.0, -1.0, dot(cross(normal, orthogonal), bitangent) < 0.0);
+    return vec4(normalize(orthogonal), handedness);
+}
+
+// Closest hit in (T_MIN, max_t)
+fn hit_scene(ray: Ray, max_t: f32) -> HitResult {
+    // Avoid infinities for axis aligned rays
+    let safe_dir = select(ray.dir, vec3(1e-8), abs(ray.dir) < vec3(1e-8));
+    let inv_dir = 1.0 / safe_dir;
+
+    var closest_t = max_t;
+    var closest_primitive = vec2(0u);
+    var closest_barycentric = vec2(0.0); // or the coordinates along the edges of a planar
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 0u;
+    var node_index = 0u;
+
+    let root = bvh_nodes[0];
+    if hit_aabb(ray, inv_dir, root.min, root.max, closest_t) >= T_MAX {
+        return NO_HIT;
+    }
+
+    loop {
+        let node = bvh_nodes[node_index];
+        if node.count > 0u {
+            for (var i = 0u; i < node.count; i++) {
+                let primitive = primitives[node.left_or_first + i];
+                if primitive.x == PRIMITIVE_SPHERE {
+                    let t = hit_sphere(spheres[primitive.y], ray, closest_t);
+                    if t < closest_t {
+                        closest_t = t;
+                        closest_primitive = primitive;
+                    }
+                } else if primitive.x == PRIMITIVE_TRIANGLE {
+                    let hit = hit_triangle(triangles[primitive.y], ray, closest_t);
+                    if hit.x < closest_t {
+                        closest_t = hit.x;
+                        closest_primitive = primitive;
+                        closest_barycentric = hit.yz;
+                    }
+                } else {
+                    let hit = hit_planar(planars[primitive.y], ray, closest_t);
+                    if hit.x < closest_t {
+                        closest_t = hit.x;
+                        closest_primitive = primitive;
+                        closest_barycentric = hit.yz;
+                    }
+                }
+            }
+        } else {
+            // Descend into the nearer child first and keep the other one for later
+            let left = bvh_nodes[node.left_or_first];
+            let right = bvh_nodes[node.left_or_first + 1u];
+            let t_left = hit_aabb(ray, inv_dir, left.min, left.max, closest_t);
+            let t_right = hit_aabb(ray, inv_dir, right.min, right.max, closest_t);
+
+            var near_index = node.left_or_first;
+            var far_index = node.left_or_first + 1u;
+            var t_near = t_left;
+            var t_far = t_right;
+            if t_right < t_left {
+                near_index = node.left_or_first + 1u;
+                far_index = node.left_or_first;
+                t_near = t_right;
+                t_far = t_left;
             }
 
-            if t >= 0.001 && t < closest_t {
-                closest_t = t;
-                closest_i = i;
+            if t_near < T_MAX {
+                if t_far < T_MAX && stack_size < BVH_STACK_SIZE {
+                    stack[stack_size] = far_index;
+                    stack_size++;
+                }
+                node_index = near_index;
+                continue;
             }
         }
+
+        if stack_size == 0u {
+            break;
+        }
+        stack_size--;
+        node_index = stack[stack_size];
+    }
+
+    if closest_t >= max_t {
+        return NO_HIT;
+    }
+    var result: HitResult;
+    if closest_primitive.x == PRIMITIVE_SPHERE {
+        result = sphere_hit_result(spheres[closest_primitive.y], ray, closest_t);
+    } else if closest_primitive.x == PRIMITIVE_TRIANGLE {
+        result = triangle_hit_result(triangles[closest_primitive.y], ray, closest_t, closest_barycentric);
+    } else {
+        result = planar_hit_result(planars[closest_primitive.y], ray, closest_t, closest_barycentric);
+    }
+    result.primitive = closest_primitive;
+    return result;
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return max(dot(color, vec3(0.2126, 0.7152, 0.0722)), 0.0);
+}
+
+// Texel of the equirectangular environment map seen in direction `dir`
+fn environment_texel(dir: vec3<f32>) -> vec2<u32> {
+    let size = textureDimensions(environment_texture);
+    let phi = atan2(dir.z, dir.x) + environment.rotation;
+    let u = fract(phi / (2.0 * PI));
+    let v = acos(clamp(dir.y, -1.0, 1.0)) / PI;
+    return min(vec2<u32>(vec2(u, v) * vec2<f32>(size)), size - 1u);
+}
+
+// Light arriving from infinitely far away in direction `dir`
+fn environment_color(dir: vec3<f32>) -> vec3<f32> {
+    let normalized = normalize(dir);
+    if environment.enabled == 0u {
+        return sky_color(normalized);
+    }
+    return textureLoad(environment_texture, environment_texel(normalized), 0).rgb * environment.intensity;
+}
+
+// Solid angle density with which `sample_environment` picks `dir`
+fn environment_pdf(dir: vec3<f32>) -> f32 {
+    let normalized = normalize(dir);
+    let sin_theta = sqrt(max(0.0, 1.0 - normalized.y * normalized.y));
+    if sin_theta <= 0.0 {
+        return 0.0;
+    }
+
+    let size = textureDimensions(environment_texture);
+    let texel = environment_texel(normalized);
+    let texel_sin_theta = sin(PI * (f32(texel.y) + 0.5) / f32(size.y));
+    let weight = luminance(textureLoad(environment_texture, texel, 0).rgb) * texel_sin_theta;
+    // Texels are picked with probability weight / weight_sum and cover 2 PI^2 sin(theta) / texel
+    // count of solid angle
+    return weight / environment.weight_sum * f32(size.x * size.y) / (2.0 * PI * PI * sin_theta);
+}
+
+// First of the `count` CDF values starting at `start` that is at least `value`
+fn search_cdf(start: u32, count: u32, value: f32) -> u32 {
+    var low = 0u;
+    var high = count - 1u;
+    while low < high {
+        let middle = (low + high) / 2u;
+        if environment_cdf[start + middle] < value {
+            low = middle + 1u;
+        } else {
+            high = middle;
+        }
+    }
+    return low;
+}
+
+// Light arriving at a point from one sampled point of a light
+struct LightSample {
+    dir: vec3<f32>,
+    distance: f32, // T_MAX for directional lights
+    radiance: vec3<f32>,
+    pdf: f32, // solid angle density for area lights, 0 for point, spot and directional lights
+}
+
+const NO_LIGHT = LightSample(vec3(0.0), 0.0, vec3(0.0), 0.0);
+
+// Orthonormal basis with `w` as third axis
+fn basis(w: vec3<f32>) -> mat3x3<f32> {
+    let a = select(vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), abs(w.x) > 0.9);
+    let v = normalize(cross(w, a));
+    let u = cross(v, w);
+    return mat3x3(u, v, w);
+}
+
+// 1 - cos of the half angle of the cone a sphere subtends, computed without cancellation for
+// small or distant spheres; 0 when the point is inside the sphere
+fn sphere_cone(sphere: Sphere, position: vec3<f32>) -> f32 {
+    let to_center = sphere.center - position;
+    let sin2 = sphere.radius * sphere.radius / dot(to_center, to_center);
+    if sin2 >= 1.0 {
+        return 0.0;
+    }
+    return sin2 / (1.0 + sqrt(1.0 - sin2));
+}
+
+fn triangle_area(triangle: Triangle) -> f32 {
+    let p0 = vertices[triangle.v0].position;
+    return 0.5 * length(cross(vertices[triangle.v1].position - p0, vertices[triangle.v2].position - p0));
+}
+
+fn emission(index: u32, uv: vec2<f32>, position: vec3<f32>, lod: f32) -> vec3<f32> {
+    let material = materials[index];
+    var color = material.color;
+    if material.color_texture >= 0 {
+        color *= sample_color(material.color_texture, uv, position, lod);
     }
+    return color * material.intensity;
+}
+
+// Picks a texel by its luminance and a direction within it
+fn sample_environment(rng: ptr<function, Sampler>) -> LightSample {
+    let size = textureDimensions(environment_texture);
+    let texel = next_random_2d(rng);
+    let y = search_cdf(size.x * size.y, size.y, texel.y);
+    let x = search_cdf(y * size.x, size.x, texel.x);
+
+    let jitter = next_random_2d(rng);
+    let u = (f32(x) + jitter.x) / f32(size.x);
+    let v = (f32(y) + jitter.y) / f32(size.y);
+    let phi = 2.0 * PI * u - environment.rotation;
+    let theta = PI * v;
+    let dir = vec3(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+
+    let pdf = environment_pdf(dir);
+    if pdf <= 0.0 {
+        return NO_LIGHT;
+    }
+    return LightSample(dir, T_MAX, environment_color(dir), pdf);
+}
 
-    if closest_t < 9999.9 {
-        let sphere = SPHERES[closest_i];
-        let collision = ray_at(ray, closest_t);
+fn sample_light(light: Light, position: vec3<f32>, rng: ptr<function, Sampler>) -> LightSample {
+    if light.kind == LIGHT_ENVIRONMENT {
+        return sample_environment(rng);
+    }
 
-        var normal = (collision - sphere.center) / sphere.radius;
-        let front_face = dot(ray.dir, normal) < 0.0;
-        if !front_face {
-            normal *= -1.0;
+    if light.kind == LIGHT_DIRECTIONAL {
+        return LightSample(-light.direction, T_MAX, light.emission, 0.0);
+    }
+
+    if light.kind == LIGHT_POINT || light.kind == LIGHT_SPOT {
+        let to_light = light.position - position;
+        let distance2 = dot(to_light, to_light);
+        let dir = to_light / sqrt(distance2);
+        var radiance = light.emission / distance2;
+        if light.kind == LIGHT_SPOT {
+            radiance *= smoothstep(light.cos_outer, light.cos_inner, dot(-dir, light.direction));
+        }
+        return LightSample(dir, sqrt(distance2), radiance, 0.0);
+    }
+
+    if light.kind == LIGHT_SPHERE {
+        // Uniformly sample the cone of directions the sphere covers
+        let sphere = spheres[light.index];
+        let cone = sphere_cone(sphere, position);
+        if cone <= 0.0 {
+            return NO_LIGHT;
+        }
+        let random = next_random_2d(rng);
+        let cos_theta = 1.0 - random.x * cone;
+        let sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * PI * random.y;
+        let axis = basis(normalize(sphere.center - position));
+        let dir = axis * vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+
+        var distance = hit_sphere(sphere, Ray(position, dir), T_MAX);
+        if distance >= T_MAX {
+            // Grazing direction lost to rounding
+            distance = length(sphere.center - position);
         }
+        let point = position + distance * dir;
+        let uv = sphere_uv(normalize(point - sphere.center));
+        let radiance = emission(sphere.material, uv, point, 0.0);
+        return LightSample(dir, distance, radiance, 1.0 / (2.0 * PI * cone));
+    }
 
-        return HitResult(true, normal, collision, sphere.material, front_face);
+    if light.kind == LIGHT_PLANAR {
+        // Uniformly sample the quad's or disk's area
+        let planar = planars[light.index];
+        var coordinates = next_random_2d(rng);
+        if planar.kind == PLANAR_DISK {
+            let radius = sqrt(coordinates.x);
+            let phi = 2.0 * PI * coordinates.y;
+            coordinates = radius * vec2(cos(phi), sin(phi));
+        }
+        let point = planar.origin + coordinates.x * planar.edge_u + coordinates.y * planar.edge_v;
+        let light_normal = normalize(cross(planar.edge_u, planar.edge_v));
+        let radiance = emission(planar.material, planar_uv(planar, coordinates), point, 0.0);
+        return area_light_sample(position, point, light_normal, planar.area, radiance);
     }
 
-    return HitResult(false, vec3(0.0), vec3(0.0), vec2(0), false);
+    // Uniformly sample the triangle's area
+    let triangle = triangles[light.index];
+    let v0 = vertices[triangle.v0];
+    let v1 = vertices[triangle.v1];
+    let v2 = vertices[triangle.v2];
+    let random = next_random_2d(rng);
+    let su = sqrt(random.x);
+    let b1 = 1.0 - su;
+    let b2 = random.y * su;
+    let point = v0.position + b1 * (v1.position - v0.position) + b2 * (v2.position - v0.position);
+    let light_normal = normalize(cross(v1.position - v0.position, v2.position - v0.position));
+    let uv = (1.0 - b1 - b2) * vec2(v0.u, v0.v) + b1 * vec2(v1.u, v1.v) + b2 * vec2(v2.u, v2.v);
+    let radiance = emission(triangle.material, uv, point, 0.0);
+    return area_light_sample(position, point, light_normal, triangle_area(triangle), radiance);
 }
 
-fn reflectance(cos_theta: f32, refraction_index: f32) -> f32 {
-    let r0 = (1 - refraction_index) / (1 + refraction_index);
-    let r00 = r0 * r0;
-    return r00 + (1.0 - r00) * pow((1.0 - cos_theta), 5.0);
+// Light arriving at `position` from `point` on a one sided area light, with the density of
+// uniformly sampling the light's area converted to solid angle
+fn area_light_sample(
+    position: vec3<f32>,
+    point: vec3<f32>,
+    light_normal: vec3<f32>,
+    area: f32,
+    radiance: vec3<f32>,
+) -> LightSample {
+    let to_light = point - position;
+    let distance2 = dot(to_light, to_light);
+    let distance = sqrt(distance2);
+    let dir = to_light / distance;
+    let cos_light = dot(-dir, light_normal);
+    if cos_light <= 0.0 {
+        // Area lights only emit from their front side
+        return NO_LIGHT;
+    }
+    return LightSample(dir, distance, radiance, distance2 / (cos_light * area));
 }
 
-fn get_color(ray: Ray, workgroup_id: vec2<u32>, sample_id: u32) -> vec3<f32> {
-    var current_ray = ray;
-    let max_bounce = 10;
-    var bounce = 0;
-    var attenuation = vec3(1.0, 1.0, 1.0);
-    let gamma = 0.2;
-    var rng_state = workgroup_id.x * 3128u + workgroup_id.y * 9213u + sample_id * 984711u;
-    while bounce <= max_bounce {
-        let result = hit_sphere(current_ray);
+// Solid angle density of uniformly sampling an area light seen along `to_light`
+fn area_light_pdf(to_light: vec3<f32>, light_normal: vec3<f32>, area: f32) -> f32 {
+    let cos_light = abs(dot(normalize(to_light), light_normal));
+    return dot(to_light, to_light) / (cos_light * area);
+}
+
+// Solid angle density with which light sampling from `origin` would have picked the emitter
+// that `result` hit, including the choice of the light
+fn light_pdf(result: HitResult, origin: vec3<f32>) -> f32 {
+    let light_count = f32(arrayLength(&lights));
+    if result.primitive.x == PRIMITIVE_SPHERE {
+        let cone = sphere_cone(spheres[result.primitive.y], origin);
+        if cone <= 0.0 {
+            return 0.0;
+        }
+        return 1.0 / (2.0 * PI * cone * light_count);
+    }
+
+    if result.primitive.x == PRIMITIVE_PLANAR {
+        let planar = planars[result.primitive.y];
+        if planar.kind == PLANAR_PLANE {
+            // Planes are never sampled as lights
+            return 0.0;
+        }
+        let light_normal = normalize(cross(planar.edge_u, planar.edge_v));
+        return area_light_pdf(result.collision - origin, light_normal, planar.area) / light_count;
+    }
+
+    let triangle = triangles[result.primitive.y];
+    let p0 = vertices[triangle.v0].position;
+    let light_normal = normalize(cross(vertices[triangle.v1].position - p0, vertices[triangle.v2].position - p0));
+    return area_light_pdf(result.collision - origin, light_normal, triangle_area(triangle)) / light_count;
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf2 = pdf * pdf;
+    return pdf2 / (pdf2 + other_pdf * other_pdf);
+}
+
+// Light sample of a uniformly picked light
+fn sample_lights(position: vec3<f32>, rng: ptr<function, Sampler>) -> LightSample {
+    let light_count = arrayLength(&lights);
+    let index = min(u32(next_random(rng) * f32(light_count)), light_count - 1u);
+    return sample_light(lights[index], position, rng);
+}
+
+// Contribution of a light sample from `sample_lights` that reaches the scattering point with
+// the fraction `visibility` and is scattered by vec4(value, density) of the BSDF or phase
+// function, weighted against finding the light by sampling the BSDF or phase function
+fn light_contribution(sample: LightSample, visibility: f32, scattering: vec4<f32>) -> vec3<f32> {
+    let light_count = f32(arrayLength(&lights));
+    let contribution = scattering.xyz * sample.radiance * visibility;
+    if sample.pdf == 0.0 {
+        // Point, spot and directional lights can't be hit, light sampling is all there is
+        return contribution * light_count;
+    }
+    let light_pdf = sample.pdf / light_count;
+    return contribution / light_pdf * power_heuristic(light_pdf, scattering.w);
+}
+
+// Next event estimation: samples one light uniformly at random and returns its contribution
+// through the BSDF, weighted against sampling the BSDF by multiple importance sampling. `wo`
+// points back along the incoming ray in the shading frame of the surface, `normal` is the
+// geometric normal on the side of `wo`. The surface lies in `medium`.
+fn direct_light(
+    collision: vec3<f32>,
+    normal: vec3<f32>,
+    surface: Surface,
+    wo: vec3<f32>,
+    medium: i32,
+    rng: ptr<function, Sampler>,
+) -> vec3<f32> {
+    let sample = sample_lights(collision, rng);
+    if all(sample.radiance == vec3(0.0)) {
+        return vec3(0.0);
+    }
+
+    // Light from below can only arrive through the glass lobe, and only when the shading and
+    // the geometric normal agree about the side
+    let wi = sample.dir * basis(surface.normal);
+    let side = dot(sample.dir, normal);
+    if side * wi.z <= 0.0 {
+        return vec3(0.0);
+    }
+    let bsdf = eval_bsdf(surface, wo, wi);
+    if all(bsdf.xyz == vec3(0.0)) {
+        return vec3(0.0);
+    }
+    let origin = collision + 0.001 * sign(side) * normal;
+    let visibility = transmittance(Ray(origin, sample.dir), sample.distance * 0.999, medium);
+    if visibility == 0.0 {
+        return vec3(0.0);
+    }
+    return light_contribution(sample, visibility, bsdf);
+}
+
+// Light scattered at `position` inside `medium`, or the fog, towards where the ray travelling
+// along `dir` came from
+fn medium_direct_light(
+    position: vec3<f32>,
+    dir: vec3<f32>,
+    anisotropy: f32,
+    medium: i32,
+    rng: ptr<function, Sampler>,
+) -> vec3<f32> {
+    let sample = sample_lights(position, rng);
+    if all(sample.radiance == vec3(0.0)) {
+        return vec3(0.0);
+    }
+    let visibility = transmittance(Ray(position, sample.dir), sample.distance * 0.999, medium);
+    if visibility == 0.0 {
+        return vec3(0.0);
+    }
+    let phase = henyey_greenstein(dot(dir, sample.dir), anisotropy);
+    return light_contribution(sample, visibility, vec4(vec3(phase), phase));
+}
+
+// Index of the volume material a ray travels through, the fog applies everywhere
+const NO_MEDIUM = -1;
+
+// Volume boundaries a ray passes through before a path or shadow ray gives up
+const MAX_CROSSINGS = 32u;
+
+// Henyey-Greenstein phase function of the angle between the directions of travel before and
+// after scattering, positive `g` scatters forward. Also the density of
+// `sample_henyey_greenstein`.
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * PI * denominator * sqrt(denominator));
+}
+
+fn sample_henyey_greenstein(dir: vec3<f32>, g: f32, random: vec2<f32>) -> vec3<f32> {
+    var cos_theta = 1.0 - 2.0 * random.x;
+    if abs(g) > 1e-3 {
+        let square = (1.0 - g * g) / (1.0 - g + 2.0 * g * random.x);
+        cos_theta = (1.0 + g * g - square * square) / (2.0 * g);
+    }
+    let sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+    let phi = 2.0 * PI * random.y;
+    return basis(dir) * vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+}
+
+// Distances along the ray where it enters and leaves the fog within `max_t`, equal when it
+// misses the fog
+fn fog_interval(ray: Ray, max_t: f32) -> vec2<f32> {
+    if fog.density <= 0.0 {
+        return vec2(0.0);
+    }
+    if abs(ray.dir.y) < 1e-8 {
+        return select(vec2(0.0), vec2(0.0, max_t), ray.origin.y < fog.height);
+    }
+    let crossing = (fog.height - ray.origin.y) / ray.dir.y;
+    if ray.dir.y > 0.0 {
+        return vec2(0.0, clamp(crossing, 0.0, max_t));
+    }
+    return vec2(clamp(crossing, 0.0, max_t), max_t);
+}
+
+// Collision of a ray with the fog or a volume, where it scatters
+struct MediumEvent {
+    distance: f32, // T_MAX when the ray reaches the next surface
+    albedo: vec3<f32>,
+    anisotropy: f32,
+}
+
+// Free flight sampling by delta tracking through the fog and `medium` up to the next surface
+// at `max_t`. Both have constant densities, so the majorant equals the density, there are no
+// null collisions and tracking reduces to sampling the exponential distance to the first
+// collision with either one.
+fn sample_medium(ray: Ray, max_t: f32, medium: i32, rng: ptr<function, Sampler>) -> MediumEvent {
+    // Both random numbers are drawn every time, so the dimensions stay the same
+    let fog_random = next_random(rng);
+    let volume_random = next_random(rng);
+
+    var event = MediumEvent(T_MAX, vec3(0.0), 0.0);
+    let interval = fog_interval(ray, max_t);
+    if interval.y > interval.x {
+        let t = interval.x - log(1.0 - fog_random) / fog.density;
+        if t < interval.y {
+            event = MediumEvent(t, fog.albedo, fog.anisotropy);
+        }
+    }
+    if medium != NO_MEDIUM {
+        let material = materials[medium];
+        let t = -log(1.0 - volume_random) / material.intensity;
+        if t < min(max_t, event.distance) {
+            event = MediumEvent(t, material.color, material.anisotropy);
+        }
+    }
+    return event;
+}
+
+// Fraction of the light travelling `max_t` along the ray that makes it through the fog and
+// volumes, 0 when a surface blocks it. For constant densities this closed form is what ratio
+// tracking estimates.
+fn transmittance(ray: Ray, max_t: f32, medium: i32) -> f32 {
+    let interval = fog_interval(ray, max_t);
+    var optical_depth = fog.density * (interval.y - interval.x);
+    var current = medium;
+    var origin = ray.origin;
+    var remaining = max_t;
+    for (var crossing = 0u; crossing < MAX_CROSSINGS; crossing++) {
+        let result = hit_scene(Ray(origin, ray.dir), remaining);
+        var segment = remaining;
         if result.hit {
-            if result.material.x == 0 {
-                // diffuse material
+            segment = distance(origin, result.collision);
+        }
+        if current != NO_MEDIUM {
+            optical_depth += materials[current].intensity * segment;
+        }
+        if !result.hit {
+            return exp(-optical_depth);
+        }
+        if materials[result.material].kind != MATERIAL_VOLUME {
+            return 0.0;
+        }
+        current = select(NO_MEDIUM, i32(result.material), result.front_face);
+        origin = result.collision;
+        remaining -= segment;
+    }
+    return 0.0;
+}
 
-                let material = DIFFUSE_MATERIALS[result.material.y];
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3(2.4));
+    return select(high, low, color <= vec3(0.04045));
+}
 
-                // let random_rng_state = invocation_id * vec2<f32>(f32(bounce) * 7.13, f32(bounce) * 4.18);
-                // let dir = normalize(result.normal + random_vec3(random_rng_state));
-                let dir = normalize(result.normal + random_unit_vec3(&rng_state));
+// Value of a texture at a hit with uv coordinates `uv` and world position `position`. Images
+// are looked up trilinearly, `lod` is the mip level with 0 the full resolution.
+fn sample_texture(index: i32, uv: vec2<f32>, position: vec3<f32>, lod: f32) -> vec4<f32> {
+    let source = texture_sources[index];
+    if source.kind == TEXTURE_IMAGE {
+        return textureSampleLevel(textures, texture_sampler, uv, source.layer, lod);
+    }
+    return vec4(procedural_texture(source, uv, position), 1.0);
+}
 
-                let epsilon = 0.001;
-                current_ray = Ray(result.collision + epsilon * result.normal, dir);
+// Linear color of a texture, images are sRGB encoded
+fn sample_color(index: i32, uv: vec2<f32>, position: vec3<f32>, lod: f32) -> vec3<f32> {
+    let color = sample_texture(index, uv, position, lod).rgb;
+    if texture_sources[index].kind == TEXTURE_IMAGE {
+        return srgb_to_linear(color);
+    }
+    return color;
+}
 
-                attenuation *= material.alpha;
-                bounce++;
-            } else if result.material.x == 1 {
-                // metallic material
-                let material = METALLIC_MATERIALS[result.material.y];
+fn procedural_texture(source: TextureSource, uv: vec2<f32>, position: vec3<f32>) -> vec3<f32> {
+    var point = position * source.scale;
+    if source.mapping == MAPPING_UV {
+        point = vec3(uv * source.scale, 0.0);
+    }
 
-                let reflected = reflect(current_ray.dir, result.normal);
-                let dir = normalize(reflected) + material.fuzz * random_unit_vec3(&rng_state);
+    var t: f32;
+    switch source.kind {
+        case TEXTURE_CHECKER: {
+            let cell = vec3<i32>(floor(point));
+            t = f32((cell.x + cell.y + cell.z) & 1);
+        }
+        case TEXTURE_NOISE: {
+            t = 0.5 + 0.5 * fractal_noise(point, source.octaves, false);
+        }
+        case TEXTURE_TURBULENCE: {
+            t = fractal_noise(point, source.octaves, true);
+        }
+        default: {
+            let turbulence = fractal_noise(point, source.octaves, true);
+            t = 0.5 + 0.5 * sin(point.x + source.distortion * turbulence);
+        }
+    }
+    return mix(source.color_a, source.color_b, clamp(t, 0.0, 1.0));
+}
 
-                // let epsilon = 0.001;
-                current_ray = Ray(result.collision, dir);
+// Dot product of `offset` with one of the 12 gradients of Perlin's improved noise, the
+// directions to the edge midpoints of a cube, picked by hashing the lattice point `cell`
+fn lattice_gradient(cell: vec3<i32>, offset: vec3<f32>) -> f32 {
+    let hash = pcg_hash(bitcast<u32>(cell.x) ^ pcg_hash(bitcast<u32>(cell.y) ^ pcg_hash(bitcast<u32>(cell.z))));
+    let h = hash % 12u;
+    var components = offset.yz;
+    if h < 4u {
+        components = offset.xy;
+    } else if h < 8u {
+        components = offset.xz;
+    }
+    let signs = select(vec2(1.0), vec2(-1.0), vec2((h & 1u) != 0u, (h & 2u) != 0u));
+    return dot(signs, components);
+}
 
-                attenuation *= material.alpha;
-                bounce++;
-            } else if result.material.x == 2 {
-                // dielectric material
-                let material = DIELECTRIC_MATERIALS[result.material.y];
+// Perlin's gradient noise, roughly in [-1, 1] and 0 on the integer lattice
+fn gradient_noise(point: vec3<f32>) -> f32 {
+    let cell = floor(point);
+    let offset = point - cell;
+    let fade = offset * offset * offset * (offset * (offset * 6.0 - 15.0) + 10.0);
+
+    var noise = 0.0;
+    for (var corner = 0u; corner < 8u; corner++) {
+        let delta = vec3(corner & 1u, (corner >> 1u) & 1u, (corner >> 2u) & 1u);
+        let weights = select(1.0 - fade, fade, delta == vec3(1u));
+        let gradient = lattice_gradient(vec3<i32>(cell) + vec3<i32>(delta), offset - vec3<f32>(delta));
+        noise += weights.x * weights.y * weights.z * gradient;
+    }
+    return noise;
+}
 
-                var refraction_index = 0.0;
-                if result.front_face {
-                    refraction_index = 1.0 / material.refraction_index;
-                } else {
-                    refraction_index = material.refraction_index;
-                }
+// fBm: `octaves` octaves of gradient noise of doubling frequency and halving amplitude,
+// normalized by the sum of the amplitudes. Turbulence sums their absolute values.
+fn fractal_noise(point: vec3<f32>, octaves: u32, turbulence: bool) -> f32 {
+    var sum = 0.0;
+    var amplitude = 1.0;
+    var total = 0.0;
+    var frequency = 1.0;
+    for (var octave = 0u; octave < octaves; octave++) {
+        let noise = gradient_noise(point * frequency);
+        sum += amplitude * select(noise, abs(noise), turbulence);
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    return sum / total;
+}
 
-                let current_dir = normalize(current_ray.dir);
+// Mip level at which a texel of the texture array covers the ray cone's footprint `width` on
+// the surface, which grows by 1 / cos for rays hitting it at a grazing angle
+fn texture_lod(result: HitResult, width: f32, cos_theta: f32) -> f32 {
+    let texels = f32(textureDimensions(textures).x) * result.uv_scale * width;
+    return log2(max(texels / max(abs(cos_theta), 0.01), 1e-8));
+}
 
-                let cos_theta = min(dot(-current_dir, result.normal), 1.0);
-                let sin_theta = sqrt(1.0 - pow(cos_theta, 2.0));
+// Principled BSDF at a hit: a metal lobe with weight `metallic`, and for the rest a glass lobe
+// with weight `transmission` or else a Lambertian base under a dielectric coat. The metal,
+// coat and glass lobes share one GGX microfacet distribution.
+struct Surface {
+    base_color: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    refraction_index: f32,
+    transmission: f32,
+    front_face: bool,
+    eta: vec3<f32>,
+    k: vec3<f32>,
+    normal: vec3<f32>, // shading normal on the side of the incoming ray
+}
 
-                var dir = vec3(0.0);
-                let random = next_random(& rng_state);
-                if refraction_index * sin_theta > 1.0 || reflectance(cos_theta, refraction_index) > random {
-                    dir = reflect(current_dir, result.normal);
-                } else {
-                    dir = refract(current_dir, result.normal, refraction_index);
-                }
+// Material with its textures applied at a hit, sampled at mip level `lod`
+fn material_surface(material: Material, result: HitResult, lod: f32) -> Surface {
+    var base_color = material.color;
+    var metallic = material.metallic;
+    var roughness = material.roughness;
+    let position = result.collision;
+    if material.color_texture >= 0 {
+        base_color *= sample_color(material.color_texture, result.uv, position, lod);
+    }
+    if material.roughness_texture >= 0 {
+        roughness *= sample_texture(material.roughness_texture, result.uv, position, lod).g;
+    }
+    if material.metallic_texture >= 0 {
+        metallic *= sample_texture(material.metallic_texture, result.uv, position, lod).b;
+    }
 
-                current_ray = Ray(result.collision, dir);
+    var normal = result.normal;
+    if material.normal_texture >= 0 && result.tangent.w != 0.0 {
+        let tangent = result.tangent.xyz;
+        let bitangent = cross(normal, tangent) * result.tangent.w;
+        let mapped = sample_texture(material.normal_texture, result.uv, position, lod).xyz * 2.0 - 1.0;
+        normal = normalize(tangent * mapped.x + bitangent * mapped.y + normal * mapped.z);
+    }
 
-                bounce++;
-            } else {
-                break;
-            }
-        } else {
-            attenuation *= sky_color(current_ray.dir);
-            break;
+    return Surface(
+        base_color,
+        metallic,
+        roughness,
+        material.refraction_index,
+        material.transmission,
+        result.front_face,
+        material.eta,
+        material.k,
+        normal,
+    );
+}
+
+// Below this roughness the GGX lobes are treated as perfectly smooth, narrower distributions
+// overflow f32
+const MIN_ROUGHNESS = 0.03;
+
+fn is_smooth(surface: Surface) -> bool {
+    return surface.roughness < MIN_ROUGHNESS;
+}
+
+// Index of refraction on the far side of the surface relative to the near side
+fn relative_eta(surface: Surface) -> f32 {
+    return select(1.0 / surface.refraction_index, surface.refraction_index, surface.front_face);
+}
+
+// The GGX functions work in the shading frame, where the normal is +z and all directions
+// point away from the surface
+
+fn ggx_d(h: vec3<f32>, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let d = h.z * h.z * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+fn ggx_lambda(w: vec3<f32>, alpha: f32) -> f32 {
+    let tan2 = (w.x * w.x + w.y * w.y) / (w.z * w.z);
+    return 0.5 * (sqrt(1.0 + alpha * alpha * tan2) - 1.0);
+}
+
+fn ggx_g1(w: vec3<f32>, alpha: f32) -> f32 {
+    return 1.0 / (1.0 + ggx_lambda(w, alpha));
+}
+
+// Height correlated masking-shadowing
+fn ggx_g2(wo: vec3<f32>, wi: vec3<f32>, alpha: f32) -> f32 {
+    return 1.0 / (1.0 + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha));
+}
+
+// Samples a microfacet normal from the normals visible from `wo` (Heitz 2018)
+fn sample_ggx_visible(wo: vec3<f32>, alpha: f32, random: vec2<f32>) -> vec3<f32> {
+    let v = normalize(vec3(alpha * wo.x, alpha * wo.y, wo.z));
+    let length2 = v.x * v.x + v.y * v.y;
+    let t1 = select(vec3(1.0, 0.0, 0.0), vec3(-v.y, v.x, 0.0) / sqrt(length2), length2 > 0.0);
+    let t2 = cross(v, t1);
+    let r = sqrt(random.x);
+    let phi = 2.0 * PI * random.y;
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + v.z);
+    let p2 = (1.0 - s) * sqrt(1.0 - p1 * p1) + s * r * sin(phi);
+    let n = p1 * t1 + p2 * t2 + sqrt(max(0.0, 1.0 - p1 * p1 - p2 * p2)) * v;
+    return normalize(vec3(alpha * n.x, alpha * n.y, max(0.0, n.z)));
+}
+
+// Density of `sample_ggx_visible` picking `h`
+fn ggx_visible_pdf(wo: vec3<f32>, h: vec3<f32>, alpha: f32) -> f32 {
+    return ggx_g1(wo, alpha) * max(dot(wo, h), 0.0) * ggx_d(h, alpha) / wo.z;
+}
+
+// Unpolarized Fresnel reflectance of a dielectric interface, `eta` is the index of refraction
+// of the far side relative to the near side
+fn fresnel_dielectric(cos_theta: f32, eta: f32) -> f32 {
+    let cos_i = clamp(cos_theta, 0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = sqrt(1.0 - sin2_t);
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    return 0.5 * (parallel * parallel + perpendicular * perpendicular);
+}
+
+// Fresnel reflectance of a conductor with the complex index of refraction `eta + i k`
+fn fresnel_conductor(cos_theta: f32, eta: vec3<f32>, k: vec3<f32>) -> vec3<f32> {
+    let cos2 = clamp(cos_theta * cos_theta, 0.0, 1.0);
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = sqrt(t0 * t0 + 4.0 * eta2 * k2);
+    let t1 = a2_plus_b2 + cos2;
+    let a = sqrt(max(vec3(0.0), 0.5 * (a2_plus_b2 + t0)));
+    let t2 = 2.0 * sqrt(cos2) * a;
+    let perpendicular = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+    return 0.5 * (parallel + perpendicular);
+}
+
+fn metal_fresnel(surface: Surface, cos_theta: f32) -> vec3<f32> {
+    if surface.eta.x > 0.0 {
+        return surface.base_color * fresnel_conductor(cos_theta, surface.eta, surface.k);
+    }
+    let f0 = surface.base_color;
+    return f0 + (1.0 - f0) * pow(1.0 - clamp(cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Probabilities of sampling the metal, glass, coat and diffuse lobe. The coat and the base
+// share the weight of the opaque dielectric by the coat's reflectance against the base's
+// albedo.
+fn lobe_probabilities(surface: Surface, wo: vec3<f32>) -> vec4<f32> {
+    let dielectric = 1.0 - surface.metallic;
+    let glass = dielectric * surface.transmission;
+    let opaque = dielectric - glass;
+    let coat = fresnel_dielectric(wo.z, surface.refraction_index);
+    let base = (1.0 - coat) * luminance(surface.base_color);
+    var coat_share = 0.0;
+    if coat > 0.0 {
+        coat_share = coat / (coat + base);
+    }
+    return vec4(surface.metallic, glass, opaque * coat_share, opaque * (1.0 - coat_share));
+}
+
+// BSDF times the cosine at `wi` in xyz and the density with which `sample_bsdf` picks `wi` in
+// w. Smooth lobes are left out, no direction but their own reaches them.
+fn eval_bsdf(surface: Surface, wo: vec3<f32>, wi: vec3<f32>) -> vec4<f32> {
+    let probabilities = lobe_probabilities(surface, wo);
+    let dielectric = 1.0 - surface.metallic;
+    let glass = dielectric * surface.transmission;
+    let opaque = dielectric - glass;
+    var value = vec3(0.0);
+    var pdf = 0.0;
+
+    if wi.z > 0.0 {
+        let coat = fresnel_dielectric(wo.z, surface.refraction_index);
+        value += opaque * (1.0 - coat) * surface.base_color / PI * wi.z;
+        pdf += probabilities.w * wi.z / PI;
+    }
+    if is_smooth(surface) || probabilities.x + probabilities.y + probabilities.z == 0.0 {
+        return vec4(value, pdf);
+    }
+
+    let alpha = surface.roughness * surface.roughness;
+    if wi.z > 0.0 {
+        let h = normalize(wo + wi);
+        let cos_h = dot(wo, h);
+        let d = ggx_d(h, alpha);
+        let specular = d * ggx_g2(wo, wi, alpha) / (4.0 * wo.z);
+        let reflection_pdf = ggx_g1(wo, alpha) * d / (4.0 * wo.z);
+        let glass_fresnel = fresnel_dielectric(cos_h, relative_eta(surface));
+        value += surface.metallic * metal_fresnel(surface, cos_h) * specular;
+        value += glass * glass_fresnel * specular;
+        value += opaque * fresnel_dielectric(cos_h, surface.refraction_index) * specular;
+        pdf += (probabilities.x + probabilities.y * glass_fresnel + probabilities.z) * reflection_pdf;
+    } else if glass > 0.0 {
+        // Refraction through the microfacet `h` halfway between `wo` and `wi` weighted by the
+        // indices of refraction (Walter et al. 2007). The radiance isn't scaled by the squared
+        // relative index, which cancels out for closed objects.
+        let eta = relative_eta(surface);
+        var h = normalize(wo + wi * eta);
+        if h.z < 0.0 {
+            h = -h;
         }
+        let cos_o = dot(wo, h);
+        let cos_i = dot(wi, h);
+        if cos_o <= 0.0 || cos_i >= 0.0 {
+            return vec4(value, pdf);
+        }
+        let transmitted = 1.0 - fresnel_dielectric(cos_o, eta);
+        let denominator = (cos_i + cos_o / eta) * (cos_i + cos_o / eta);
+        let d = ggx_d(h, alpha);
+        value += glass * surface.base_color * transmitted * d * ggx_g2(wo, wi, alpha)
+            * abs(cos_i * cos_o) / (wo.z * denominator);
+        pdf += probabilities.y * transmitted * ggx_visible_pdf(wo, h, alpha) * abs(cos_i) / denominator;
     }
-    if bounce == 0 {
-        attenuation *= sky_color(current_ray.dir);
+    return vec4(value, pdf);
+}
+
+// Scattered direction of a BSDF sample
+struct BsdfSample {
+    dir: vec3<f32>, // in the shading frame
+    weight: vec3<f32>, // BSDF times cosine over density
+    pdf: f32, // 0 for smooth lobes, -1 when the path is absorbed
+    kind: u32, // DIFFUSE_BOUNCE, SPECULAR_BOUNCE or TRANSMISSION_BOUNCE
+}
+
+const ABSORBED = BsdfSample(vec3(0.0), vec3(0.0), -1.0, DIFFUSE_BOUNCE);
+
+// Picks a lobe by `lobe_probabilities` and a direction from it. Rough lobes are weighted by
+// the whole BSDF over the density of all lobes together, smooth ones by themselves.
+fn sample_bsdf(surface: Surface, wo: vec3<f32>, rng: ptr<function, Sampler>) -> BsdfSample {
+    let probabilities = lobe_probabilities(surface, wo);
+    let lobe = next_random(rng);
+    let random = next_random_2d(rng);
+    let smooth_surface = is_smooth(surface);
+    let alpha = surface.roughness * surface.roughness;
+    var h = vec3(0.0, 0.0, 1.0);
+    if !smooth_surface {
+        h = sample_ggx_visible(wo, alpha, random);
     }
 
-    return attenuation;
+    var wi = vec3(0.0);
+    var kind = SPECULAR_BOUNCE;
+    var smooth_weight = vec3(0.0);
+    let metal_end = probabilities.x;
+    let glass_end = metal_end + probabilities.y;
+    let coat_end = glass_end + probabilities.z;
+    if lobe < metal_end {
+        wi = reflect(-wo, h);
+        smooth_weight = metal_fresnel(surface, wo.z);
+    } else if lobe < glass_end {
+        // The position within the glass lobe's share picks reflection or refraction
+        let eta = relative_eta(surface);
+        let fresnel = fresnel_dielectric(dot(wo, h), eta);
+        if (lobe - metal_end) / probabilities.y < fresnel {
+            wi = reflect(-wo, h);
+            smooth_weight = vec3(1.0);
+        } else {
+            wi = refract(-wo, h, 1.0 / eta);
+            kind = TRANSMISSION_BOUNCE;
+            smooth_weight = surface.base_color;
+        }
+    } else if lobe < coat_end {
+        wi = reflect(-wo, h);
+        let opaque = (1.0 - surface.metallic) * (1.0 - surface.transmission);
+        smooth_weight = vec3(opaque * fresnel_dielectric(wo.z, surface.refraction_index) / probabilities.z);
+    } else {
+        // Cosine weighted hemisphere
+        let r = sqrt(random.x);
+        let phi = 2.0 * PI * random.y;
+        wi = vec3(r * cos(phi), r * sin(phi), sqrt(max(0.0, 1.0 - random.x)));
+        kind = DIFFUSE_BOUNCE;
+    }
+
+    // Reflections below the surface and refractions above it are absorbed
+    if (kind == TRANSMISSION_BOUNCE) != (wi.z < 0.0) || wi.z == 0.0 {
+        return ABSORBED;
+    }
+    if smooth_surface && kind != DIFFUSE_BOUNCE {
+        return BsdfSample(wi, smooth_weight, 0.0, kind);
+    }
+    let bsdf = eval_bsdf(surface, wo, wi);
+    if bsdf.w <= 0.0 {
+        return ABSORBED;
+    }
+    return BsdfSample(wi, bsdf.xyz / bsdf.w, bsdf.w, kind);
 }
 
-const WORKGROUP_WIDTH = 6;
-const WORKGROUP_HEIGHT = 6;
-const WORKGROUP_DEPTH = 1;
+const DIFFUSE_BOUNCE = 0u;
+const SPECULAR_BOUNCE = 1u;
+const TRANSMISSION_BOUNCE = 2u;
 
-const SAMPLE_SIZE = WORKGROUP_WIDTH * WORKGROUP_HEIGHT * WORKGROUP_DEPTH; 
+// Counts a bounce of the given kind, false when the path already used up the limit for it
+fn count_bounce(bounces: ptr<function, vec3<u32>>, kind: u32) -> bool {
+    if (*bounces)[kind] >= util.bounce_limits[kind] {
+        return false;
+    }
+    (*bounces)[kind] += 1u;
+    return true;
+}
 
-// 36 samples
-var<workgroup> workgroupColors: array<vec3<f32>, SAMPLE_SIZE>; 
+// `spread` is the angle of the pixel's ray cone, whose width at a hit picks the texture mip level
+fn get_color(ray: Ray, spread: f32, rng: ptr<function, Sampler>) -> vec3<f32> {
+    var current_ray = ray;
+    var cone_spread = spread;
+    var cone_width = 0.0;
+    let max_bounce = util.max_bounces;
+    var bounce = 0u;
+    // Bounces so far per kind, indexed by DIFFUSE_BOUNCE, SPECULAR_BOUNCE and TRANSMISSION_BOUNCE
+    var bounces = vec3(0u);
+    var attenuation = vec3(1.0, 1.0, 1.0);
+    var radiance = vec3(0.0);
+    // Density with which the previous surface's BSDF or the medium's phase function picked
+    // `current_ray`, 0 for camera rays and smooth lobes, which light sampling can't reproduce
+    var bsdf_pdf = 0.0;
+    // Where the path last scattered, light densities are measured from there
+    var scatter_origin = ray.origin;
+    var medium = NO_MEDIUM;
+    var crossings = 0u;
+    var crossed = false;
+    while bounce <= max_bounce {
+        // Passing through a volume boundary continues the bounce
+        if !crossed {
+            start_bounce(rng, bounce);
+
+            // Russian roulette, see `BounceLimits` in settings.rs
+            if bounce >= util.russian_roulette_depth {
+                let survival = min(max(attenuation.x, max(attenuation.y, attenuation.z)), 0.95);
+                if next_random(rng) >= survival {
+                    break;
+                }
+                attenuation /= survival;
+            }
+        }
+        crossed = false;
 
-@compute @workgroup_size(WORKGROUP_WIDTH, WORKGROUP_HEIGHT, WORKGROUP_DEPTH)
-fn main(
-    @builtin(local_invocation_index) local_invocation_index: u32,
-    @builtin(workgroup_id) workgroup_id: vec3<u32>,
-) {
-    let texture_dimensions = textureDimensions(outputTex);
+        let result = hit_scene(current_ray, T_MAX);
+        var surface_distance = T_MAX;
+        if result.hit {
+            surface_distance = distance(current_ray.origin, result.collision);
+        }
+        let event = sample_medium(current_ray, surface_distance, medium, rng);
+        if event.distance < T_MAX {
+            // Scattering in the fog or a volume counts as a diffuse bounce
+            let position = ray_at(current_ray, event.distance);
+            attenuation *= event.albedo;
+            radiance += attenuation * medium_direct_light(position, current_ray.dir, event.anisotropy, medium, rng);
+            if !count_bounce(&bounces, DIFFUSE_BOUNCE) {
+                return radiance;
+            }
+            let dir = sample_henyey_greenstein(current_ray.dir, event.anisotropy, next_random_2d(rng));
+            bsdf_pdf = henyey_greenstein(dot(current_ray.dir, dir), event.anisotropy);
+            cone_width += cone_spread * event.distance;
+            cone_spread += 1.0;
+            current_ray = Ray(position, dir);
+            scatter_origin = position;
+            bounce++;
+            continue;
+        }
 
-    let aspect_ratio = f32(texture_dimensions.x) / f32(texture_dimensions.y);
+        if !result.hit {
+            // The environment map is also sampled as a light, the sky gradient isn't
+            var weight = 1.0;
+            if environment.enabled != 0u && bsdf_pdf > 0.0 {
+                let light_pdf = environment_pdf(current_ray.dir) / f32(arrayLength(&lights));
+                weight = power_heuristic(bsdf_pdf, light_pdf);
+            }
+            radiance += attenuation * environment_color(current_ray.dir) * weight;
+            break;
+        }
 
-    var rng_state = local_invocation_index * 19347u;
+        cone_width += cone_spread * surface_distance;
+        let lod = texture_lod(result, cone_width, dot(current_ray.dir, result.normal));
 
-    let random_sample = random_unit_vec3(& rng_state).xy * 0.5;
+        let material = materials[result.material];
+        if material.kind == MATERIAL_VOLUME {
+            // The ray enters or leaves the medium unchanged
+            crossings++;
+            if crossings > MAX_CROSSINGS {
+                return radiance;
+            }
+            medium = select(NO_MEDIUM, i32(result.material), result.front_face);
+            current_ray = Ray(result.collision, current_ray.dir);
+            crossed = true;
+            continue;
+        }
+        if material.kind == MATERIAL_EMISSIVE {
+            // The part of its light that next event estimation at the previous surface already
+            // picked up is weighted out
+            if result.front_face {
+                var weight = 1.0;
+                if bsdf_pdf > 0.0 {
+                    weight = power_heuristic(bsdf_pdf, light_pdf(result, scatter_origin));
+                }
+                radiance += attenuation * emission(result.material, result.uv, result.collision, lod) * weight;
+            }
+            return radiance;
+        }
 
-    let pixel_center = camera.first_pixel_pos + (f32(workgroup_id.x) + random_sample.x) * camera.pixel_delta_u + (f32(workgroup_id.y) + random_sample.y) * camera.pixel_delta_v;
+        var surface = material_surface(material, result, lod);
+        let out_dir = -normalize(current_ray.dir);
+        if dot(out_dir, surface.normal) <= 0.0 {
+            // A normal map turned the surface away from the ray
+            surface.normal = result.normal;
+        }
+        let frame = basis(surface.normal);
+        var wo = out_dir * frame;
+        wo.z = max(wo.z, 1e-6);
 
-    let ray_direction = normalize(pixel_center - camera.position);
-    workgroupColors[local_invocation_index] = get_color(Ray(camera.position, ray_direction), workgroup_id.xy, local_invocation_index);
+        radiance += attenuation * direct_light(result.collision, result.normal, surface, wo, medium, rng);
 
-    workgroupBarrier();
+        let scattered = sample_bsdf(surface, wo, rng);
+        if scattered.pdf < 0.0 {
+            return radiance;
+        }
+        let dir = frame * scattered.dir;
+        // Directions the shading normal allows but the geometry doesn't end the path
+        let side = dot(dir, result.normal);
+        if side * scattered.dir.z <= 0.0 || !count_bounce(&bounces, scattered.kind) {
+            return radiance;
+        }
 
-    if local_invocation_index == 0 {
-        var aggregated_color = vec3(0.0);
-        for (var i = 0; i < SAMPLE_SIZE; i++) {
-            aggregated_color += workgroupColors[i];
+        current_ray = Ray(result.collision + 0.001 * sign(side) * result.normal, dir);
+        scatter_origin = current_ray.origin;
+        bsdf_pdf = scattered.pdf;
+        attenuation *= scattered.weight;
+        // Diffuse bounces widen the cone to about a radian, glossy ones by their lobe's width
+        if scattered.kind == DIFFUSE_BOUNCE {
+            cone_spread += 1.0;
+        } else {
+            cone_spread += surface.roughness * surface.roughness;
         }
+        bounce++;
+    }
+
+    return radiance;
+}
+
+// Every invocation traces all samples of one pixel in a WORKGROUP_SIZE x WORKGROUP_SIZE tile
+const WORKGROUP_SIZE = 8;
 
-        textureStore(outputTex, vec2<i32>(workgroup_id.xy), vec4(aggregated_color / f32(SAMPLE_SIZE), 1.0));
+@compute @workgroup_size(WORKGROUP_SIZE, WORKGROUP_SIZE, 1)
+fn main(
+    @builtin(global_invocation_id) global_invocation_id: vec3<u32>,
+) {
+    let texture_dimensions = textureDimensions(outputTex);
+    let pixel = global_invocation_id.xy;
+    if pixel.x >= texture_dimensions.x || pixel.y >= texture_dimensions.y {
+        return;
     }
+
+    var color = vec3(0.0);
+    for (var sample_id = 0u; sample_id < util.samples_per_frame; sample_id++) {
+        var pixel_sampler = init_sampler(pixel, sample_id);
+
+        let random_sample = next_random_2d(&pixel_sampler) - 0.5;
+
+        let pixel_center = camera.first_pixel_pos + (f32(pixel.x) + random_sample.x) * camera.pixel_delta_u + (f32(pixel.y) + random_sample.y) * camera.pixel_delta_v;
+
+        let lens = random_in_unit_disk(&pixel_sampler);
+        let ray_origin = camera.position + lens.x * camera.defocus_disk_u + lens.y * camera.defocus_disk_v;
+        let ray_direction = normalize(pixel_center - ray_origin);
+        let spread = length(camera.pixel_delta_u) / distance(pixel_center, camera.position);
+        color += get_color(Ray(ray_origin, ray_direction), spread, &pixel_sampler);
+    }
+
+    let pixel_index = pixel.y * texture_dimensions.x + pixel.x;
+    var accumulated = vec4(color, f32(util.samples_per_frame));
+    if util.frame > 0u {
+        accumulated += accumulation[pixel_index];
+    }
+    accumulation[pixel_index] = accumulated;
+
+    textureStore(outputTex, vec2<i32>(pixel), vec4(accumulated.rgb / accumulated.w, 1.0));
 }
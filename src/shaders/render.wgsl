@@ -1,5 +1,17 @@
 @group(0) @binding(0) var render_texture: texture_2d<f32>;
 @group(0) @binding(1) var texture_sampler: sampler;
+@group(0) @binding(2) var<uniform> display: DisplaySettings;
+
+struct DisplaySettings {
+    exposure: f32,
+    tone_mapping: u32,
+    encode_srgb: u32,
+};
+
+const TONE_MAPPING_NONE: u32 = 0u;
+const TONE_MAPPING_REINHARD: u32 = 1u;
+const TONE_MAPPING_ACES: u32 = 2u;
+const TONE_MAPPING_AGX: u32 = 3u;
 
 struct VSOut {
     @builtin(position) pos: vec4<f32>,
@@ -26,7 +38,78 @@ fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VSOut {
     return out;
 }
 
+// Stephen Hill's fit of the ACES RRT and sRGB ODT, matrices are column major
+fn aces(color: vec3<f32>) -> vec3<f32> {
+    let input = mat3x3<f32>(
+        0.59719, 0.07600, 0.02840,
+        0.35458, 0.90834, 0.13383,
+        0.04823, 0.01566, 0.83777,
+    );
+    let output = mat3x3<f32>(
+        1.60475, -0.10208, -0.00327,
+        -0.53108, 1.10813, -0.07276,
+        -0.07367, -0.00605, 1.07602,
+    );
+    let v = input * color;
+    let fitted = (v * (v + 0.0245786) - 9.0537e-05) / (v * (0.983729 * v + 0.432951) + 0.238081);
+    return output * fitted;
+}
+
+// AgX with the default look, the contrast curve is a polynomial approximation
+fn agx(color: vec3<f32>) -> vec3<f32> {
+    let inset = mat3x3<f32>(
+        0.8424791, 0.04232824, 0.04237565,
+        0.0784336, 0.8784686, 0.0784336,
+        0.07922375, 0.07916613, 0.879143,
+    );
+    let outset = mat3x3<f32>(
+        1.196879, -0.05289685, -0.05297164,
+        -0.09802088, 1.151903, -0.09804345,
+        -0.09902974, -0.09896118, 1.151074,
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    let log_color = clamp(log2(max(inset * color, vec3(1e-10))), vec3(min_ev), vec3(max_ev));
+    let x = (log_color - min_ev) / (max_ev - min_ev);
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+    return pow(max(outset * curve, vec3(0.0)), vec3(2.2));
+}
+
+// Maps linear scene radiance to linear display values in [0, 1], mirrors `ToneMapping::apply`
+fn tone_map(color: vec3<f32>) -> vec3<f32> {
+    let c = max(color, vec3(0.0));
+    var mapped = c;
+    switch display.tone_mapping {
+        case TONE_MAPPING_REINHARD: {
+            mapped = c / (1.0 + c);
+        }
+        case TONE_MAPPING_ACES: {
+            mapped = aces(c);
+        }
+        case TONE_MAPPING_AGX: {
+            mapped = agx(c);
+        }
+        case TONE_MAPPING_NONE, default: {}
+    }
+    return clamp(mapped, vec3(0.0), vec3(1.0));
+}
+
+fn linear_to_srgb(linear: vec3<f32>) -> vec3<f32> {
+    let low = linear * 12.92;
+    let high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+    return select(high, low, linear <= vec3(0.0031308));
+}
+
 @fragment
 fn fs_main(in: VSOut) -> @location(0) vec4<f32> {
-    return textureSample(render_texture, texture_sampler, in.uv);
+    let radiance = textureSample(render_texture, texture_sampler, in.uv).rgb;
+    var color = tone_map(radiance * display.exposure);
+    // sRGB surfaces encode on write, others expect the encoded value
+    if display.encode_srgb == 1u {
+        color = linear_to_srgb(color);
+    }
+    return vec4(color, 1.0);
 }
@@ -0,0 +1,294 @@
+//! Golden image tests for the GPU and the CPU renderer.
+//!
+//! Every test renders one of the scenes in `assets/scenes` headlessly with a fixed seed and
+//! compares the displayed image against its reference in `tests/golden`. A software adapter
+//! such as llvmpipe is enough. Without any adapter the GPU tests fail, unless
+//! `GOLDEN_ALLOW_NO_ADAPTER` is set, which skips them. The references come from the GPU
+//! renderer, every scene is also rendered by the CPU renderer to check that it converges to the
+//! same picture.
+//!
+//! Different adapters round differently, so the noise of two renders only matches on the same
+//! machine. The images are therefore compared after averaging blocks of pixels, which removes
+//! most of the noise but keeps changes in brightness, color and shape. On failure the render
+//! and an amplified difference image are written to `target/tmp/golden`.
+//!
+//! After an intended change of the picture, rewrite the references with
+//! `UPDATE_GOLDEN=1 cargo test --test golden`, which skips the CPU tests.
+
+use std::path::{Path, PathBuf};
+
+use image::{Rgb, RgbImage, RgbaImage};
+use raytracer::core::{CpuRenderer, HeadlessRenderer, RenderSettings, Scene};
+
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 120;
+const SAMPLES: u32 = 72;
+const SEED: u32 = 1;
+/// Edge length of the pixel blocks averaged before comparing.
+const BLOCK_SIZE: u32 = 4;
+/// Largest root mean square error of the averaged 8 bit channels, in [0, 1].
+const TOLERANCE: f32 = 0.02;
+/// Scale of the differences in the diff image.
+const DIFF_SCALE: f32 = 4.0;
+
+fn load_scene(name: &str) -> Scene {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/scenes");
+    Scene::load(path.join(format!("{name}.toml"))).unwrap()
+}
+
+fn settings() -> RenderSettings {
+    RenderSettings {
+        width: WIDTH,
+        height: HEIGHT,
+        seed: SEED,
+        ..Default::default()
+    }
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.png"))
+}
+
+fn check(name: &str) {
+    let scene = load_scene(name);
+    let mut renderer = match pollster::block_on(HeadlessRenderer::new(&scene, &settings())) {
+        Ok(renderer) => renderer,
+        Err(error) if std::env::var_os("GOLDEN_ALLOW_NO_ADAPTER").is_some() => {
+            eprintln!("skipping golden image {name}: {error:#}");
+            return;
+        }
+        Err(error) => panic!(
+            "no GPU renderer for golden image {name}: {error:#}\n  set GOLDEN_ALLOW_NO_ADAPTER=1 to skip the GPU tests"
+        ),
+    };
+    let actual = renderer.render(SAMPLES).unwrap().to_rgba8(&scene.display);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        actual.save(reference_path(name)).unwrap();
+        return;
+    }
+    compare(name, name, &actual);
+}
+
+/// Renders the scene on the CPU and compares it against the GPU reference.
+fn check_cpu(name: &str) {
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        return;
+    }
+    let scene = load_scene(name);
+    let mut renderer = CpuRenderer::new(&scene, &settings());
+    let actual = renderer.render(SAMPLES).unwrap().to_rgba8(&scene.display);
+    compare(name, &format!("{name}_cpu"), &actual);
+}
+
+/// Compares a render of the scene `name` against its reference, failure images are written
+/// under `output_name`.
+fn compare(name: &str, output_name: &str, actual: &RgbaImage) {
+    let reference_path = reference_path(name);
+    let reference = image::open(&reference_path)
+        .unwrap_or_else(|error| {
+            panic!(
+                "missing reference {} ({error}), create it with UPDATE_GOLDEN=1",
+                reference_path.display()
+            )
+        })
+        .to_rgba8();
+    assert_eq!(
+        reference.dimensions(),
+        actual.dimensions(),
+        "reference {} has a different size",
+        reference_path.display()
+    );
+
+    let error = rmse(&block_average(&reference), &block_average(actual));
+    if error > TOLERANCE {
+        let (actual_path, diff_path) = write_failure(output_name, &reference, actual);
+        panic!(
+            "{output_name} differs from its reference: RMSE {error:.4} > {TOLERANCE}\n  render: {}\n  diff: {}",
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+/// Mean of every `BLOCK_SIZE` square block, as channels in [0, 1].
+fn block_average(image: &RgbaImage) -> Vec<[f32; 3]> {
+    let (width, height) = image.dimensions();
+    let mut blocks = Vec::new();
+    for block_y in 0..height / BLOCK_SIZE {
+        for block_x in 0..width / BLOCK_SIZE {
+            let mut sum = [0.0; 3];
+            for y in 0..BLOCK_SIZE {
+                for x in 0..BLOCK_SIZE {
+                    let pixel = image.get_pixel(block_x * BLOCK_SIZE + x, block_y * BLOCK_SIZE + y);
+                    for channel in 0..3 {
+                        sum[channel] += pixel[channel] as f32 / 255.0;
+                    }
+                }
+            }
+            blocks.push(sum.map(|channel| channel / (BLOCK_SIZE * BLOCK_SIZE) as f32));
+        }
+    }
+    blocks
+}
+
+fn rmse(a: &[[f32; 3]], b: &[[f32; 3]]) -> f32 {
+    let squared_sum: f32 = a
+        .iter()
+        .zip(b)
+        .flat_map(|(a, b)| (0..3).map(move |channel| (a[channel] - b[channel]).powi(2)))
+        .sum();
+    (squared_sum / (a.len() * 3) as f32).sqrt()
+}
+
+/// Writes the render and the amplified per pixel difference, returns their paths.
+fn write_failure(name: &str, reference: &RgbaImage, actual: &RgbaImage) -> (PathBuf, PathBuf) {
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let diff = RgbImage::from_fn(actual.width(), actual.height(), |x, y| {
+        let (a, b) = (reference.get_pixel(x, y), actual.get_pixel(x, y));
+        Rgb([0, 1, 2].map(|channel| {
+            let difference = (a[channel] as f32 - b[channel] as f32).abs();
+            (difference * DIFF_SCALE).min(255.0) as u8
+        }))
+    });
+
+    let actual_path = directory.join(format!("{name}.png"));
+    let diff_path = directory.join(format!("{name}_diff.png"));
+    actual.save(&actual_path).unwrap();
+    diff.save(&diff_path).unwrap();
+    (actual_path, diff_path)
+}
+
+#[test]
+fn default_scene() {
+    check("default");
+}
+
+#[test]
+fn cornell_box() {
+    check("cornell_box");
+}
+
+#[test]
+fn lights() {
+    check("lights");
+}
+
+#[test]
+fn environment() {
+    check("environment");
+}
+
+#[test]
+fn materials() {
+    check("materials");
+}
+
+#[test]
+fn meshes() {
+    check("meshes");
+}
+
+#[test]
+fn gltf() {
+    check("gltf");
+}
+
+#[test]
+fn many_spheres() {
+    check("many_spheres");
+}
+
+#[test]
+fn shapes() {
+    check("shapes");
+}
+
+#[test]
+fn cornell_shapes() {
+    check("cornell_shapes");
+}
+
+#[test]
+fn textures() {
+    check("textures");
+}
+
+#[test]
+fn procedural() {
+    check("procedural");
+}
+
+#[test]
+fn volumes() {
+    check("volumes");
+}
+
+#[test]
+fn default_scene_cpu() {
+    check_cpu("default");
+}
+
+#[test]
+fn cornell_box_cpu() {
+    check_cpu("cornell_box");
+}
+
+#[test]
+fn lights_cpu() {
+    check_cpu("lights");
+}
+
+#[test]
+fn environment_cpu() {
+    check_cpu("environment");
+}
+
+#[test]
+fn materials_cpu() {
+    check_cpu("materials");
+}
+
+#[test]
+fn meshes_cpu() {
+    check_cpu("meshes");
+}
+
+#[test]
+fn gltf_cpu() {
+    check_cpu("gltf");
+}
+
+#[test]
+fn many_spheres_cpu() {
+    check_cpu("many_spheres");
+}
+
+#[test]
+fn shapes_cpu() {
+    check_cpu("shapes");
+}
+
+#[test]
+fn cornell_shapes_cpu() {
+    check_cpu("cornell_shapes");
+}
+
+#[test]
+fn textures_cpu() {
+    check_cpu("textures");
+}
+
+#[test]
+fn procedural_cpu() {
+    check_cpu("procedural");
+}
+
+#[test]
+fn volumes_cpu() {
+    check_cpu("volumes");
+}